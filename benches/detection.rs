@@ -0,0 +1,35 @@
+//! Letterbox detection on synthetic frames of increasing size
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use image::{DynamicImage, Rgba, RgbaImage};
+use remove_letterbox::detect_letterbox;
+use std::hint::black_box;
+
+/// A noisy frame with black bars taking an eighth of the height at the top and bottom
+fn letterboxed_frame(width: u32, height: u32) -> DynamicImage {
+    let bar = height / 8;
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+        if y < bar || y >= height - bar {
+            Rgba([0, 0, 0, 255])
+        } else {
+            let noise = u8::try_from((x * 31 + y * 17) % 200).unwrap_or(0);
+            Rgba([noise + 40, 120, 255 - noise, 255])
+        }
+    }))
+}
+
+fn bench_detection(c: &mut Criterion) {
+    let mut group = c.benchmark_group("detect_letterbox");
+    for (width, height) in [(640, 360), (1920, 1080), (3840, 2160), (7680, 4320)] {
+        let img = letterboxed_frame(width, height);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{width}x{height}")),
+            &img,
+            |b, img| b.iter(|| detect_letterbox(black_box(img), 10)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_detection);
+criterion_main!(benches);
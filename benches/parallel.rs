@@ -0,0 +1,58 @@
+//! Cropping a folder of 1000 letterboxed images into an output directory, as tokio tasks and
+//! on the thread pool of `--parallel`
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use image::{Rgba, RgbaImage};
+use remove_letterbox::{Config, OverwritePolicy, process_directory};
+use std::hint::black_box;
+use std::path::Path;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// Number of images in the benchmarked folder
+const IMAGES: usize = 1000;
+
+/// Write `IMAGES` noisy 640x360 frames with black bars at the top and bottom to `dir`
+fn write_folder(dir: &Path) {
+    let (width, height) = (640, 360);
+    let bar = height / 8;
+    for i in 0..IMAGES {
+        let offset = u32::try_from(i).unwrap_or(0);
+        let img = RgbaImage::from_fn(width, height, |x, y| {
+            if y < bar || y >= height - bar {
+                Rgba([0, 0, 0, 255])
+            } else {
+                let noise = u8::try_from((x * 31 + y * 17 + offset) % 200).unwrap_or(0);
+                Rgba([noise + 40, 120, 255 - noise, 255])
+            }
+        });
+        img.save(dir.join(format!("frame{i:04}.png")))
+            .expect("Failed to write benchmark image");
+    }
+}
+
+fn bench_parallel(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("Failed to start runtime");
+    let input = TempDir::new().expect("Failed to create input directory");
+    let output = TempDir::new().expect("Failed to create output directory");
+    write_folder(input.path());
+
+    let mut group = c.benchmark_group("process_directory");
+    group.sample_size(10);
+    for parallel in [false, true] {
+        let config = Config {
+            output: Some(output.path().to_owned()),
+            overwrite: OverwritePolicy::Overwrite,
+            parallel,
+            ..Config::default()
+        };
+        let name = if parallel { "parallel" } else { "tasks" };
+        group.bench_with_input(BenchmarkId::from_parameter(name), &config, |b, config| {
+            b.iter(|| runtime.block_on(process_directory(black_box(input.path()), config)));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parallel);
+criterion_main!(benches);
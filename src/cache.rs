@@ -0,0 +1,188 @@
+//! Persistent cache of images known to have no letterbox, so that incremental runs skip them
+//! without decoding
+
+use crate::{Config, CropResult, DetectionParams};
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+/// A file as it was when last processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Size of the file in bytes
+    size: u64,
+    /// Modification time of the file, as seconds and nanoseconds since the Unix epoch
+    mtime: (i64, u32),
+    /// Dimensions of the image
+    dimensions: (u32, u32),
+}
+
+/// Contents of a cache file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    /// Detection and conversion settings the entries were recorded with
+    settings: String,
+    /// Entries by absolute path of the image
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+/// Images that were found without a letterbox, or were cropped in place, by an earlier run.
+/// An image is skipped while its size and modification time match its entry.
+///
+/// The cache is only consulted when images are processed in place. Entries are dropped when
+/// the cache is loaded with different detection or conversion settings.
+#[derive(Debug)]
+pub struct ResultCache {
+    path: PathBuf,
+    contents: Mutex<CacheFile>,
+}
+
+impl ResultCache {
+    /// Load the cache stored at `path`, or start an empty one if the file does not exist yet
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file exists but cannot be read or parsed.
+    pub async fn load(path: &Path, config: &Config) -> Result<Self> {
+        let settings = settings_key(config);
+        let mut contents = match tokio::fs::read(path).await {
+            Ok(json) => serde_json::from_slice::<CacheFile>(&json)
+                .with_context(|| format!("Invalid cache file: {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => CacheFile::default(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read cache file: {}", path.display()));
+            }
+        };
+        if contents.settings != settings {
+            if !contents.entries.is_empty() {
+                debug!("Settings changed, discarding cache: {}", path.display());
+            }
+            contents = CacheFile {
+                settings,
+                entries: HashMap::new(),
+            };
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            contents: Mutex::new(contents),
+        })
+    }
+
+    /// Write the cache back to the file it was loaded from
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the cache cannot be serialized or the file cannot be written.
+    pub async fn save(&self) -> Result<()> {
+        let json = {
+            let contents = self.contents.lock().await;
+            serde_json::to_vec(&*contents).context("Failed to serialize cache")?
+        };
+        tokio::fs::write(&self.path, json)
+            .await
+            .with_context(|| format!("Failed to write cache file: {}", self.path.display()))
+    }
+
+    /// The result for an image whose entry still matches the file on disk
+    pub(crate) async fn lookup(&self, path: &Path) -> Option<CropResult> {
+        let key = std::path::absolute(path).ok()?;
+        let current = file_state(path).await?;
+        let contents = self.contents.lock().await;
+        let entry = contents.entries.get(&key)?;
+        (entry.size == current.0 && entry.mtime == current.1)
+            .then(|| CropResult::new(entry.dimensions, None))
+    }
+
+    /// Record the outcome of processing an image in place. Only images that are left without
+    /// a letterbox are kept, which excludes crops predicted by a dry run.
+    pub(crate) async fn update(&self, path: &Path, result: &CropResult, dry_run: bool) {
+        let Ok(key) = std::path::absolute(path) else {
+            return;
+        };
+        // A dry run leaves the letterbox of a predicted crop in place, so the image must be
+        // checked again on the next run
+        let state = if result.modified && dry_run {
+            None
+        } else {
+            file_state(path).await
+        };
+
+        let mut contents = self.contents.lock().await;
+        match state {
+            Some((size, mtime)) => {
+                let entry = CacheEntry {
+                    size,
+                    mtime,
+                    dimensions: result.cropped,
+                };
+                contents.entries.insert(key, entry);
+            }
+            // Images converted to another format are gone from their original path
+            None => {
+                contents.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// Size and modification time of a file, or `None` if it cannot be read
+async fn file_state(path: &Path) -> Option<(u64, (i64, u32))> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    Some((metadata.len(), (mtime.unix_seconds(), mtime.nanoseconds())))
+}
+
+/// Settings that decide whether an image is cropped or converted, so that entries recorded
+/// with other settings are not trusted
+fn settings_key(config: &Config) -> String {
+    format!("{:?} {:?}", DetectionParams::new(config), config.format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process_file;
+    use crate::tests::create_test_image;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_result_cache() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        let cache_path = temp_dir.path().join("cache.json");
+        let mut config = Config::default();
+        let cache = Arc::new(ResultCache::load(&cache_path, &config).await?);
+        config.cache = Some(Arc::clone(&cache));
+
+        // The cropped image is recorded as it is after the crop
+        let result = process_file(&path, &config).await?;
+        assert!(result.is_some_and(|r| r.modified));
+        cache.save().await?;
+
+        let cache = ResultCache::load(&cache_path, &config).await?;
+        assert_eq!(
+            cache.lookup(&path).await,
+            Some(CropResult::new((100, 51), None))
+        );
+
+        // Changing the file or the settings invalidates the entry
+        img.save(&path)?;
+        assert_eq!(cache.lookup(&path).await, None);
+        let config = Config {
+            threshold: [20; 3],
+            ..config
+        };
+        let cache = ResultCache::load(&cache_path, &config).await?;
+        assert!(cache.contents.lock().await.entries.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Append-only log of every crop across runs, one tab-separated line per modified image
+
+use crate::{Config, CropResult};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// A TSV file that crops are appended to. Each line holds the time of the crop, the absolute
+/// path of the image, its original and cropped dimensions, the pixels trimmed from each side,
+/// and the threshold used. Lines are flushed as they are written, so the log survives an
+/// interrupted run.
+#[derive(Debug)]
+pub struct CropLog {
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl CropLog {
+    /// Open the log at `path` for appending, creating it if it does not exist yet
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened for writing.
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open crop log: {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append the crop of the image at `image`
+    pub(crate) async fn append(
+        &self,
+        image: &Path,
+        result: &CropResult,
+        config: &Config,
+    ) -> Result<()> {
+        let absolute = std::path::absolute(image)
+            .with_context(|| format!("Failed to resolve path: {}", image.display()))?;
+        let line = format_line(SystemTime::now(), &absolute, result, config);
+        let context = || format!("Failed to write crop log: {}", self.path.display());
+        let mut file = self.file.lock().await;
+        file.write_all(line.as_bytes())
+            .await
+            .with_context(context)?;
+        file.flush().await.with_context(context)
+    }
+}
+
+/// One line of the log, ending in a newline
+fn format_line(time: SystemTime, image: &Path, result: &CropResult, config: &Config) -> String {
+    let threshold = if config.auto_threshold.is_some() {
+        "auto".to_owned()
+    } else if config.exact {
+        "exact".to_owned()
+    } else {
+        let [red, green, blue] = config.threshold;
+        format!("{red},{green},{blue}")
+    };
+    format!(
+        "{}\t{}\t{}x{}\t{}x{}\ttop={} bottom={} left={} right={}\t{threshold}\n",
+        humantime::format_rfc3339_seconds(time),
+        image.display(),
+        result.original.0,
+        result.original.1,
+        result.cropped.0,
+        result.cropped.1,
+        result.top,
+        result.bottom,
+        result.left,
+        result.right
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_format_line() {
+        let result = CropResult {
+            original: (1920, 1080),
+            cropped: (1920, 800),
+            top: 140,
+            bottom: 140,
+            left: 0,
+            right: 0,
+            modified: true,
+            blank: false,
+            confidence: 1.0,
+        };
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(86_400);
+        assert_eq!(
+            format_line(time, Path::new("/img/a.png"), &result, &Config::default()),
+            "1970-01-02T00:00:00Z\t/img/a.png\t1920x1080\t1920x800\ttop=140 bottom=140 left=0 right=0\t10,10,10\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_append_across_runs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("crops.tsv");
+        let result = CropResult {
+            original: (100, 100),
+            cropped: (100, 50),
+            top: 25,
+            bottom: 25,
+            left: 0,
+            right: 0,
+            modified: true,
+            blank: false,
+            confidence: 1.0,
+        };
+        for _ in 0..2 {
+            let log = CropLog::open(&path).await?;
+            log.append(Path::new("a.png"), &result, &Config::default())
+                .await?;
+        }
+
+        let contents = std::fs::read_to_string(&path)?;
+        assert_eq!(contents.lines().count(), 2);
+        assert!(
+            contents
+                .lines()
+                .all(|line| line.split('\t').count() == 6 && line.contains("a.png"))
+        );
+        Ok(())
+    }
+}
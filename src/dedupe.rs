@@ -0,0 +1,140 @@
+//! Removal of pixel-identical images left behind by a run, such as cropped frames of a video
+
+use crate::{Config, FileSink, LetterboxError, is_image_file, walk_tree};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+/// Name of the directory that duplicates are moved to, inside the processed directory
+const DUPLICATES_DIR: &str = "duplicates";
+
+/// What to do with every image but the first of a group of identical ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DedupeAction {
+    /// Move duplicates into a `duplicates` directory, keeping their relative paths
+    #[default]
+    Move,
+    /// Delete duplicates
+    Delete,
+}
+
+/// Find the images under `root` whose contents are identical to an earlier one, by path
+/// order, and move or delete them. Images are compared by a BLAKE3 hash of their file
+/// contents. The directory of moved duplicates is not searched.
+///
+/// Returns the number of duplicates removed.
+pub(crate) async fn remove_duplicates(
+    root: &Path,
+    config: &Config,
+    action: DedupeAction,
+) -> Result<usize> {
+    let duplicates_dir = root.join(DUPLICATES_DIR);
+    let mut files = Vec::new();
+    // The output root is walked as it is, without mirroring it anywhere
+    let walk_config = Config {
+        output: None,
+        since: None,
+        max_files: None,
+        ..config.clone()
+    };
+    let interrupted = AtomicBool::new(false);
+    walk_tree(
+        root,
+        &walk_config,
+        &interrupted,
+        &mut FileSink::Collect(&mut files),
+    )
+    .await?;
+    let mut files: Vec<PathBuf> = files
+        .into_iter()
+        .map(|(path, _)| path)
+        .filter(|path| is_image_file(path) && !path.starts_with(&duplicates_dir))
+        .collect();
+    files.sort();
+
+    let mut seen = HashMap::new();
+    let mut removed = 0;
+    for path in files {
+        let contents = tokio::fs::read(&path)
+            .await
+            .map_err(|err| LetterboxError::io(&path, err))?;
+        let hash = blake3::hash(&contents);
+        let Some(original) = seen.get(&hash) else {
+            seen.insert(hash, path);
+            continue;
+        };
+        debug!("{} is identical to {}", path.display(), original.display());
+
+        match action {
+            DedupeAction::Move => {
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                let destination = duplicates_dir.join(relative);
+                if let Some(parent) = destination.parent() {
+                    tokio::fs::create_dir_all(parent).await.with_context(|| {
+                        format!("Failed to create directory: {}", parent.display())
+                    })?;
+                }
+                tokio::fs::rename(&path, &destination)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to move {} to {}",
+                            path.display(),
+                            destination.display()
+                        )
+                    })?;
+                info!("Moved duplicate {}", path.display());
+            }
+            DedupeAction::Delete => {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .with_context(|| format!("Failed to delete duplicate: {}", path.display()))?;
+                info!("Deleted duplicate {}", path.display());
+            }
+        }
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process_directory;
+    use image::{Rgba, RgbaImage};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_dedupe_after() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        // Frames that differ only in their letterbox become identical once cropped
+        for (name, height, bar) in [("a.png", 100, 20), ("b.png", 80, 10), ("c.png", 100, 0)] {
+            let img = RgbaImage::from_fn(100, height, |_, y| {
+                if y < bar || y >= height - bar {
+                    Rgba([0, 0, 0, 255])
+                } else if y - bar < 30 {
+                    Rgba([255, 255, 255, 255])
+                } else {
+                    Rgba([200, 0, 0, 255])
+                }
+            });
+            img.save(temp_dir.path().join(name))?;
+        }
+
+        let config = Config {
+            dedupe: Some(DedupeAction::Move),
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        assert_eq!(report.duplicates, 1);
+        assert!(temp_dir.path().join("a.png").exists());
+        assert!(!temp_dir.path().join("b.png").exists());
+        assert!(temp_dir.path().join("duplicates/b.png").exists());
+        assert!(temp_dir.path().join("c.png").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Error type of the library functions, sorting failures into categories callers can match on
+
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Boxed error of one of the decoders or encoders
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Error returned by the processing functions of the library, such as [`process_file`] and
+/// [`crop_image_bytes`].
+///
+/// Failures that fit none of the categories, such as a directory that cannot be read or an
+/// output file that already exists, are [`LetterboxError::Other`], which keeps the whole chain
+/// of context of the failure.
+///
+/// [`process_file`]: crate::process_file
+/// [`crop_image_bytes`]: crate::crop_image_bytes
+#[derive(Debug, thiserror::Error)]
+pub enum LetterboxError {
+    /// An image file could not be read or written
+    #[error("I/O error on image file: {}", path.display())]
+    Io {
+        /// Path of the image file
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// An image file could not be read or written because access to it, or to its directory,
+    /// was denied, such as a read-only file cropped in place
+    #[error("Permission denied on image file: {}", path.display())]
+    PermissionDenied {
+        /// Path of the image file
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// An image could not be decoded, because it is corrupt or uses a feature the decoder
+    /// does not support
+    #[error("Failed to decode image file: {}", path.display())]
+    Decode {
+        /// Path of the image file, or the name given to an image held in memory
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+
+    /// A cropped image could not be encoded
+    #[error("Failed to encode image: {}", path.display())]
+    Encode {
+        /// Path the image was to be written to, or the name given to an image held in memory
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+
+    /// The file is not an image, or not in a format that can be read or written
+    #[error("Unsupported image format: {}", path.display())]
+    UnsupportedFormat {
+        /// Path of the file
+        path: PathBuf,
+    },
+
+    /// Any other failure
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl LetterboxError {
+    /// Reading or writing the image file at `path` failed, for lack of permission or otherwise
+    pub(crate) fn io(path: &Path, source: io::Error) -> Self {
+        let path = path.to_owned();
+        if source.kind() == io::ErrorKind::PermissionDenied {
+            Self::PermissionDenied { path, source }
+        } else {
+            Self::Io { path, source }
+        }
+    }
+
+    /// Decoding the image at `path` failed
+    pub(crate) fn decode(path: &Path, source: impl Into<BoxError>) -> Self {
+        Self::Decode {
+            path: path.to_owned(),
+            source: source.into(),
+        }
+    }
+
+    /// Encoding the image for `path` failed
+    pub(crate) fn encode(path: &Path, source: impl Into<BoxError>) -> Self {
+        Self::Encode {
+            path: path.to_owned(),
+            source: source.into(),
+        }
+    }
+
+    /// The file at `path` is not in a supported image format
+    pub(crate) fn unsupported(path: &Path) -> Self {
+        Self::UnsupportedFormat {
+            path: path.to_owned(),
+        }
+    }
+}
+
+/// Internally, errors are passed on as [`anyhow::Error`] with context added along the way.
+/// A categorized error at the root of the chain is taken out of it, dropping that context,
+/// since the error names the file it concerns.
+impl From<anyhow::Error> for LetterboxError {
+    fn from(err: anyhow::Error) -> Self {
+        err.downcast::<Self>().unwrap_or_else(Self::Other)
+    }
+}
+
+/// Whether a failure was caused by denied permission, wherever in the chain of context the
+/// I/O error is
+pub(crate) fn is_permission_denied(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let io_error = match cause.downcast_ref::<image::ImageError>() {
+            Some(image::ImageError::IoError(err)) => Some(err),
+            _ => cause.downcast_ref::<io::Error>(),
+        };
+        io_error.is_some_and(|err| err.kind() == io::ErrorKind::PermissionDenied)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, crop_image_bytes, process_file};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_from_anyhow() {
+        let err = anyhow::Error::new(LetterboxError::unsupported(Path::new("a.txt")))
+            .context("Failed to process image file: a.txt");
+        assert!(matches!(
+            LetterboxError::from(err),
+            LetterboxError::UnsupportedFormat { path } if path == Path::new("a.txt")
+        ));
+        let err = anyhow::anyhow!("Failed to read directory: input");
+        assert!(matches!(
+            LetterboxError::from(err),
+            LetterboxError::Other(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_error_categories() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let missing = temp_dir.path().join("missing.png");
+        assert!(matches!(
+            process_file(&missing, &Config::default()).await,
+            Err(LetterboxError::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound
+        ));
+
+        let corrupt = temp_dir.path().join("corrupt.png");
+        std::fs::write(&corrupt, b"\x89PNG\r\n\x1a\nnot really")?;
+        assert!(matches!(
+            process_file(&corrupt, &Config::default()).await,
+            Err(LetterboxError::Decode { .. })
+        ));
+
+        let text = temp_dir.path().join("notes.txt");
+        std::fs::write(&text, "not an image")?;
+        let config = Config {
+            strict_input: true,
+            ..Config::default()
+        };
+        assert!(matches!(
+            process_file(&text, &config).await,
+            Err(LetterboxError::UnsupportedFormat { .. })
+        ));
+        assert!(matches!(
+            crop_image_bytes(b"not an image", &text, &Config::default()),
+            Err(LetterboxError::UnsupportedFormat { .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! Progress events for tools that wrap the command line, one JSON object per line
+
+use crate::CropResult;
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Serialize;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+/// An event of a single file
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event<'a> {
+    /// The file is about to be processed
+    Start { path: &'a str },
+    /// The file was processed, with its dimensions before and after the crop
+    Done {
+        path: &'a str,
+        cropped: bool,
+        original: [u32; 2],
+        new: [u32; 2],
+        confidence: f32,
+    },
+    /// The file was skipped, such as one that is too small or whose copy already exists
+    Skipped { path: &'a str },
+    /// Processing the file failed
+    Error { path: &'a str, error: String },
+}
+
+/// A stream that a JSON object is written to on its own line as each image starts and
+/// finishes processing, for user interfaces that show progress as it happens. Every object has
+/// an `event` of `start`, `done`, `skipped`, or `error` and the `path` of the image. `done`
+/// events add whether the image was `cropped`, its `original` and `new` dimensions as
+/// `[width, height]`, and the detection `confidence`, and `error` events the `error` message.
+pub struct ProgressEvents {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for ProgressEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressEvents").finish_non_exhaustive()
+    }
+}
+
+impl ProgressEvents {
+    /// Write events to `out`
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Write events to stderr
+    #[must_use]
+    pub fn stderr() -> Self {
+        Self::new(std::io::stderr())
+    }
+
+    /// Append events to the file at `path`, which may also be a pipe or a descriptor such as
+    /// `/dev/fd/3`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened for writing.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open progress events: {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+
+    /// Report that the image at `path` is about to be processed
+    pub(crate) fn start(&self, path: &Path) {
+        self.write(&Event::Start {
+            path: &path.to_string_lossy(),
+        });
+    }
+
+    /// Report how processing the image at `path` ended
+    pub(crate) fn finish(&self, path: &Path, outcome: &Result<Option<CropResult>>) {
+        let path = &path.to_string_lossy();
+        self.write(&match outcome {
+            Ok(Some(result)) => Event::Done {
+                path,
+                cropped: result.modified,
+                original: result.original.into(),
+                new: result.cropped.into(),
+                confidence: result.confidence,
+            },
+            Ok(None) => Event::Skipped { path },
+            Err(err) => Event::Error {
+                path,
+                error: format!("{err:#}"),
+            },
+        });
+    }
+
+    /// Write an event as a line of its own. Events that cannot be written, for example after
+    /// the reading end of a pipe was closed, are dropped.
+    fn write(&self, event: &Event) {
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(err) => {
+                debug!("Failed to serialize progress event: {err}");
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(err) = out.write_all(&line).and_then(|()| out.flush()) {
+            debug!("Failed to write progress event: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::create_test_image;
+    use crate::{Config, process_directory};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Writer whose contents can be read after it was handed to the events
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_progress_events() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_image(&temp_dir.path().join("boxed.png"), 100, 100, true)?;
+        std::fs::write(temp_dir.path().join("broken.png"), b"not a png")?;
+
+        let out = Shared::default();
+        let config = Config {
+            continue_on_error: true,
+            progress_events: Some(Arc::new(ProgressEvents::new(out.clone()))),
+            ..Config::default()
+        };
+        process_directory(temp_dir.path(), &config).await?;
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone())?;
+        let mut events = text
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| event["event"] == "start")
+                .count(),
+            2
+        );
+        events.retain(|event| event["event"] != "start");
+        events.sort_by_key(|event| event["event"].to_string());
+        assert_eq!(events[0]["cropped"], true);
+        assert_eq!(events[0]["new"], serde_json::json!([100, 51]));
+        assert!(events[0]["path"].as_str().unwrap().ends_with("boxed.png"));
+        assert_eq!(events[1]["event"], "error");
+        assert!(events[1]["path"].as_str().unwrap().ends_with("broken.png"));
+        Ok(())
+    }
+}
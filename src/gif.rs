@@ -0,0 +1,263 @@
+//! Letterbox removal for animated GIFs, applying one crop to every frame
+
+use crate::{
+    CropRect, CropResult, DetectionParams, LetterboxError, adjust_crop, backup_file, read_file,
+    write_file_atomic,
+};
+use anyhow::{Context, Result};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::metadata::LoopCount;
+use image::{AnimationDecoder, DynamicImage, Frame, imageops};
+use log::debug;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Whether the path has a `.gif` extension
+pub(crate) fn is_gif_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"))
+}
+
+/// The decoded frames of an animation and how often it repeats
+pub(crate) struct Animation {
+    pub(crate) frames: Vec<Frame>,
+    pub(crate) loop_count: LoopCount,
+}
+
+impl Animation {
+    /// Read and decode every frame of a GIF file
+    async fn read(path: &Path) -> Result<Self> {
+        let bytes = read_file(path).await?;
+        let decoder =
+            GifDecoder::new(Cursor::new(bytes)).map_err(|err| LetterboxError::decode(path, err))?;
+        let loop_count = decoder.loop_count();
+        let frames = decoder
+            .into_frames()
+            .collect_frames()
+            .map_err(|err| LetterboxError::decode(path, err))?;
+        Ok(Self { frames, loop_count })
+    }
+
+    /// Dimensions of the animation, taken from its first frame
+    pub(crate) fn dimensions(&self) -> (u32, u32) {
+        self.frames
+            .first()
+            .map_or((0, 0), |frame| frame.buffer().dimensions())
+    }
+
+    /// The frame that detection runs on unless `per_frame` is set, see
+    /// [`Config::frame_select`](crate::Config::frame_select)
+    fn selected_frame(&self, params: &DetectionParams, path: &Path) -> Result<&Frame> {
+        self.frames.get(params.frame).with_context(|| {
+            format!(
+                "Cannot select frame {} of an animation with {} frames: {}",
+                params.frame,
+                self.frames.len(),
+                path.display()
+            )
+        })
+    }
+
+    /// The selected frame as an image, for previewing the crop
+    pub(crate) fn preview_frame(
+        &self,
+        params: &DetectionParams,
+        path: &Path,
+    ) -> Result<DynamicImage> {
+        let frame = self.selected_frame(params, path)?;
+        Ok(DynamicImage::ImageRgba8(frame.buffer().clone()))
+    }
+
+    /// Decide on the crop shared by all frames. Detection runs on the selected frame, or with
+    /// `per_frame` set, on every frame with the results combined so no frame loses content.
+    /// Frames without a letterbox keep the full image.
+    ///
+    /// Fails if the selected frame is past the last frame.
+    pub(crate) fn plan_crop(
+        &self,
+        params: &DetectionParams,
+        path: &Path,
+    ) -> Result<Option<CropRect>> {
+        let (width, height) = self.dimensions();
+        let full = CropRect::full((width, height));
+        let detect = |frame: &Frame| params.detect(frame.buffer(), path).unwrap_or(full);
+
+        let rect = if params.per_frame {
+            self.frames.iter().map(detect).reduce(CropRect::union)
+        } else {
+            Some(detect(self.selected_frame(params, path)?))
+        };
+        Ok(rect
+            .filter(|&rect| rect != full)
+            .and_then(|rect| adjust_crop(rect, (width, height), params, path)))
+    }
+
+    /// Encode the animation with every frame cropped to `rect`, keeping frame delays and the
+    /// loop count
+    fn encode(self, rect: CropRect, path: &Path) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let mut encoder = GifEncoder::new(&mut buf);
+        let repeat = match self.loop_count {
+            LoopCount::Infinite => Repeat::Infinite,
+            LoopCount::Finite(count) => {
+                Repeat::Finite(u16::try_from(count.get()).unwrap_or(u16::MAX))
+            }
+        };
+        encoder
+            .set_repeat(repeat)
+            .map_err(|err| LetterboxError::encode(path, err))?;
+
+        let frames = self.frames.into_iter().map(|frame| {
+            let cropped =
+                imageops::crop_imm(frame.buffer(), rect.x, rect.y, rect.width, rect.height)
+                    .to_image();
+            Frame::from_parts(cropped, 0, 0, frame.delay())
+        });
+        encoder
+            .encode_frames(frames)
+            .map_err(|err| LetterboxError::encode(path, err))?;
+        drop(encoder);
+
+        Ok(buf)
+    }
+}
+
+/// Predict the crop of a GIF without modifying it, returning the frame it was detected on
+/// along with it
+pub(crate) async fn plan_gif(
+    path: &Path,
+    params: &DetectionParams,
+) -> Result<(DynamicImage, CropResult)> {
+    let animation = Animation::read(path).await?;
+    let rect = animation.plan_crop(params, path)?;
+    let frame = animation.preview_frame(params, path)?;
+    Ok((frame, CropResult::new(animation.dimensions(), rect)))
+}
+
+/// Crop every frame of a GIF to the same letterbox-free area and write it back to the same
+/// path. When `backup` holds a suffix, the file is backed up before it is overwritten.
+pub(crate) async fn remove_gif_letterbox(
+    path: &Path,
+    params: &DetectionParams,
+    backup: Option<&str>,
+    force: bool,
+) -> Result<CropResult> {
+    let animation = Animation::read(path).await?;
+    let dimensions = animation.dimensions();
+    let rect = animation.plan_crop(params, path)?;
+
+    if let Some(rect) = rect {
+        if let Some(suffix) = backup {
+            backup_file(path, suffix, force).await?;
+        }
+        let frames = animation.frames.len();
+        let encoded = animation.encode(rect, path)?;
+        write_file_atomic(path, &encoded).await?;
+        debug!("Cropped {frames} frames of {}", path.display());
+    }
+
+    Ok(CropResult::new(dimensions, rect))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, crop_file};
+    use image::{Delay, Rgba, RgbaImage};
+    use std::num::NonZeroU32;
+    use tempfile::TempDir;
+
+    /// Write a 100x100 GIF whose frames have black bars of the given heights at the top and
+    /// bottom, looping three times
+    fn create_test_gif(path: &Path, bars: &[u32]) -> Result<()> {
+        let frames = bars.iter().map(|&bar| {
+            let buffer = RgbaImage::from_fn(100, 100, |_, y| {
+                if y < bar || y >= 100 - bar {
+                    Rgba([0, 0, 0, 255])
+                } else {
+                    Rgba([255, 255, 255, 255])
+                }
+            });
+            Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(50, 1))
+        });
+
+        let mut buf = Vec::new();
+        let mut encoder = GifEncoder::new(&mut buf);
+        encoder.set_repeat(Repeat::Finite(3))?;
+        encoder.encode_frames(frames)?;
+        drop(encoder);
+        std::fs::write(path, buf)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_animated_gif() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.gif");
+        create_test_gif(&path, &[20, 10])?;
+
+        // The first frame decides the crop by default
+        let result = crop_file(&path, &Config::default()).await?;
+        assert_eq!(result.cropped, (100, 60));
+
+        let animation = Animation::read(&path).await?;
+        assert_eq!(animation.frames.len(), 2);
+        assert!(
+            animation
+                .frames
+                .iter()
+                .all(|frame| frame.buffer().height() == 60)
+        );
+        assert_eq!(
+            animation.frames[1].delay(),
+            Delay::from_numer_denom_ms(50, 1)
+        );
+        assert!(matches!(
+            animation.loop_count,
+            LoopCount::Finite(count) if count == NonZeroU32::new(3).unwrap()
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_animated_gif_per_frame() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.gif");
+        create_test_gif(&path, &[20, 10])?;
+
+        let config = Config {
+            per_frame_crop: true,
+            ..Config::default()
+        };
+        let result = crop_file(&path, &config).await?;
+
+        // The frame with the thinner bars limits the crop
+        assert_eq!(result.cropped, (100, 80));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frame_select() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.gif");
+        create_test_gif(&path, &[20, 10])?;
+
+        let config = Config {
+            dry_run: true,
+            frame_select: 1,
+            ..Config::default()
+        };
+        assert_eq!(crop_file(&path, &config).await?.cropped, (100, 80));
+
+        // Frames past the last one, and frames of still images, cannot be selected
+        let config = Config {
+            frame_select: 2,
+            ..config
+        };
+        assert!(crop_file(&path, &config).await.is_err());
+        let still = temp_dir.path().join("still.png");
+        RgbaImage::new(100, 100).save(&still)?;
+        assert!(crop_file(&still, &config).await.is_err());
+        Ok(())
+    }
+}
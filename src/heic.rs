@@ -0,0 +1,34 @@
+//! HEIC, HEIF, and AVIF decoding through libheif
+
+use crate::LetterboxError;
+use anyhow::Result;
+use image::{DynamicImage, RgbaImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+use std::path::Path;
+
+/// Decode the primary image of a HEIF container, such as a HEIC or AVIF file
+pub(crate) fn decode_heic(bytes: &[u8], path: &Path) -> Result<DynamicImage> {
+    let decode_error = |err: libheif_rs::HeifError| LetterboxError::decode(path, err);
+    let heif = HeifContext::read_from_bytes(bytes).map_err(decode_error)?;
+    let handle = heif.primary_image_handle().map_err(decode_error)?;
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(decode_error)?;
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or_else(|| LetterboxError::decode(path, "no interleaved RGBA plane"))?;
+
+    // Rows may be padded beyond the four bytes per pixel
+    let row_len = plane.width as usize * 4;
+    let pixels = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+    let img = RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .ok_or_else(|| LetterboxError::decode(path, "pixel data does not fill the image"))?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
@@ -0,0 +1,154 @@
+//! Windows ICO icons, of which only the largest image is cropped and written back
+
+use crate::{LetterboxError, decode_upright};
+use anyhow::Result;
+use image::{DynamicImage, ImageFormat};
+use std::path::Path;
+
+/// Size of the header of an ICO file, before its directory
+const HEADER_LEN: usize = 6;
+
+/// Size of each entry of the directory
+const ENTRY_LEN: usize = 16;
+
+/// Offset of the image data in an icon holding a single image
+const SINGLE_DATA_OFFSET: u32 = 22;
+
+/// Whether the path has an `.ico` extension
+pub(crate) fn is_ico_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ico"))
+}
+
+/// Directory entry of an image in an ICO file
+struct Entry<'a> {
+    /// The 16 bytes of the entry as stored
+    raw: &'a [u8],
+    /// Number of pixels of the image
+    area: u32,
+    /// Encoded image data, a PNG or a headerless BMP
+    data: &'a [u8],
+}
+
+/// The directory entries of an ICO file
+fn read_directory<'a>(bytes: &'a [u8], path: &Path) -> Result<Vec<Entry<'a>>, LetterboxError> {
+    let invalid = |reason: &'static str| LetterboxError::decode(path, reason);
+    let header = bytes
+        .get(..HEADER_LEN)
+        .ok_or_else(|| invalid("truncated header"))?;
+    let count = usize::from(u16::from_le_bytes([header[4], header[5]]));
+    (0..count)
+        .map(|index| {
+            let start = HEADER_LEN + index * ENTRY_LEN;
+            let raw = bytes
+                .get(start..start + ENTRY_LEN)
+                .ok_or_else(|| invalid("truncated directory"))?;
+            // A width or height of 0 stands for 256 pixels
+            let length = |byte: u8| if byte == 0 { 256 } else { u32::from(byte) };
+            let field = |offset: usize| {
+                let value = [
+                    raw[offset],
+                    raw[offset + 1],
+                    raw[offset + 2],
+                    raw[offset + 3],
+                ];
+                usize::try_from(u32::from_le_bytes(value)).unwrap_or(usize::MAX)
+            };
+            let (size, offset) = (field(8), field(12));
+            let data = offset
+                .checked_add(size)
+                .and_then(|end| bytes.get(offset..end))
+                .ok_or_else(|| invalid("image data lies outside the file"))?;
+            Ok(Entry {
+                raw,
+                area: length(raw[0]) * length(raw[1]),
+                data,
+            })
+        })
+        .collect()
+}
+
+/// The largest image of an ICO file by area, as an ICO file of its own. Of images of the same
+/// size, the first one is taken.
+fn largest_image(bytes: &[u8], path: &Path) -> Result<Vec<u8>, LetterboxError> {
+    let entries = read_directory(bytes, path)?;
+    let largest = entries
+        .iter()
+        .rev()
+        .max_by_key(|entry| entry.area)
+        .ok_or_else(|| LetterboxError::decode(path, "icon holds no images"))?;
+
+    let mut icon = Vec::with_capacity(HEADER_LEN + ENTRY_LEN + largest.data.len());
+    icon.extend_from_slice(&bytes[..4]);
+    icon.extend_from_slice(&1u16.to_le_bytes());
+    icon.extend_from_slice(&largest.raw[..12]);
+    icon.extend_from_slice(&SINGLE_DATA_OFFSET.to_le_bytes());
+    icon.extend_from_slice(largest.data);
+    Ok(icon)
+}
+
+/// Decode the largest image of an ICO file. Its other images are dropped when the cropped
+/// image is written back.
+pub(crate) fn decode_ico(bytes: &[u8], path: &Path) -> Result<DynamicImage> {
+    let icon = largest_image(bytes, path)?;
+    Ok(decode_upright(&icon, Some(ImageFormat::Ico))
+        .map_err(|err| LetterboxError::decode(path, err))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, process_file};
+    use image::codecs::ico::{IcoEncoder, IcoFrame};
+    use image::{ExtendedColorType, GenericImageView, Rgba, RgbaImage};
+    use tempfile::TempDir;
+
+    /// Encode an icon with a letterboxed image of `size` pixels between two plain ones
+    fn create_test_icon(size: u32) -> Result<Vec<u8>> {
+        let frame = |size: u32, bar: u32| -> Result<IcoFrame<'static>> {
+            let img = RgbaImage::from_fn(size, size, |_, y| {
+                if y < bar || y >= size - bar {
+                    Rgba([0, 0, 0, 255])
+                } else {
+                    Rgba([255, 255, 255, 255])
+                }
+            });
+            Ok(IcoFrame::as_png(
+                img.as_raw(),
+                size,
+                size,
+                ExtendedColorType::Rgba8,
+            )?)
+        };
+        let mut bytes = Vec::new();
+        IcoEncoder::new(&mut bytes).encode_images(&[
+            frame(16, 0)?,
+            frame(size, size / 4)?,
+            frame(32, 0)?,
+        ])?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn test_largest_image() -> Result<()> {
+        let path = Path::new("app.ico");
+        let bytes = create_test_icon(64)?;
+        assert_eq!(read_directory(&bytes, path)?.len(), 3);
+        assert_eq!(decode_ico(&bytes, path)?.dimensions(), (64, 64));
+        assert!(decode_ico(&bytes[..10], path).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_ico() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("app.ico");
+        std::fs::write(&path, create_test_icon(64)?)?;
+
+        let result = process_file(&path, &Config::default()).await?.unwrap();
+        assert_eq!(result.original, (64, 64));
+        assert_eq!(result.cropped, (64, 32));
+        assert_eq!(image::open(&path)?.dimensions(), (64, 32));
+        Ok(())
+    }
+}
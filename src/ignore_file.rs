@@ -0,0 +1,118 @@
+//! `.letterboxignore` files, which keep files and directories out of directory runs using
+//! gitignore syntax
+
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Name of the ignore file read in every processed directory
+pub(crate) const IGNORE_FILE_NAME: &str = ".letterboxignore";
+
+/// The ignore files that apply to a directory, from the root of the run down to the directory
+/// itself
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IgnoreRules {
+    matchers: Arc<Vec<Gitignore>>,
+}
+
+impl IgnoreRules {
+    /// The rules for `dir`, a subdirectory of the directory these rules belong to, adding the
+    /// patterns of its own ignore file if it has one
+    pub(crate) async fn enter(&self, dir: &Path) -> Result<Self> {
+        let file = dir.join(IGNORE_FILE_NAME);
+        let contents = match tokio::fs::read_to_string(&file).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(self.clone()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read ignore file: {}", file.display()));
+            }
+        };
+
+        let mut builder = GitignoreBuilder::new(dir);
+        for line in contents.lines() {
+            builder
+                .add_line(Some(file.clone()), line)
+                .with_context(|| format!("Invalid pattern in {}: {line}", file.display()))?;
+        }
+        let matcher = builder
+            .build()
+            .with_context(|| format!("Invalid ignore file: {}", file.display()))?;
+
+        let mut matchers = self.matchers.as_ref().clone();
+        matchers.push(matcher);
+        Ok(Self {
+            matchers: Arc::new(matchers),
+        })
+    }
+
+    /// The rules for a file below `root`, reading the ignore files of every directory on the
+    /// way. Returns `None` when one of those directories is itself ignored.
+    pub(crate) async fn for_file(root: &Path, path: &Path) -> Result<Option<Self>> {
+        let mut rules = Self::default().enter(root).await?;
+        let Some(relative) = path.parent().and_then(|dir| dir.strip_prefix(root).ok()) else {
+            return Ok(Some(rules));
+        };
+
+        let mut dir = root.to_owned();
+        for component in relative.components() {
+            dir.push(component);
+            if rules.is_ignored(&dir, true) {
+                return Ok(None);
+            }
+            rules = rules.enter(&dir).await?;
+        }
+        Ok(Some(rules))
+    }
+
+    /// Whether a file or directory directly inside the directory these rules belong to is
+    /// ignored. Patterns in deeper ignore files take precedence, so they can re-include paths
+    /// with `!`.
+    pub(crate) fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if path
+            .file_name()
+            .is_some_and(|name| name == IGNORE_FILE_NAME)
+        {
+            return true;
+        }
+        self.matchers
+            .iter()
+            .rev()
+            .map(|matcher| matcher.matched(path, is_dir))
+            .find(|matched| !matched.is_none())
+            .is_some_and(|matched| matched.is_ignore())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_ignore_rules() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let sub = root.join("sub");
+        std::fs::create_dir(&sub)?;
+        std::fs::write(root.join(IGNORE_FILE_NAME), "*.orig.png\nassets/\n")?;
+        std::fs::write(sub.join(IGNORE_FILE_NAME), "!keep.orig.png\n")?;
+
+        let rules = IgnoreRules::default().enter(root).await?;
+        assert!(rules.is_ignored(&root.join("a.orig.png"), false));
+        assert!(rules.is_ignored(&root.join("assets"), true));
+        assert!(!rules.is_ignored(&root.join("a.png"), false));
+
+        let rules = rules.enter(&sub).await?;
+        assert!(rules.is_ignored(&sub.join("b.orig.png"), false));
+        assert!(!rules.is_ignored(&sub.join("keep.orig.png"), false));
+
+        assert!(
+            IgnoreRules::for_file(root, &root.join("assets/a.png"))
+                .await?
+                .is_none()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,5901 @@
+#![warn(clippy::all, clippy::pedantic)]
+
+//! Remove letterboxing from images.
+//!
+//! The [`process_file`] and [`process_directory`] functions implement the `remove-letterbox`
+//! command line tool and can be used directly from async code running on tokio.
+//! They fail with a [`LetterboxError`], which tells I/O errors, images that cannot be decoded
+//! or encoded, and unsupported formats apart.
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use globset::GlobSet;
+use ignore_file::IgnoreRules;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{
+    DynamicImage, GenericImageView, ImageDecoder, ImageEncoder, ImageFormat, Rgb, RgbImage, Rgba,
+};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use little_exif::exif_tag::ExifTag;
+use little_exif::filetype::FileExtension;
+use little_exif::metadata::Metadata;
+use log::{debug, info, warn};
+use profile::StageTimes;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::{Cursor, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Semaphore, mpsc};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+
+mod cache;
+mod crop_log;
+mod dedupe;
+mod error;
+mod events;
+mod gif;
+#[cfg(feature = "heic")]
+mod heic;
+mod ico;
+mod ignore_file;
+mod marker;
+mod multipage;
+mod palette;
+mod parallel;
+mod pattern;
+#[cfg(feature = "psd")]
+mod photoshop;
+mod preview;
+mod profile;
+mod report;
+mod retry;
+mod sidecar;
+mod triage;
+mod watch;
+mod webp;
+
+pub use cache::ResultCache;
+pub use crop_log::CropLog;
+pub use dedupe::DedupeAction;
+pub use error::LetterboxError;
+pub use events::ProgressEvents;
+pub use pattern::OutputPattern;
+pub use profile::Profile;
+pub use report::{ReportEntry, ReportFormat, Stats, Status, Summary, write_report};
+pub use sidecar::{undo_directory, undo_file};
+pub use watch::watch_directory;
+
+/// Log target of the messages about files that are skipped, for telling them apart from other
+/// messages of the same level
+pub const SKIPPED_LOG_TARGET: &str = "remove_letterbox::skipped";
+
+/// Log target of the messages about images that were cropped
+pub const CROPPED_LOG_TARGET: &str = "remove_letterbox::cropped";
+
+/// Options controlling how images are processed
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Config {
+    /// Per-channel RGB thresholds for letterbox detection (0-255). Pixels whose red, green,
+    /// and blue values are all at or below the corresponding threshold are considered part of
+    /// the letterbox.
+    pub threshold: [u8; 3],
+
+    /// Thresholds that replace [`Config::threshold`] when looking for the border on one side,
+    /// for sources whose bars differ in brightness. Sides without one use the global threshold.
+    pub side_thresholds: SideThresholds,
+
+    /// Estimate the threshold for each image instead of using [`Config::threshold`], from the
+    /// color of its edges or its corners, see [`AutoThreshold`].
+    pub auto_threshold: Option<AutoThreshold>,
+
+    /// Descend into subdirectories when processing a directory
+    pub recursive: bool,
+
+    /// How many levels of subdirectories to descend into when processing recursively, where
+    /// 0 means only the top directory. When `None`, there is no limit.
+    pub max_depth: Option<usize>,
+
+    /// Descend into symlinked directories when processing recursively. Symlinked files are
+    /// always processed if they resolve to a regular file.
+    pub follow_symlinks: bool,
+
+    /// Directory for cropped copies. When `None`, images are modified in place.
+    pub output: Option<PathBuf>,
+
+    /// Write every cropped copy directly into [`Config::output`] instead of mirroring the
+    /// subdirectories of a recursive run. Copies of images with the same name are renamed as
+    /// with [`OverwritePolicy::Rename`], while files already in the output directory follow
+    /// [`Config::overwrite`].
+    pub flatten: bool,
+
+    /// How to handle files that already exist in [`Config::output`]
+    pub overwrite: OverwritePolicy,
+
+    /// Template that cropped copies in [`Config::output`] are named after, instead of keeping
+    /// the name of the original. [`Config::overwrite`] applies to the rendered name.
+    pub output_pattern: Option<OutputPattern>,
+
+    /// Which borders to detect and remove
+    pub axis: Axis,
+
+    /// Sides of the image that may be cropped. Borders detected on the other sides are kept.
+    pub sides: Sides,
+
+    /// Detect light borders instead of dark ones. Pixels whose channels are all at or above
+    /// `255 - threshold` are considered part of the border.
+    pub invert: bool,
+
+    /// Color of the borders to remove. Pixels whose channels are all within the threshold of
+    /// this color are considered part of the border. When `None`, borders are black, or white
+    /// with [`Config::invert`]. [`Config::auto_threshold`] only applies to those.
+    pub border_color: Option<BorderColor>,
+
+    /// Trim any uniform margin instead of only letterbox bars: crop to the bounding box of
+    /// pixels differing from the dominant edge color by more than the threshold, on all four
+    /// sides. Overrides [`Config::axis`], [`Config::invert`], [`Config::border_color`], and
+    /// [`Config::tolerance`].
+    pub autocrop: bool,
+
+    /// Maximum percentage (0-100) of pixels in a row or column that may fall outside the
+    /// threshold while the line still counts as letterbox. Useful for compression noise.
+    pub tolerance: u8,
+
+    /// Remove only rows and columns whose every pixel is exactly the border color, for clean
+    /// synthetic sources. Overrides [`Config::threshold`], [`Config::side_thresholds`],
+    /// [`Config::auto_threshold`], [`Config::tolerance`], [`Config::rounded_corners`],
+    /// [`Config::sample_step`], and [`Config::feather`].
+    pub exact: bool,
+
+    /// What to do with images that consist entirely of letterbox pixels. Applies to
+    /// single-frame images, not to animated GIFs or WebPs, multi-page TIFFs, or JXL files.
+    pub blank_policy: BlankPolicy,
+
+    /// Ignore [`Config::corner_radius`] pixels at both ends of each row and column when looking
+    /// for the edge of the content, for screenshots whose content has rounded black corners
+    pub rounded_corners: bool,
+
+    /// Radius of the rounded corners ignored with [`Config::rounded_corners`], in pixels
+    pub corner_radius: u32,
+
+    /// Check only every Nth pixel along a row or column when deciding whether it is letterbox,
+    /// which speeds up detection on very large images. [`Config::tolerance`] applies to the
+    /// checked pixels. A large step can miss thin bright streaks in a bar and crop into it.
+    /// Values below 1 count as 1, which checks every pixel.
+    pub sample_step: u32,
+
+    /// Extend the crop by up to this many rows or columns on each side with a letterbox, as
+    /// long as they keep moving away from the border color toward the content. Trims the
+    /// anti-aliased edges of bars that fade into the picture. Does not apply to
+    /// [`Config::autocrop`].
+    pub feather: u32,
+
+    /// Largest share (0-100) of the image's width or height that may be cropped away. Crops
+    /// exceeding it are refused and the image is left unchanged.
+    pub max_crop_percent: u8,
+
+    /// Smallest border, in pixels, that is cropped from a side. Thinner borders, such as a
+    /// one-pixel dark frame, are kept. 0 crops borders of any size.
+    pub min_border: u32,
+
+    /// Smallest detection confidence (0-1) at which a crop is applied, see
+    /// [`CropResult::confidence`]. Less confident crops are refused and the image is left
+    /// unchanged. 0 applies every crop. Not used for animations or TIFFs with shared page crops.
+    pub min_confidence: f32,
+
+    /// Crop the same amount from opposite sides, using the smaller of the two detected borders
+    pub symmetric: bool,
+
+    /// Pixels of border to keep on each cropped side, clamped to the image bounds
+    pub pad: u32,
+
+    /// Trim cropped images so their width and height are multiples of this value. Values of 0
+    /// and 1 leave the dimensions as detected.
+    pub round_to: u32,
+
+    /// Detect the letterbox of animated GIFs and WebPs on every frame and crop all frames to the
+    /// union of their content, instead of using the crop detected on the first frame
+    pub per_frame_crop: bool,
+
+    /// Detect the letterbox of animated GIFs and WebPs on this frame, counting from 0, instead
+    /// of the first. Dry-run previews show this frame. Images with a single frame fail when it
+    /// is not 0.
+    pub frame_select: usize,
+
+    /// Crop every page of a multi-page TIFF to the union of their content, instead of
+    /// cropping each page to its own content
+    pub shared_page_crop: bool,
+
+    /// Scale cropped images to this size before they are encoded. Applies to single-frame
+    /// images, not to animated GIFs or WebPs or TIFFs. Images are only scaled when they are
+    /// written, so images processed in place that have no letterbox keep their size.
+    pub resize: Option<Resize>,
+
+    /// Filter used to scale images with [`Config::resize`]
+    pub resize_filter: ResizeFilter,
+
+    /// Convert cropped images to this format, changing their extension to match. Animated
+    /// GIFs and WebPs and TIFFs keep their format. When `None`, images keep the format they
+    /// were read in, found from their contents rather than their extension, so that a PNG named
+    /// `.jpg` stays a PNG. Images read in a format that cannot be written, such as PSD, are
+    /// written in the format of their destination's extension.
+    pub format: Option<OutputFormat>,
+
+    /// JPEG quality (0-100) used when writing cropped images. Ignored for lossless formats.
+    pub quality: u8,
+
+    /// Color that transparent areas are composited onto when an image with an alpha channel
+    /// is written as JPEG, which cannot store transparency. Lossless formats keep the alpha
+    /// channel and ignore this color.
+    pub background: [u8; 3],
+
+    /// Keep the original file when an image is converted in place to another format. PSD files
+    /// are always kept, since the layers flattened into the written image would be lost.
+    pub keep_original: bool,
+
+    /// Directory that images cropped in place are moved to once written. Not used when
+    /// writing copies to [`Config::output`].
+    pub cropped_dir: Option<PathBuf>,
+
+    /// Directory that images processed in place without a letterbox are moved to. Not used
+    /// when writing copies to [`Config::output`].
+    pub unchanged_dir: Option<PathBuf>,
+
+    /// Only report what would be cropped without modifying any files
+    pub dry_run: bool,
+
+    /// Directory that dry runs write a PNG preview of each image to, with the predicted crop
+    /// outlined. Previews are named after the image's file name, in subdirectories mirroring
+    /// those of the processed directory.
+    pub preview_dir: Option<PathBuf>,
+
+    /// Suffix for backups of originals. When set, an image is copied to a sibling file with
+    /// this suffix appended before it is overwritten in place, and a `.letterbox.json` sidecar
+    /// next to the cropped image records the crop so that [`undo_file`] can restore it.
+    pub backup: Option<String>,
+
+    /// Overwrite existing backups, and process images that were marked by [`Config::mark`]
+    pub force: bool,
+
+    /// Mark written images as processed, with a PNG text chunk or an extended attribute for
+    /// other formats. Marked images are skipped unless [`Config::force`] is set.
+    pub mark: bool,
+
+    /// Drop EXIF metadata instead of copying it from the original into the cropped image
+    pub strip_metadata: bool,
+
+    /// Drop the ICC color profile instead of embedding it in the cropped image. Profiles are
+    /// carried over into PNG, JPEG, and WebP output.
+    pub strip_icc: bool,
+
+    /// Give the written image the modification time of the original
+    pub preserve_mtime: bool,
+
+    /// Decode each written image again and check that it has the dimensions of the crop,
+    /// failing the file if it is corrupt or has the wrong size. With
+    /// [`Config::continue_on_error`], a failed check is only logged as a warning.
+    pub verify: bool,
+
+    /// Only process files with one of these extensions (case-insensitive, without the dot).
+    /// When `None`, every supported image is processed.
+    pub extensions: Option<Vec<String>>,
+
+    /// Skip files whose name, or path relative to the processed directory, matches one of
+    /// these patterns
+    pub exclude: GlobSet,
+
+    /// Skip directories whose name matches one of these patterns when walking a tree. Excluded
+    /// directories are never read, so nothing below them is processed.
+    pub exclude_dir: GlobSet,
+
+    /// Skip files last modified before this time when walking a directory, without reading
+    /// them. Files given explicitly are always processed.
+    pub since: Option<SystemTime>,
+
+    /// Skip images narrower than this many pixels
+    pub min_width: u32,
+
+    /// Skip images shorter than this many pixels
+    pub min_height: u32,
+
+    /// Skip images with more pixels than this, with a warning. The size is read from the
+    /// header before the image is decoded, so huge images and decompression bombs are never
+    /// allocated.
+    pub max_pixels: Option<u64>,
+
+    /// After processing a directory, move or delete every image whose contents are identical
+    /// to an earlier one, by path order, in the output directory or the processed directory.
+    /// Duplicates are moved to a `duplicates` directory there.
+    pub dedupe: Option<DedupeAction>,
+
+    /// Stop walking a directory once this many images have been found and queued, for trying
+    /// out settings on part of a large directory. Other files do not count toward the limit.
+    pub max_files: Option<usize>,
+
+    /// Maximum number of files processed concurrently when processing a directory
+    pub jobs: usize,
+
+    /// Process the files of a directory on a rayon thread pool of [`Config::jobs`] threads once
+    /// the walk has found them all, instead of as tokio tasks while walking. Still images are
+    /// processed synchronously on the pool threads unless a setting needs the async pipeline.
+    /// JXL files are still processed as tokio tasks.
+    pub parallel: bool,
+
+    /// Read directories on a separate task that runs up to [`Config::read_ahead`] files ahead
+    /// of processing, instead of waiting for a free slot before reading the next entry. Helps
+    /// on network file systems, where listing directories is slow.
+    pub parallel_io: bool,
+
+    /// Number of found files that may wait for processing with [`Config::parallel_io`]
+    pub read_ahead: usize,
+
+    /// Show a progress bar on stdout while processing a directory
+    pub progress: bool,
+
+    /// Fail [`process_file`] for files that are not supported images instead of skipping
+    /// them. Files found while processing a directory are still skipped.
+    pub strict_input: bool,
+
+    /// Keep processing a directory when a file fails, collecting the failures instead of
+    /// aborting on the first one
+    pub continue_on_error: bool,
+
+    /// How many times to retry reading or writing a file that failed on a transient I/O error,
+    /// such as a timeout or `EIO` on network storage. Only the failed read or write is
+    /// repeated. Decoding errors and permanent I/O errors are not retried.
+    pub retries: u32,
+
+    /// Time to wait before the first retry, doubling with every further one
+    pub retry_delay: Duration,
+
+    /// Give up on a file that takes longer than this to process, including its retries, and
+    /// count it as failed. A decoder stuck on a malformed image keeps its thread busy until it
+    /// returns, but the run moves on to the next file.
+    pub timeout: Option<Duration>,
+
+    /// Skip images that an earlier run left without a letterbox, as long as their size and
+    /// modification time are unchanged, and record the images processed in place. The cache
+    /// is not written back automatically, see [`ResultCache::save`].
+    pub cache: Option<Arc<ResultCache>>,
+
+    /// Time the decode, detection, and encode stages of each image, logging them at debug
+    /// level and adding them to the totals of this profile
+    pub profile: Option<Arc<Profile>>,
+
+    /// Append a line for every image cropped to this log, which is kept across runs. Dry runs
+    /// are not logged.
+    pub crop_log: Option<Arc<CropLog>>,
+
+    /// Write a JSON line when each image starts and finishes processing, for tools that show
+    /// progress as it happens
+    pub progress_events: Option<Arc<ProgressEvents>>,
+
+    /// Counters that the outcome of every processed file is added to. Directory runs replace
+    /// them with counters of their own, see [`RunReport::summary`].
+    pub stats: Option<Arc<Stats>>,
+
+    /// Paths in [`Config::output`] given so far to the images of a [`Config::flatten`] run.
+    /// Directory runs start from an empty set when this is `None`. Runs sharing one keep the
+    /// names of all their images apart.
+    pub flat_targets: Option<Arc<std::sync::Mutex<FlatTargets>>>,
+
+    /// Stop starting new files when Ctrl-C is pressed while processing a directory, letting
+    /// files in progress finish. The run is then marked as [`RunReport::interrupted`].
+    pub interruptible: bool,
+}
+
+/// What to do when a cropped copy would replace an existing file in the output directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OverwritePolicy {
+    /// Leave the existing file alone and skip the image
+    #[default]
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Write to a new file with a numeric suffix, e.g. `name-1.png`
+    Rename,
+}
+
+/// How [`Config::auto_threshold`] estimates the threshold of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AutoThreshold {
+    /// From the dominant color of the outermost rows and columns. Images whose edges show no
+    /// clear border are left unchanged.
+    #[default]
+    Edges,
+    /// From the four corner pixels, which is cheaper. When the corners do not share a border
+    /// color, [`Config::threshold`] is used instead, with a warning.
+    Corners,
+}
+
+/// What to do with an image that consists entirely of letterbox pixels, such as an all-black
+/// frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum BlankPolicy {
+    /// Leave the image alone and skip it
+    #[default]
+    Skip,
+    /// Treat the image like one without a letterbox, writing it unchanged
+    Keep,
+    /// Delete the image, when processing in place
+    Delete,
+}
+
+/// Image format that cropped images are converted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Lossless PNG
+    Png,
+    /// Lossy JPEG, with transparency flattened onto [`Config::background`]
+    Jpeg,
+    /// Lossless WebP
+    Webp,
+}
+
+impl OutputFormat {
+    fn image_format(self) -> ImageFormat {
+        match self {
+            Self::Png => ImageFormat::Png,
+            Self::Jpeg => ImageFormat::Jpeg,
+            Self::Webp => ImageFormat::WebP,
+        }
+    }
+
+    /// File extension of images written in this format
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+        }
+    }
+}
+
+/// Size that cropped images are scaled to, see [`Config::resize`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resize {
+    /// Width to scale to, or `None` to follow the height and keep the aspect ratio
+    pub width: Option<u32>,
+    /// Height to scale to, or `None` to follow the width and keep the aspect ratio
+    pub height: Option<u32>,
+}
+
+impl Resize {
+    /// Dimensions that an image of the given dimensions is scaled to. A missing width or height
+    /// is computed from the aspect ratio of the image, rounded to the nearest pixel.
+    #[must_use]
+    pub fn dimensions(self, (width, height): (u32, u32)) -> (u32, u32) {
+        let scale = |length: u32, from: u32, to: u32| {
+            let from = u64::from(from.max(1));
+            let scaled = (u64::from(length) * u64::from(to) + from / 2) / from;
+            u32::try_from(scaled).unwrap_or(u32::MAX).max(1)
+        };
+        match (self.width, self.height) {
+            (Some(to_width), Some(to_height)) => (to_width, to_height),
+            (Some(to_width), None) => (to_width, scale(height, width, to_width)),
+            (None, Some(to_height)) => (scale(width, height, to_height), to_height),
+            (None, None) => (width, height),
+        }
+    }
+}
+
+/// Filter used to scale images with [`Config::resize`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ResizeFilter {
+    /// Nearest neighbor, keeping hard pixel edges
+    Nearest,
+    /// Linear interpolation
+    Triangle,
+    /// Cubic Catmull-Rom interpolation
+    #[value(name = "catmullrom")]
+    CatmullRom,
+    /// Lanczos with a window of 3, the sharpest
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            Self::Nearest => FilterType::Nearest,
+            Self::Triangle => FilterType::Triangle,
+            Self::CatmullRom => FilterType::CatmullRom,
+            Self::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Color of the borders to remove
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BorderColor {
+    /// Use the dominant color of each image's uniform edges
+    Auto,
+    /// Always use this RGB color
+    Rgb([u8; 3]),
+}
+
+/// Direction in which borders are removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Axis {
+    /// Remove top and bottom bars (letterboxing)
+    Vertical,
+    /// Remove left and right bars (pillarboxing)
+    Horizontal,
+    /// Remove bars on all four sides
+    #[default]
+    Both,
+}
+
+impl Axis {
+    /// Whether top and bottom bars are removed
+    fn crops_vertical(self) -> bool {
+        matches!(self, Self::Vertical | Self::Both)
+    }
+
+    /// Whether left and right bars are removed
+    fn crops_horizontal(self) -> bool {
+        matches!(self, Self::Horizontal | Self::Both)
+    }
+}
+
+/// Per-channel RGB thresholds for the border on each side of an image, where `None` falls
+/// back to [`Config::threshold`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SideThresholds {
+    pub top: Option<[u8; 3]>,
+    pub bottom: Option<[u8; 3]>,
+    pub left: Option<[u8; 3]>,
+    pub right: Option<[u8; 3]>,
+}
+
+/// Sides of an image that may be cropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Sides {
+    pub top: bool,
+    pub bottom: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+impl Sides {
+    /// Every side may be cropped
+    pub const ALL: Self = Self {
+        top: true,
+        bottom: true,
+        left: true,
+        right: true,
+    };
+}
+
+impl Default for Sides {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            threshold: [10; 3],
+            side_thresholds: SideThresholds::default(),
+            auto_threshold: None,
+            recursive: false,
+            max_depth: None,
+            follow_symlinks: false,
+            output: None,
+            flatten: false,
+            overwrite: OverwritePolicy::default(),
+            output_pattern: None,
+            axis: Axis::default(),
+            sides: Sides::default(),
+            invert: false,
+            border_color: None,
+            autocrop: false,
+            tolerance: 0,
+            exact: false,
+            blank_policy: BlankPolicy::Skip,
+            rounded_corners: false,
+            corner_radius: 32,
+            sample_step: 1,
+            feather: 0,
+            max_crop_percent: 50,
+            min_border: 0,
+            min_confidence: 0.0,
+            symmetric: false,
+            pad: 0,
+            round_to: 1,
+            per_frame_crop: false,
+            frame_select: 0,
+            shared_page_crop: false,
+            resize: None,
+            resize_filter: ResizeFilter::default(),
+            format: None,
+            quality: 90,
+            background: [255, 255, 255],
+            keep_original: false,
+            cropped_dir: None,
+            unchanged_dir: None,
+            dry_run: false,
+            preview_dir: None,
+            backup: None,
+            force: false,
+            mark: false,
+            strip_metadata: false,
+            strip_icc: false,
+            preserve_mtime: false,
+            verify: false,
+            extensions: None,
+            exclude: GlobSet::empty(),
+            exclude_dir: GlobSet::empty(),
+            since: None,
+            min_width: 0,
+            min_height: 0,
+            max_pixels: None,
+            dedupe: None,
+            max_files: None,
+            jobs: std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            parallel: false,
+            parallel_io: false,
+            read_ahead: 256,
+            progress: false,
+            continue_on_error: false,
+            retries: 0,
+            retry_delay: Duration::from_millis(500),
+            timeout: None,
+            strict_input: false,
+            cache: None,
+            profile: None,
+            crop_log: None,
+            progress_events: None,
+            stats: None,
+            flat_targets: None,
+            interruptible: false,
+        }
+    }
+}
+
+/// Directories that the copies of an image are written to, mirroring the place of the image
+/// in the walked tree
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Destinations {
+    /// Directory of the cropped copy, see [`Config::output`]
+    pub(crate) output: Option<PathBuf>,
+    /// Directory of the dry-run preview, see [`Config::preview_dir`]
+    pub(crate) preview: Option<PathBuf>,
+}
+
+impl Destinations {
+    /// Destinations of the images at the top of a run
+    fn new(config: &Config) -> Self {
+        Self {
+            output: config.output.clone(),
+            preview: config.preview_dir.clone(),
+        }
+    }
+
+    /// Destinations of the images in the subdirectory `name`. Previews always mirror the
+    /// tree, cropped copies only unless [`Config::flatten`] is set.
+    fn join(&self, name: &std::ffi::OsStr, config: &Config) -> Self {
+        let output = if config.flatten {
+            self.output.clone()
+        } else {
+            self.output.as_ref().map(|output| output.join(name))
+        };
+        Self {
+            output,
+            preview: self.preview.as_ref().map(|preview| preview.join(name)),
+        }
+    }
+}
+
+/// Paths in the output directory given to the images of [`Config::flatten`] runs, so that
+/// images of the same name from different directories are told apart even before either is
+/// written, and an image processed again keeps the name it was given
+#[derive(Debug, Default)]
+pub struct FlatTargets {
+    /// Path given to each input image
+    by_input: BTreeMap<PathBuf, PathBuf>,
+    /// Every path given so far
+    given: BTreeSet<PathBuf>,
+}
+
+/// Choose the path in `output` that a cropped copy of `path` is written to, following
+/// [`Config::overwrite`] when a file of that name already exists. Returns `None` when the image
+/// should be skipped.
+fn output_target(path: &Path, output: &Path, config: &Config) -> Result<Option<PathBuf>> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Input path has no file name: {}", path.display()))?;
+    // Patterned names depend on the crop, so they are only checked once it is written
+    if config.output_pattern.is_some() {
+        return Ok(Some(pattern::staging_path(path, output)));
+    }
+    let mut flat = config
+        .flat_targets
+        .as_deref()
+        .filter(|_| config.flatten)
+        .map(|flat| flat.lock().unwrap_or_else(PoisonError::into_inner));
+    let previous = flat
+        .as_ref()
+        .and_then(|flat| flat.by_input.get(path))
+        .filter(|previous| previous.parent() == Some(output))
+        .cloned();
+    // Paths given to another image of a flattened run are taken even if not written yet
+    let is_reserved = |target: &Path| {
+        previous.as_deref() != Some(target)
+            && flat
+                .as_ref()
+                .is_some_and(|flat| flat.given.contains(target))
+    };
+    // JXL copies are replaced by a PNG and converted images get a new extension, so the file
+    // that is finally written is the one that has to be checked
+    let exists = |target: &Path| converted_path(target, config.format).exists();
+    let renamed = || {
+        let stem = path.file_stem().unwrap_or(file_name);
+        (1..=u32::MAX)
+            .map(|n| {
+                let mut name = stem.to_owned();
+                name.push(format!("-{n}"));
+                if let Some(extension) = path.extension() {
+                    name.push(".");
+                    name.push(extension);
+                }
+                output.join(name)
+            })
+            .find(|candidate| !is_reserved(candidate) && !exists(candidate))
+    };
+
+    let target = previous.clone().unwrap_or_else(|| output.join(file_name));
+    let chosen = if is_reserved(&target) {
+        renamed()
+    } else if !exists(&target) {
+        Some(target)
+    } else {
+        match config.overwrite {
+            OverwritePolicy::Skip => None,
+            OverwritePolicy::Overwrite => Some(target),
+            OverwritePolicy::Rename => renamed(),
+        }
+    };
+    if let (Some(flat), Some(chosen)) = (flat.as_mut(), &chosen) {
+        flat.by_input.insert(path.to_owned(), chosen.clone());
+        flat.given.insert(chosen.clone());
+    }
+    Ok(chosen)
+}
+
+/// Copy the input file to `target` and return the copy's path.
+/// When no target is given, the input path itself is returned.
+async fn prepare_target(path: &Path, target: Option<&Path>) -> Result<PathBuf> {
+    let Some(target) = target else {
+        return Ok(path.to_owned());
+    };
+
+    if let Some(output) = target.parent() {
+        tokio::fs::create_dir_all(output)
+            .await
+            .with_context(|| format!("Failed to create output directory: {}", output.display()))?;
+    }
+    tokio::fs::copy(path, target)
+        .await
+        .with_context(|| format!("Failed to copy {} to {}", path.display(), target.display()))?;
+
+    Ok(target.to_owned())
+}
+
+/// Region of an image that remains after removing the letterbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropRect {
+    /// Left edge of the region
+    pub x: u32,
+    /// Top edge of the region
+    pub y: u32,
+    /// Width of the region
+    pub width: u32,
+    /// Height of the region
+    pub height: u32,
+}
+
+impl CropRect {
+    /// The whole of an image with the given dimensions
+    fn full((width, height): (u32, u32)) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// Smallest rectangle containing both `self` and `other`
+    fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+
+    /// Trim the rectangle so its width and height are multiples of `multiple`, splitting the
+    /// trimmed pixels between opposite sides
+    fn rounded(self, multiple: u32) -> Self {
+        let round = |start: u32, len: u32| {
+            let excess = len % multiple;
+            (start + excess / 2, len - excess)
+        };
+        let (x, width) = round(self.x, self.width);
+        let (y, height) = round(self.y, self.height);
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Shrink the larger of each pair of opposite borders to match the smaller one, keeping
+    /// the content centered in an image of the given dimensions
+    fn symmetric(self, (width, height): (u32, u32)) -> Self {
+        let vertical = self.y.min(height - self.y - self.height);
+        let horizontal = self.x.min(width - self.x - self.width);
+        Self {
+            x: horizontal,
+            y: vertical,
+            width: width - 2 * horizontal,
+            height: height - 2 * vertical,
+        }
+    }
+
+    /// Undo the crop on every side not selected in `sides`, for an image of the given
+    /// dimensions
+    fn restricted(self, sides: Sides, (width, height): (u32, u32)) -> Self {
+        let top = if sides.top { self.y } else { 0 };
+        let left = if sides.left { self.x } else { 0 };
+        let bottom = if sides.bottom {
+            height - self.y - self.height
+        } else {
+            0
+        };
+        let right = if sides.right {
+            width - self.x - self.width
+        } else {
+            0
+        };
+        Self {
+            x: left,
+            y: top,
+            width: width - left - right,
+            height: height - top - bottom,
+        }
+    }
+
+    /// Undo the crop on every side whose border is thinner than `min` pixels, for an image of
+    /// the given dimensions
+    fn without_thin_borders(self, min: u32, (width, height): (u32, u32)) -> Self {
+        let keep = |border: u32| if border < min { 0 } else { border };
+        let top = keep(self.y);
+        let bottom = keep(height - self.y - self.height);
+        let left = keep(self.x);
+        let right = keep(width - self.x - self.width);
+        Self {
+            x: left,
+            y: top,
+            width: width - left - right,
+            height: height - top - bottom,
+        }
+    }
+
+    /// Grow the rectangle by `pad` pixels on every side, without leaving an image of the given
+    /// dimensions
+    fn padded(self, pad: u32, (width, height): (u32, u32)) -> Self {
+        let x = self.x.saturating_sub(pad);
+        let y = self.y.saturating_sub(pad);
+        let right = (self.x + self.width).saturating_add(pad).min(width);
+        let bottom = (self.y + self.height).saturating_add(pad).min(height);
+        Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+/// Color channel of the pixels that detection runs on, so 16-bit images are compared at full
+/// precision. Thresholds are given in the 8-bit range and scaled to the channel.
+trait Channel: image::Primitive + Ord + std::hash::Hash + std::fmt::Debug {
+    /// Scale an 8-bit value to this channel's range
+    fn from_u8(value: u8) -> Self;
+    /// Scale this value to the 8-bit range
+    fn to_u8(self) -> u8;
+    fn abs_diff(self, other: Self) -> Self;
+    fn saturating_add(self, other: Self) -> Self;
+}
+
+impl Channel for u8 {
+    fn from_u8(value: u8) -> Self {
+        value
+    }
+
+    fn to_u8(self) -> u8 {
+        self
+    }
+
+    fn abs_diff(self, other: Self) -> Self {
+        u8::abs_diff(self, other)
+    }
+
+    fn saturating_add(self, other: Self) -> Self {
+        u8::saturating_add(self, other)
+    }
+}
+
+impl Channel for u16 {
+    fn from_u8(value: u8) -> Self {
+        u16::from(value) * 257
+    }
+
+    fn to_u8(self) -> u8 {
+        u8::try_from(self / 257).unwrap_or(u8::MAX)
+    }
+
+    fn abs_diff(self, other: Self) -> Self {
+        u16::abs_diff(self, other)
+    }
+
+    fn saturating_add(self, other: Self) -> Self {
+        u16::saturating_add(self, other)
+    }
+}
+
+/// Whether each RGB channel of `pixel` is within the matching channel of `threshold` of
+/// `color`
+fn is_border_pixel<T: Channel>(pixel: Rgba<T>, color: [T; 3], threshold: [T; 3]) -> bool {
+    (0..3).all(|c| pixel[c].abs_diff(color[c]) <= threshold[c])
+}
+
+/// Find the content area of an image by scanning for rows and columns of letterbox pixels.
+/// A pixel is part of the letterbox when each of its RGB values is within the matching
+/// channel of the threshold of `color`. Black letterboxes use a `color` of `[0, 0, 0]`.
+/// `thresholds` holds the threshold for finding the top, bottom, left, and right borders.
+/// A row or column counts as letterbox when no more than `tolerance` percent of its pixels
+/// fall outside the threshold. Only every `step`th pixel of a line is checked, and the
+/// tolerance applies to those. The first and last `corner` pixels of each line are ignored,
+/// so that rounded corners of the content do not count. Only the borders selected by `axis`
+/// are considered.
+///
+/// Returns `None` when there is nothing to crop, either because the image has no letterbox
+/// or because it consists entirely of letterbox pixels.
+fn letterbox_bounds<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+    img: &I,
+    thresholds: [[T; 3]; 4],
+    axis: Axis,
+    color: [T; 3],
+    tolerance: u8,
+    step: u32,
+    corner: u32,
+) -> Option<CropRect> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    // Keep at least the middle pixel of every line
+    let corner_x = corner.min((width - 1) / 2);
+    let corner_y = corner.min((height - 1) / 2);
+
+    let step = step.max(1) as usize;
+    let [
+        top_threshold,
+        bottom_threshold,
+        left_threshold,
+        right_threshold,
+    ] = thresholds;
+    let is_letterbox =
+        |x: u32, y: u32, threshold| is_border_pixel(img.get_pixel(x, y), color, threshold);
+    // A line is content when more than `tolerance` percent of its sampled pixels are not
+    // letterbox
+    let exceeds_tolerance = |content_pixels: usize, samples: usize| {
+        content_pixels as u64 * 100 > u64::from(tolerance) * samples as u64
+    };
+    let is_content_row = |y: u32, threshold| {
+        let samples = (corner_x..width - corner_x).step_by(step);
+        let content_pixels = samples
+            .clone()
+            .filter(|&x| !is_letterbox(x, y, threshold))
+            .count();
+        exceeds_tolerance(content_pixels, samples.len())
+    };
+    let is_content_column = |x: u32, threshold| {
+        let samples = (corner_y..height - corner_y).step_by(step);
+        let content_pixels = samples
+            .clone()
+            .filter(|&y| !is_letterbox(x, y, threshold))
+            .count();
+        exceeds_tolerance(content_pixels, samples.len())
+    };
+
+    // An image made entirely of letterbox pixels has no content to crop to, and neither has
+    // one whose borders overlap under different thresholds for opposite sides
+    let (top, bottom) = if axis.crops_vertical() {
+        let top = (0..height).find(|&y| is_content_row(y, top_threshold))?;
+        let bottom = (0..height)
+            .rev()
+            .find(|&y| is_content_row(y, bottom_threshold))?;
+        (top, bottom)
+    } else {
+        (0, height - 1)
+    };
+    let (left, right) = if axis.crops_horizontal() {
+        let left = (0..width).find(|&x| is_content_column(x, left_threshold))?;
+        let right = (0..width)
+            .rev()
+            .find(|&x| is_content_column(x, right_threshold))?;
+        (left, right)
+    } else {
+        (0, width - 1)
+    };
+    if top > bottom || left > right {
+        return None;
+    }
+
+    let rect = CropRect {
+        x: left,
+        y: top,
+        width: right - left + 1,
+        height: bottom - top + 1,
+    };
+    (rect.width != width || rect.height != height).then_some(rect)
+}
+
+/// Find the content area of an in-memory image with black letterbox bars on any side. A pixel
+/// is part of the letterbox when its red, green, and blue values are all at or below
+/// `threshold`.
+///
+/// This runs only the detection, without file I/O or the adjustments that [`Config`] applies
+/// when cropping files, such as padding or the maximum crop. The whole image is returned when
+/// there is nothing to crop.
+#[must_use]
+pub fn detect_letterbox(img: &DynamicImage, threshold: u8) -> CropRect {
+    let thresholds = [[threshold; 3]; 4];
+    let rect = if is_16_bit(img) {
+        letterbox_bounds(
+            &img.to_rgba16(),
+            thresholds.map(|threshold| threshold.map(u16::from_u8)),
+            Axis::Both,
+            [0; 3],
+            0,
+            1,
+            0,
+        )
+    } else {
+        letterbox_bounds(img, thresholds, Axis::Both, [0; 3], 0, 1, 0)
+    };
+    rect.unwrap_or_else(|| CropRect::full(img.dimensions()))
+}
+
+/// Total difference of a line of pixels from the border color, in 8-bit steps
+fn line_distance<T: Channel>(pixels: impl Iterator<Item = Rgba<T>>, color: [T; 3]) -> u64 {
+    pixels
+        .map(|pixel| {
+            pixel.0[..3]
+                .iter()
+                .zip(color)
+                .map(|(&c, border)| u64::from(c.abs_diff(border).to_u8()))
+                .sum::<u64>()
+        })
+        .sum()
+}
+
+/// Extend the content area of an image inward by up to `feather` lines on each side with a
+/// border, trimming lines that are closer to the border color than the next line inward. This
+/// removes the gradient where a letterbox bar fades into the content. At least one row and
+/// column of the content area is kept.
+fn feathered<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+    img: &I,
+    rect: CropRect,
+    color: [T; 3],
+    feather: u32,
+) -> CropRect {
+    if feather == 0 {
+        return rect;
+    }
+    let (width, height) = img.dimensions();
+    let row = |y: u32| {
+        line_distance(
+            (rect.x..rect.x + rect.width).map(|x| img.get_pixel(x, y)),
+            color,
+        )
+    };
+    let column = |x: u32| {
+        line_distance(
+            (rect.y..rect.y + rect.height).map(|y| img.get_pixel(x, y)),
+            color,
+        )
+    };
+    // Count the lines, from the outermost one inward, that are closer to the border than their
+    // successor
+    let rising = |lines: Vec<u64>| {
+        let count = lines
+            .windows(2)
+            .take_while(|pair| pair[0] < pair[1])
+            .count();
+        u32::try_from(count).unwrap_or(u32::MAX)
+    };
+
+    let vertical_limit = feather.min((rect.height - 1) / 2);
+    let horizontal_limit = feather.min((rect.width - 1) / 2);
+    let bottom_edge = rect.y + rect.height - 1;
+    let right_edge = rect.x + rect.width - 1;
+
+    let top = if rect.y > 0 {
+        rising((0..=vertical_limit).map(|k| row(rect.y + k)).collect())
+    } else {
+        0
+    };
+    let bottom = if bottom_edge < height - 1 {
+        rising((0..=vertical_limit).map(|k| row(bottom_edge - k)).collect())
+    } else {
+        0
+    };
+    let left = if rect.x > 0 {
+        rising((0..=horizontal_limit).map(|k| column(rect.x + k)).collect())
+    } else {
+        0
+    };
+    let right = if right_edge < width - 1 {
+        rising(
+            (0..=horizontal_limit)
+                .map(|k| column(right_edge - k))
+                .collect(),
+        )
+    } else {
+        0
+    };
+
+    CropRect {
+        x: rect.x + left,
+        y: rect.y + top,
+        width: rect.width - left - right,
+        height: rect.height - top - bottom,
+    }
+}
+
+/// Find the bounding box of the pixels that differ from the image's dominant edge color by
+/// more than `threshold`, looking at all four sides.
+///
+/// Returns the reason when there is nothing to crop to, because no edge has a uniform color or
+/// the whole image is that color. Returns `Ok(None)` when the content reaches every edge.
+fn autocrop_bounds<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+    img: &I,
+    threshold: [T; 3],
+) -> Result<Option<CropRect>, &'static str> {
+    let color = dominant_border_color(img, Axis::Both, threshold)?;
+    let (width, height) = img.dimensions();
+
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in img.pixels() {
+        if is_border_pixel(pixel, color, threshold) {
+            continue;
+        }
+        let (left, top, right, bottom) = bounds.get_or_insert((x, y, x, y));
+        *left = (*left).min(x);
+        *top = (*top).min(y);
+        *right = (*right).max(x);
+        *bottom = (*bottom).max(y);
+    }
+    let (left, top, right, bottom) = bounds.ok_or("the image is a single uniform color")?;
+
+    let rect = CropRect {
+        x: left,
+        y: top,
+        width: right - left + 1,
+        height: bottom - top + 1,
+    };
+    Ok((rect.width != width || rect.height != height).then_some(rect))
+}
+
+/// Amount added to the border color found by [`estimate_threshold`], to allow for noise
+const AUTO_THRESHOLD_MARGIN: u8 = 8;
+
+/// Percentage of an edge's pixels that must share one color for the edge to count as border
+const AUTO_THRESHOLD_MIN_SHARE: usize = 90;
+
+/// Largest difference in luminance between the corners of an image for [`corner_threshold`]
+/// to take them as one border color
+const CORNER_MAX_SPREAD: u8 = 24;
+
+/// Perceived brightness of a pixel, in the 8-bit range
+fn luminance<T: Channel>(pixel: &Rgba<T>) -> u8 {
+    let [r, g, b, _] = pixel.0.map(|c| u32::from(c.to_u8()));
+    u8::try_from((299 * r + 587 * g + 114 * b) / 1000).unwrap_or(u8::MAX)
+}
+
+/// Per-channel threshold [`AUTO_THRESHOLD_MARGIN`] beyond the most extreme channel values of
+/// the border pixels, above the brightest for dark borders or below the darkest with `invert`
+fn threshold_beyond<T: Channel>(border: &[&Rgba<T>], invert: bool) -> [T; 3] {
+    let mut threshold = [T::zero(); 3];
+    for (c, channel) in threshold.iter_mut().enumerate() {
+        let extreme = if invert {
+            let min = border.iter().map(|pixel| pixel[c]).min();
+            T::DEFAULT_MAX_VALUE - min.unwrap_or(T::DEFAULT_MAX_VALUE)
+        } else {
+            border
+                .iter()
+                .map(|pixel| pixel[c])
+                .max()
+                .unwrap_or(T::zero())
+        };
+        *channel = extreme.saturating_add(T::from_u8(AUTO_THRESHOLD_MARGIN));
+    }
+    threshold
+}
+
+/// Estimate a per-channel threshold from the four corner pixels, which are assumed to be part
+/// of the letterbox. They must all be dark, or light with `invert`, and close in brightness.
+///
+/// Returns the reason when the corners do not look like one border.
+fn corner_threshold<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+    img: &I,
+    invert: bool,
+) -> Result<[T; 3], &'static str> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return Err("the image is empty");
+    }
+    let corners = [
+        (0, 0),
+        (width - 1, 0),
+        (0, height - 1),
+        (width - 1, height - 1),
+    ]
+    .map(|(x, y)| img.get_pixel(x, y));
+    let brightness = corners.each_ref().map(luminance);
+    let darkest = brightness.iter().min().copied().unwrap_or_default();
+    let brightest = brightness.iter().max().copied().unwrap_or_default();
+    if brightest - darkest > CORNER_MAX_SPREAD {
+        return Err("the corners differ in color");
+    }
+    let bordered = if invert {
+        darkest >= 128
+    } else {
+        brightest < 128
+    };
+    if !bordered {
+        return Err("the corners are not the color of a border");
+    }
+    Ok(threshold_beyond(&corners.each_ref(), invert))
+}
+
+/// The outermost rows and columns of an image on the sides selected by `axis`
+fn edge_lines<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+    img: &I,
+    axis: Axis,
+) -> Vec<Vec<Rgba<T>>> {
+    let (width, height) = img.dimensions();
+    let mut edges = Vec::new();
+    if width == 0 || height == 0 {
+        return edges;
+    }
+    if axis.crops_vertical() {
+        for y in [0, height - 1] {
+            edges.push((0..width).map(|x| img.get_pixel(x, y)).collect());
+        }
+    }
+    if axis.crops_horizontal() {
+        for x in [0, width - 1] {
+            edges.push((0..height).map(|y| img.get_pixel(x, y)).collect());
+        }
+    }
+    edges
+}
+
+/// Find the border color of an image from the outermost rows and columns selected by `axis`.
+/// An edge is uniform when at least [`AUTO_THRESHOLD_MIN_SHARE`] percent of its pixels are
+/// within `threshold` of its most common color, and the uniform edge with the most such pixels
+/// decides the color.
+///
+/// Returns the reason when no edge is uniform.
+fn dominant_border_color<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+    img: &I,
+    axis: Axis,
+    threshold: [T; 3],
+) -> Result<[T; 3], &'static str> {
+    let mut best: Option<([T; 3], usize)> = None;
+    for edge in edge_lines(img, axis) {
+        let mut counts: HashMap<[T; 3], usize> = HashMap::new();
+        for pixel in &edge {
+            *counts.entry([pixel[0], pixel[1], pixel[2]]).or_default() += 1;
+        }
+        // Ties are broken by the color itself so the result does not depend on hash order
+        let Some((mode, _)) = counts
+            .into_iter()
+            .max_by_key(|&(color, count)| (count, color))
+        else {
+            continue;
+        };
+
+        let matching = edge
+            .iter()
+            .filter(|&&pixel| is_border_pixel(pixel, mode, threshold))
+            .count();
+        if matching * 100 >= edge.len() * AUTO_THRESHOLD_MIN_SHARE
+            && best.is_none_or(|(_, most)| matching > most)
+        {
+            best = Some((mode, matching));
+        }
+    }
+    best.map(|(color, _)| color)
+        .ok_or("no edge has a uniform border color")
+}
+
+/// Estimate a per-channel threshold from the outermost rows and columns selected by `axis`.
+/// Each edge whose pixels are dominated by one dark luminance, or a light one with `invert`,
+/// counts as border, and the threshold is placed [`AUTO_THRESHOLD_MARGIN`] beyond the most
+/// extreme channel values of those pixels.
+///
+/// Returns the reason when no edge looks like a border.
+fn estimate_threshold<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+    img: &I,
+    axis: Axis,
+    invert: bool,
+) -> Result<[T; 3], &'static str> {
+    let edges = edge_lines(img, axis);
+    if edges.is_empty() {
+        return Err("the image is empty");
+    }
+
+    let mut border: Vec<&Rgba<T>> = Vec::new();
+    for edge in &edges {
+        let mut histogram = [0usize; 256];
+        for pixel in edge {
+            histogram[usize::from(luminance(pixel))] += 1;
+        }
+        let mode = (0..=u8::MAX)
+            .max_by_key(|&l| histogram[usize::from(l)])
+            .unwrap_or_default();
+        if (mode >= 128) != invert {
+            continue;
+        }
+
+        let close = |pixel: &&Rgba<T>| luminance(pixel).abs_diff(mode) <= AUTO_THRESHOLD_MARGIN;
+        if edge.iter().filter(close).count() * 100 >= edge.len() * AUTO_THRESHOLD_MIN_SHARE {
+            border.extend(edge.iter().filter(close));
+        }
+    }
+    if border.is_empty() {
+        return Err("no edge has a uniform border color");
+    }
+    Ok(threshold_beyond(&border, invert))
+}
+
+/// Outcome of letterbox removal for a single image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropResult {
+    /// Dimensions of the image before cropping
+    pub original: (u32, u32),
+    /// Dimensions of the image after cropping
+    pub cropped: (u32, u32),
+    /// Pixels removed from the top edge
+    pub top: u32,
+    /// Pixels removed from the bottom edge
+    pub bottom: u32,
+    /// Pixels removed from the left edge
+    pub left: u32,
+    /// Pixels removed from the right edge
+    pub right: u32,
+    /// Whether a crop was applied, or would be applied in a dry run
+    pub modified: bool,
+    /// Whether the image consists entirely of letterbox pixels, see [`Config::blank_policy`]
+    pub blank: bool,
+    /// How sharply the image changes at the edges of the detected letterbox, from 0 for bars
+    /// that fade into the picture to 1 for a clean edge. 1 when no letterbox was detected, and
+    /// for animations and multi-page TIFFs.
+    pub confidence: f32,
+}
+
+impl CropResult {
+    /// Build the result for an image of the given dimensions and its detected content area
+    fn new((width, height): (u32, u32), rect: Option<CropRect>) -> Self {
+        match rect {
+            Some(rect) => Self {
+                original: (width, height),
+                cropped: (rect.width, rect.height),
+                top: rect.y,
+                bottom: height - rect.y - rect.height,
+                left: rect.x,
+                right: width - rect.x - rect.width,
+                modified: true,
+                blank: false,
+                confidence: 1.0,
+            },
+            None => Self {
+                original: (width, height),
+                cropped: (width, height),
+                top: 0,
+                bottom: 0,
+                left: 0,
+                right: 0,
+                modified: false,
+                blank: false,
+                confidence: 1.0,
+            },
+        }
+    }
+}
+
+/// Describe a crop, e.g. "test.png: 100x100 -> 100x50, removing 25px top/25px bottom"
+fn describe_crop(path: &Path, result: &CropResult) -> String {
+    let (width, height) = result.original;
+    if !result.modified {
+        return format!(
+            "{}: {width}x{height}, no letterbox detected",
+            path.display()
+        );
+    }
+
+    let sides = [
+        (result.top, "top"),
+        (result.bottom, "bottom"),
+        (result.left, "left"),
+        (result.right, "right"),
+    ];
+    let removed = sides
+        .iter()
+        .filter(|(amount, _)| *amount > 0)
+        .map(|(amount, side)| format!("{amount}px {side}"))
+        .collect::<Vec<_>>()
+        .join("/");
+    format!(
+        "{}: {width}x{height} -> {}x{}, removing {removed}",
+        path.display(),
+        result.cropped.0,
+        result.cropped.1
+    )
+}
+
+/// Read the file at `path`, repeating the read on transient errors, see [`Config::retries`]
+async fn read_file(path: &Path) -> Result<Vec<u8>> {
+    retry::with_retries(path, || async {
+        tokio::fs::read(path)
+            .await
+            .map_err(|err| LetterboxError::io(path, err).into())
+    })
+    .await
+}
+
+/// Read an image file, returning its raw bytes along with the decoded image
+async fn read_image(path: &Path) -> Result<(Vec<u8>, DynamicImage)> {
+    let bytes = read_file(path).await?;
+    let img = decode_image(&bytes, path)?;
+    Ok((bytes, img))
+}
+
+/// Decode the contents of the image file at `path`, which picks the decoder
+fn decode_image(bytes: &[u8], path: &Path) -> Result<DynamicImage> {
+    #[cfg(feature = "heic")]
+    if is_heic_file(path) {
+        return heic::decode_heic(bytes, path);
+    }
+    #[cfg(feature = "psd")]
+    if is_psd_file(path) {
+        return photoshop::decode_psd(bytes, path);
+    }
+    if ico::is_ico_file(path) {
+        return ico::decode_ico(bytes, path);
+    }
+    Ok(decode_upright(bytes, None).map_err(|err| LetterboxError::decode(path, err))?)
+}
+
+/// Decode encoded image data, in `format` or the format guessed from its contents, and turn it
+/// upright as its EXIF orientation says it is displayed. Letterboxes are detected and cropped
+/// in that orientation, and written images are stored upright, see [`copy_metadata`].
+fn decode_upright(bytes: &[u8], format: Option<ImageFormat>) -> image::ImageResult<DynamicImage> {
+    let mut reader = image::ImageReader::new(Cursor::new(bytes));
+    match format {
+        Some(format) => reader.set_format(format),
+        None => reader = reader.with_guessed_format()?,
+    }
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Read and decode an image file
+async fn load_image(path: &Path) -> Result<DynamicImage> {
+    Ok(read_image(path).await?.1)
+}
+
+/// Decode a JXL file by converting it to a temporary PNG, leaving the original untouched. The
+/// PNG gets a unique name, so that JXL files of the same name decoded at once never share it.
+async fn load_jxl_image(path: &Path) -> Result<DynamicImage> {
+    let temp_png = tempfile::Builder::new()
+        .prefix("remove-letterbox-")
+        .suffix(".png")
+        .tempfile()
+        .context("Failed to create temporary file for JXL conversion")?;
+    imx::convert_jxl_to_png(path, temp_png.path()).await?;
+    load_image(temp_png.path()).await
+}
+
+/// Decode an image file in any supported format, leaving it untouched. Only the first frame
+/// of animated GIFs is decoded.
+async fn decode_file(path: &Path) -> Result<DynamicImage> {
+    if imx::is_jxl_file(path) {
+        load_jxl_image(path).await
+    } else {
+        load_image(path).await
+    }
+}
+
+/// Read the dimensions of an image file, decoding only the header where possible
+async fn read_dimensions(path: &Path) -> Result<(u32, u32)> {
+    if imx::is_jxl_file(path) {
+        return Ok(load_jxl_image(path).await?.dimensions());
+    }
+    if is_heic_file(path) || is_psd_file(path) || ico::is_ico_file(path) {
+        return Ok(load_image(path).await?.dimensions());
+    }
+
+    let mut decoder = image::ImageReader::open(path)
+        .and_then(image::ImageReader::with_guessed_format)
+        .map_err(|err| LetterboxError::io(path, err))?
+        .into_decoder()
+        .map_err(|err| LetterboxError::decode(path, err))?;
+    // Images are decoded upright, see decode_upright
+    let (width, height) = decoder.dimensions();
+    match decoder.orientation() {
+        Ok(
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH,
+        ) => Ok((height, width)),
+        _ => Ok((width, height)),
+    }
+}
+
+/// The [`Config::max_pixels`] limit, if an image of these dimensions is over it
+fn exceeds_max_pixels(width: u32, height: u32, config: &Config) -> Option<u64> {
+    config
+        .max_pixels
+        .filter(|&max| u64::from(width) * u64::from(height) > max)
+}
+
+/// Which metadata of the original file to carry over into a written image
+#[derive(Debug, Clone, Copy)]
+struct KeepMetadata {
+    exif: bool,
+    icc: bool,
+}
+
+impl KeepMetadata {
+    fn new(config: &Config) -> Self {
+        Self {
+            exif: !config.strip_metadata,
+            icc: !config.strip_icc,
+        }
+    }
+}
+
+/// How cropped images are scaled and encoded, taken from a [`Config`]
+#[derive(Debug, Clone, Copy)]
+struct EncodeSettings {
+    keep: KeepMetadata,
+    quality: u8,
+    background: [u8; 3],
+    resize: Option<Resize>,
+    resize_filter: ResizeFilter,
+    format: Option<OutputFormat>,
+    mark: bool,
+}
+
+impl EncodeSettings {
+    fn new(config: &Config) -> Self {
+        Self {
+            keep: KeepMetadata::new(config),
+            quality: config.quality,
+            background: config.background,
+            resize: config.resize,
+            resize_filter: config.resize_filter,
+            format: config.format,
+            mark: config.mark,
+        }
+    }
+
+    /// Format that an image read from the `original` file contents is written to `path` in,
+    /// see [`Config::format`]
+    fn output_format(&self, original: &[u8], path: &Path) -> Result<ImageFormat, LetterboxError> {
+        if let Some(format) = self.format {
+            return Ok(format.image_format());
+        }
+        match image::guess_format(original) {
+            Ok(format) if format.writing_enabled() => Ok(format),
+            _ => ImageFormat::from_path(path).map_err(|_| LetterboxError::unsupported(path)),
+        }
+    }
+
+    /// Scale a cropped image to the size of [`Config::resize`], if it is set and differs
+    fn resized<'a>(&self, img: Cow<'a, DynamicImage>) -> Cow<'a, DynamicImage> {
+        let Some(resize) = self.resize else {
+            return img;
+        };
+        let (width, height) = resize.dimensions(img.dimensions());
+        if (width, height) == img.dimensions() {
+            return img;
+        }
+        Cow::Owned(img.resize_exact(width, height, self.resize_filter.filter_type()))
+    }
+}
+
+/// Composite an image onto a solid `background`, by the opacity of each pixel, for formats
+/// without an alpha channel
+fn flatten(img: &DynamicImage, background: [u8; 3]) -> RgbImage {
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let Rgba([red, green, blue, alpha]) = *rgba.get_pixel(x, y);
+        let alpha = u16::from(alpha);
+        let blend = |value: u8, background: u8| {
+            let mixed =
+                (u16::from(value) * alpha + u16::from(background) * (255 - alpha) + 127) / 255;
+            u8::try_from(mixed).unwrap_or(u8::MAX)
+        };
+        Rgb([
+            blend(red, background[0]),
+            blend(green, background[1]),
+            blend(blue, background[2]),
+        ])
+    })
+}
+
+/// The ICC color profile embedded in encoded image data, such as a PNG `iCCP` chunk or JPEG
+/// `APP2` markers. Only the header of the image is decoded.
+fn read_icc_profile(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut decoder = image::ImageReader::new(Cursor::new(encoded))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    decoder.icc_profile().ok().flatten()
+}
+
+/// Encode an image with `encoder`, embedding `icc_profile` when there is one. Encoders that
+/// cannot embed profiles write the image without it.
+fn write_with_profile(
+    img: &DynamicImage,
+    mut encoder: impl ImageEncoder,
+    icc_profile: Option<Vec<u8>>,
+    path: &Path,
+) -> image::ImageResult<()> {
+    if let Some(Err(err)) = icc_profile.map(|profile| encoder.set_icc_profile(profile)) {
+        warn!("Failed to keep color profile of {}: {err}", path.display());
+    }
+    img.write_with_encoder(encoder)
+}
+
+/// Copy the EXIF metadata of the `original` file contents into the freshly `encoded` image.
+/// Images are decoded upright, so the orientation is reset to normal. Failures are logged and
+/// leave the encoded image without metadata.
+fn copy_metadata(original: &[u8], encoded: &mut Vec<u8>, path: &Path) {
+    let (Some(source_type), Some(target_type)) = (
+        FileExtension::auto_detect(&mut Cursor::new(original)),
+        FileExtension::auto_detect(&mut Cursor::new(encoded.as_slice())),
+    ) else {
+        return;
+    };
+
+    let mut metadata = match Metadata::new_from_vec(&original.to_vec(), source_type) {
+        Ok(metadata) if !metadata.get_ifds().is_empty() => metadata,
+        Ok(_) => return,
+        Err(err) => {
+            debug!("No metadata read from {}: {err}", path.display());
+            return;
+        }
+    };
+    metadata.set_tag(ExifTag::Orientation(vec![1]));
+
+    let mut with_metadata = encoded.clone();
+    match metadata.write_to_vec(&mut with_metadata, target_type) {
+        Ok(()) => *encoded = with_metadata,
+        Err(err) => warn!("Failed to copy metadata to {}: {err}", path.display()),
+    }
+}
+
+/// Encode an image in the format chosen by [`EncodeSettings::output_format`] and write it to
+/// disk. JPEG images are encoded with the quality of `settings`, other formats are lossless.
+/// `original` holds the contents of the source file, whose EXIF metadata and ICC profile are
+/// carried over as selected by `settings`.
+async fn save_image(
+    img: &DynamicImage,
+    path: &Path,
+    original: &[u8],
+    settings: EncodeSettings,
+) -> Result<()> {
+    let format = settings.output_format(original, path)?;
+    let buf = encode_image(img, format, original, settings, path)?;
+    write_file_atomic(path, &buf).await
+}
+
+/// Encode an image in `format`, as described for [`save_image`]. The path is only used in
+/// messages.
+fn encode_image(
+    img: &DynamicImage,
+    format: ImageFormat,
+    original: &[u8],
+    settings: EncodeSettings,
+    path: &Path,
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    // JPEG has no alpha channel, so transparency is flattened onto the background, and neither
+    // JPEG nor WebP stores more than 8 bits per channel. Other formats keep the bit depth of
+    // the decoded image.
+    let img = match format {
+        ImageFormat::Jpeg if img.color().has_alpha() => {
+            Cow::Owned(DynamicImage::ImageRgb8(flatten(img, settings.background)))
+        }
+        ImageFormat::Jpeg
+            if !matches!(
+                img,
+                DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_)
+            ) =>
+        {
+            Cow::Owned(DynamicImage::ImageRgb8(img.to_rgb8()))
+        }
+        ImageFormat::WebP
+            if !matches!(
+                img,
+                DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_)
+            ) =>
+        {
+            Cow::Owned(DynamicImage::ImageRgba8(img.to_rgba8()))
+        }
+        _ => Cow::Borrowed(img),
+    };
+    let icc_profile = if settings.keep.icc {
+        read_icc_profile(original)
+    } else {
+        None
+    };
+    // Palette PNGs are decoded as RGB, so their cropped pixels are mapped back onto the
+    // palette. Profiles can only be embedded by the image encoder.
+    let indexed = if format == ImageFormat::Png && icc_profile.is_none() {
+        palette::encode_indexed(&img, original, settings.mark, path)
+    } else {
+        None
+    };
+    let encoded = if let Some(indexed) = indexed {
+        buf = indexed;
+        Ok(())
+    } else {
+        match format {
+            ImageFormat::Jpeg => write_with_profile(
+                &img,
+                JpegEncoder::new_with_quality(&mut buf, settings.quality),
+                icc_profile,
+                path,
+            ),
+            // The encoder of the image crate cannot write text chunks
+            ImageFormat::Png if settings.mark => {
+                marker::encode_marked_png(&img, icc_profile).map(|png| buf = png)
+            }
+            ImageFormat::Png => {
+                write_with_profile(&img, PngEncoder::new(&mut buf), icc_profile, path)
+            }
+            ImageFormat::WebP => {
+                write_with_profile(&img, WebPEncoder::new_lossless(&mut buf), icc_profile, path)
+            }
+            _ => {
+                if icc_profile.is_some() {
+                    debug!(
+                        "Dropping color profile, {format:?} output cannot embed it: {}",
+                        path.display()
+                    );
+                }
+                img.write_to(&mut Cursor::new(&mut buf), format)
+            }
+        }
+    };
+    encoded.map_err(|err| LetterboxError::encode(path, err))?;
+    if settings.keep.exif {
+        copy_metadata(original, &mut buf, path);
+    }
+    Ok(buf)
+}
+
+/// Temporary file that is removed when it is dropped, also when the task writing it is
+/// cancelled, such as on [`Config::timeout`]. Nothing is removed once it was renamed away.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it into place, so that an
+/// interrupted write never leaves a partially written image behind. An existing file keeps
+/// its permissions. If the rename is not possible, the file is written directly instead. The
+/// write is repeated on transient errors, see [`Config::retries`].
+async fn write_file_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    retry::with_retries(path, || write_file_once(path, contents)).await
+}
+
+/// Write a file once, as described for [`write_file_atomic`]
+async fn write_file_once(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(
+        path.file_name()
+            .with_context(|| format!("Failed to write image file: {}", path.display()))?,
+    );
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_file = TempFile(path.with_file_name(temp_name));
+    let temp = temp_file.0.as_path();
+
+    let written = async {
+        let mut file = tokio::fs::File::create(temp).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        if let Ok(metadata) = tokio::fs::metadata(path).await {
+            tokio::fs::set_permissions(temp, metadata.permissions()).await?;
+        }
+        tokio::fs::rename(temp, path).await
+    }
+    .await;
+
+    match written {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            warn!(
+                "Cannot replace {} atomically, writing it directly",
+                path.display()
+            );
+            let written = async {
+                let mut file = tokio::fs::File::create(path).await?;
+                file.write_all(contents).await?;
+                file.sync_all().await
+            }
+            .await;
+            written.map_err(|err| LetterboxError::io(path, err).into())
+        }
+        Err(err) => Err(LetterboxError::io(path, err).into()),
+    }
+}
+
+/// Write a file once without blocking on the runtime, for threads outside of it, as described
+/// for [`write_file_atomic`]
+fn write_file_blocking(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(
+        path.file_name()
+            .with_context(|| format!("Failed to write image file: {}", path.display()))?,
+    );
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_file = TempFile(path.with_file_name(temp_name));
+    let temp = temp_file.0.as_path();
+
+    let written = (|| {
+        let mut file = std::fs::File::create(temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            std::fs::set_permissions(temp, metadata.permissions())?;
+        }
+        std::fs::rename(temp, path)
+    })();
+
+    match written {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            warn!(
+                "Cannot replace {} atomically, writing it directly",
+                path.display()
+            );
+            let written = (|| {
+                let mut file = std::fs::File::create(path)?;
+                file.write_all(contents)?;
+                file.sync_all()
+            })();
+            written.map_err(|err| LetterboxError::io(path, err).into())
+        }
+        Err(err) => Err(LetterboxError::io(path, err).into()),
+    }
+}
+
+/// Fail with a permission error when the file at `path` is read-only, before an in-place crop
+/// spends time decoding an image it must not replace. Writing through a temporary file would
+/// otherwise replace it wherever its directory is writable.
+async fn ensure_writable(path: &Path) -> Result<()> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .map_err(|err| LetterboxError::io(path, err))?;
+    check_writable(path, &metadata)
+}
+
+/// Fail as [`ensure_writable`] does when `metadata`, read from `path`, marks it read-only
+fn check_writable(path: &Path, metadata: &std::fs::Metadata) -> Result<()> {
+    if metadata.permissions().readonly() {
+        warn!("Not cropping read-only file in place: {}", path.display());
+        let err = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "file is read-only");
+        return Err(LetterboxError::io(path, err).into());
+    }
+    Ok(())
+}
+
+/// Copy `path` to a sibling file with `suffix` appended to its name.
+/// An existing backup is kept unless `force` is set.
+async fn backup_file(path: &Path, suffix: &str, force: bool) -> Result<()> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(suffix);
+    let backup = PathBuf::from(backup);
+
+    if !force && tokio::fs::try_exists(&backup).await.unwrap_or(false) {
+        warn!("Backup already exists, keeping it: {}", backup.display());
+        return Ok(());
+    }
+
+    tokio::fs::copy(path, &backup).await.with_context(|| {
+        format!(
+            "Failed to back up {} to {}",
+            path.display(),
+            backup.display()
+        )
+    })?;
+    info!("Backed up {} to {}", path.display(), backup.display());
+    Ok(())
+}
+
+/// Settings that decide which crop is applied to an image, taken from a [`Config`]
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+struct DetectionParams {
+    threshold: [u8; 3],
+    side_thresholds: SideThresholds,
+    axis: Axis,
+    sides: Sides,
+    invert: bool,
+    border_color: Option<BorderColor>,
+    autocrop: bool,
+    tolerance: u8,
+    max_crop_percent: u8,
+    min_border: u32,
+    min_confidence: f32,
+    symmetric: bool,
+    pad: u32,
+    round_to: u32,
+    per_frame: bool,
+    frame: usize,
+    auto_threshold: Option<AutoThreshold>,
+    feather: u32,
+    sample_step: u32,
+    blank_policy: BlankPolicy,
+    corner_radius: u32,
+}
+
+impl DetectionParams {
+    fn new(config: &Config) -> Self {
+        let params = Self {
+            threshold: config.threshold,
+            side_thresholds: config.side_thresholds,
+            axis: config.axis,
+            sides: config.sides,
+            invert: config.invert,
+            border_color: config.border_color,
+            autocrop: config.autocrop,
+            tolerance: config.tolerance,
+            max_crop_percent: config.max_crop_percent,
+            min_border: config.min_border,
+            min_confidence: config.min_confidence,
+            symmetric: config.symmetric,
+            pad: config.pad,
+            round_to: config.round_to,
+            per_frame: config.per_frame_crop,
+            frame: config.frame_select,
+            auto_threshold: config.auto_threshold,
+            feather: config.feather,
+            sample_step: config.sample_step,
+            blank_policy: config.blank_policy,
+            corner_radius: if config.rounded_corners {
+                config.corner_radius
+            } else {
+                0
+            },
+        };
+        if config.exact { params.exact() } else { params }
+    }
+
+    /// The same settings, matching only pixels that are exactly the border color and only
+    /// lines made entirely of them
+    fn exact(self) -> Self {
+        Self {
+            threshold: [0; 3],
+            side_thresholds: SideThresholds::default(),
+            auto_threshold: None,
+            tolerance: 0,
+            sample_step: 1,
+            feather: 0,
+            corner_radius: 0,
+            ..self
+        }
+    }
+
+    /// Threshold and color of the border of an image, as set or estimated from its edges.
+    /// Fails when an automatic threshold or border color finds no clear border.
+    fn border<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+        &self,
+        img: &I,
+        path: &Path,
+    ) -> Result<([T; 3], [T; 3]), &'static str> {
+        let scaled = |color: [u8; 3]| color.map(T::from_u8);
+        let threshold = match self.auto_threshold.filter(|_| self.border_color.is_none()) {
+            Some(AutoThreshold::Edges) => {
+                let threshold = estimate_threshold(img, self.axis, self.invert)?;
+                debug!("Estimated threshold {threshold:?} for {}", path.display());
+                threshold
+            }
+            Some(AutoThreshold::Corners) => match corner_threshold(img, self.invert) {
+                Ok(threshold) => {
+                    debug!("Estimated threshold {threshold:?} for {}", path.display());
+                    threshold
+                }
+                Err(reason) => {
+                    warn!(
+                        "No consistent border color in {}, {reason}, using the threshold instead",
+                        path.display()
+                    );
+                    scaled(self.threshold)
+                }
+            },
+            None => scaled(self.threshold),
+        };
+        let color = match self.border_color {
+            None if self.invert => [T::DEFAULT_MAX_VALUE; 3],
+            None => [T::zero(); 3],
+            Some(BorderColor::Rgb(color)) => scaled(color),
+            Some(BorderColor::Auto) => {
+                let color = dominant_border_color(img, self.axis, threshold)?;
+                debug!("Detected border color {color:?} for {}", path.display());
+                color
+            }
+        };
+        Ok((threshold, color))
+    }
+
+    /// Find the content area of an image, before any adjustments. With automatic thresholds
+    /// or border colors, images without a clear border are left alone and the reason is
+    /// logged.
+    fn detect<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+        &self,
+        img: &I,
+        path: &Path,
+    ) -> Option<CropRect> {
+        if self.autocrop {
+            let threshold = self.threshold.map(T::from_u8);
+            return autocrop_bounds(img, threshold).unwrap_or_else(|reason| {
+                info!("Leaving {} unchanged, {reason}", path.display());
+                None
+            });
+        }
+
+        let (threshold, color) = match self.border(img, path) {
+            Ok(border) => border,
+            Err(reason) => {
+                info!("Leaving {} unchanged, {reason}", path.display());
+                return None;
+            }
+        };
+        let sides = &self.side_thresholds;
+        let thresholds = [sides.top, sides.bottom, sides.left, sides.right]
+            .map(|side| side.map_or(threshold, |side| side.map(T::from_u8)));
+        let rect = letterbox_bounds(
+            img,
+            thresholds,
+            self.axis,
+            color,
+            self.tolerance,
+            self.sample_step,
+            self.corner_radius,
+        )?;
+        Some(feathered(img, rect, color, self.feather))
+    }
+
+    /// Whether every pixel of an image is part of the border, leaving no content to crop to.
+    /// Autocrop looks for no particular border color, so it never finds an image blank.
+    fn is_blank<T: Channel, I: GenericImageView<Pixel = Rgba<T>>>(
+        &self,
+        img: &I,
+        path: &Path,
+    ) -> bool {
+        !self.autocrop
+            && self.border(img, path).is_ok_and(|(threshold, color)| {
+                img.pixels()
+                    .all(|(_, _, pixel)| is_border_pixel(pixel, color, threshold))
+            })
+    }
+}
+
+/// Whether a crop removes more than `max_percent` of the image's width or height
+fn exceeds_max_crop((width, height): (u32, u32), rect: CropRect, max_percent: u8) -> bool {
+    let exceeds = |original: u32, remaining: u32| {
+        u64::from(original - remaining) * 100 > u64::from(max_percent) * u64::from(original)
+    };
+    exceeds(width, rect.width) || exceeds(height, rect.height)
+}
+
+/// Whether an image has 16 bits per channel, so that detection runs at full precision rather
+/// than on 8-bit pixels
+fn is_16_bit(img: &DynamicImage) -> bool {
+    matches!(
+        img,
+        DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_)
+    )
+}
+
+/// Detect the letterbox of an image and decide on the crop to apply, if any, along with the
+/// confidence of the detection, see [`CropResult::confidence`]
+fn plan_crop(img: &DynamicImage, params: &DetectionParams, path: &Path) -> (Option<CropRect>, f32) {
+    let Some(rect) = detect_content(img, params, path) else {
+        return (None, 1.0);
+    };
+    let confidence = edge_confidence(img, rect);
+    if confidence < params.min_confidence {
+        warn!(
+            "Refusing to crop {}, since the detection confidence of {confidence:.2} is below {}",
+            path.display(),
+            params.min_confidence
+        );
+        return (None, confidence);
+    }
+
+    let rect = adjust_crop(rect, img.dimensions(), params, path);
+    if rect.is_some() && confidence < LOW_CONFIDENCE {
+        warn!(
+            "Cropping {} with a low detection confidence of {confidence:.2}",
+            path.display()
+        );
+    }
+    (rect, confidence)
+}
+
+/// Average difference in luminance across the edge of a letterbox at which a detection is
+/// fully confident
+const CONFIDENT_EDGE_JUMP: f32 = 64.0;
+
+/// Detection confidence below which applied crops are logged as a warning
+const LOW_CONFIDENCE: f32 = 0.5;
+
+/// How sharply the image changes across the edges of a detected content area, see
+/// [`CropResult::confidence`]. Each cropped side is scored by the average difference in
+/// luminance between its last border line and the first line of content, and the weakest side
+/// decides.
+fn edge_confidence(img: &DynamicImage, rect: CropRect) -> f32 {
+    let (width, height) = img.dimensions();
+    let (right, bottom) = (rect.x + rect.width, rect.y + rect.height);
+    let (columns, rows) = (rect.x..right, rect.y..bottom);
+    let mut scores = Vec::with_capacity(4);
+    if rect.y > 0 {
+        let pairs = columns.clone().map(|x| ((x, rect.y - 1), (x, rect.y)));
+        scores.push(edge_score(img, pairs));
+    }
+    if bottom < height {
+        let pairs = columns.map(|x| ((x, bottom), (x, bottom - 1)));
+        scores.push(edge_score(img, pairs));
+    }
+    if rect.x > 0 {
+        let pairs = rows.clone().map(|y| ((rect.x - 1, y), (rect.x, y)));
+        scores.push(edge_score(img, pairs));
+    }
+    if right < width {
+        let pairs = rows.map(|y| ((right, y), (right - 1, y)));
+        scores.push(edge_score(img, pairs));
+    }
+    scores.into_iter().fold(1.0, f32::min)
+}
+
+/// Score of one edge from pairs of a border pixel and the content pixel next to it
+fn edge_score(img: &DynamicImage, pairs: impl Iterator<Item = ((u32, u32), (u32, u32))>) -> f32 {
+    let (mut total, mut count) = (0u64, 0u64);
+    for ((bx, by), (cx, cy)) in pairs {
+        let border = luminance(&img.get_pixel(bx, by));
+        let content = luminance(&img.get_pixel(cx, cy));
+        total += u64::from(border.abs_diff(content));
+        count += 1;
+    }
+    let mean = u8::try_from(total / count.max(1)).unwrap_or(u8::MAX);
+    (f32::from(mean) / CONFIDENT_EDGE_JUMP).min(1.0)
+}
+
+/// Find the content area of an image, before any adjustments
+fn detect_content(img: &DynamicImage, params: &DetectionParams, path: &Path) -> Option<CropRect> {
+    if is_16_bit(img) {
+        params.detect(&img.to_rgba16(), path)
+    } else {
+        params.detect(img, path)
+    }
+}
+
+/// Whether an image consists entirely of letterbox pixels
+fn is_blank_image(img: &DynamicImage, params: &DetectionParams, path: &Path) -> bool {
+    if is_16_bit(img) {
+        params.is_blank(&img.to_rgba16(), path)
+    } else {
+        params.is_blank(img, path)
+    }
+}
+
+/// Apply the side restriction, minimum border, symmetric, padding, rounding, and maximum crop
+/// settings to a detected content area of an image with the given dimensions
+fn adjust_crop(
+    mut rect: CropRect,
+    (width, height): (u32, u32),
+    params: &DetectionParams,
+    path: &Path,
+) -> Option<CropRect> {
+    rect = rect.restricted(params.sides, (width, height));
+    if params.min_border > 0 {
+        rect = rect.without_thin_borders(params.min_border, (width, height));
+    }
+    if params.symmetric {
+        rect = rect.symmetric((width, height));
+    }
+    rect = rect.padded(params.pad, (width, height));
+
+    // Side restrictions, minimum borders, symmetric crops, and padding can reach every edge
+    // of the image, leaving nothing to crop
+    if rect.width == width && rect.height == height {
+        return None;
+    }
+
+    if params.round_to > 1 {
+        if rect.width < params.round_to || rect.height < params.round_to {
+            warn!(
+                "Not rounding crop of {} to a multiple of {}, since {}x{} is too small",
+                path.display(),
+                params.round_to,
+                rect.width,
+                rect.height
+            );
+        } else {
+            rect = rect.rounded(params.round_to);
+        }
+    }
+
+    if exceeds_max_crop((width, height), rect, params.max_crop_percent) {
+        warn!(
+            "Refusing to crop {} from {}x{} to {}x{}, which removes more than {}% of the image",
+            path.display(),
+            width,
+            height,
+            rect.width,
+            rect.height,
+            params.max_crop_percent
+        );
+        return None;
+    }
+
+    Some(rect)
+}
+
+/// Detect and crop the letterbox of an image file, writing the result to `destination`, which
+/// is usually the same path. Images written to a different path are re-encoded in the format
+/// implied by its extension even when there is nothing to crop. When `backup` holds a suffix,
+/// the file is backed up before it is written. The image is encoded as `settings` says, see
+/// [`save_image`]. Returns the crop along with the time spent in each stage.
+async fn remove_letterbox(
+    path: &Path,
+    destination: &Path,
+    params: &DetectionParams,
+    backup: Option<&str>,
+    force: bool,
+    settings: EncodeSettings,
+) -> Result<(CropResult, StageTimes)> {
+    let mut times = StageTimes::default();
+    let (original, img) = profile::timed_async(&mut times.decode, read_image(path)).await?;
+    let (rect, confidence, blank) = profile::timed(&mut times.detect, || {
+        let (rect, confidence) = plan_crop(&img, params, path);
+        let blank = rect.is_none() && is_blank_image(&img, params, path);
+        (rect, confidence, blank)
+    });
+
+    let mut result = CropResult::new(img.dimensions(), rect);
+    result.blank = blank;
+    result.confidence = confidence;
+    // Blank images are left to the caller unless they are kept
+    if result.blank && params.blank_policy != BlankPolicy::Keep {
+        return Ok((result, times));
+    }
+
+    if rect.is_some() || destination != path {
+        if let Some(suffix) = backup {
+            backup_file(path, suffix, force).await?;
+        }
+        let start = Instant::now();
+        let cropped = match rect {
+            Some(rect) => Cow::Owned(img.crop_imm(rect.x, rect.y, rect.width, rect.height)),
+            None => Cow::Borrowed(&img),
+        };
+        let cropped = settings.resized(cropped);
+        save_image(&cropped, destination, &original, settings).await?;
+        times.encode = start.elapsed();
+    }
+
+    Ok((result, times))
+}
+
+/// Create a processor function that owns the settings of the run and stores the crop result.
+/// Every detection option applies to the PNG converted from a JXL file just as to other images,
+/// and the PNG is replaced when writing to another [`Config::format`].
+fn create_processor<'a>(
+    config: Arc<Config>,
+    result: Arc<Mutex<Option<CropResult>>>,
+) -> impl for<'r> FnOnce(&'r Path) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> + Send + 'a
+{
+    move |path: &Path| {
+        let path = path.to_owned();
+        Box::pin(async move {
+            // The converted PNG must always be written, so blank JXL files are kept
+            let params = DetectionParams {
+                blank_policy: BlankPolicy::Keep,
+                ..DetectionParams::new(&config)
+            };
+            let destination = converted_path(&path, config.format);
+            // The original JXL is backed up by the caller, and the converted PNG carries no
+            // EXIF metadata of its own, only the color profile of the JXL
+            let settings = EncodeSettings {
+                keep: KeepMetadata {
+                    exif: false,
+                    ..KeepMetadata::new(&config)
+                },
+                ..EncodeSettings::new(&config)
+            };
+            let (crop, times) =
+                remove_letterbox(&path, &destination, &params, None, false, settings).await?;
+            if let Some(profile) = &config.profile {
+                profile.record(&path, times);
+            }
+            if destination != path {
+                remove_converted(&path).await?;
+            }
+            *result.lock().await = Some(crop);
+            Ok(())
+        })
+    }
+}
+
+/// Path that a cropped image read from `path` is written to when converting to `format`.
+/// JXL, HEIC, and PSD files are written as PNG, and animated GIFs and TIFFs, which may have
+/// several pages, are always kept in their format.
+fn converted_path(path: &Path, format: Option<OutputFormat>) -> PathBuf {
+    let path = if imx::is_jxl_file(path) || is_heic_file(path) || is_psd_file(path) {
+        path.with_extension("png")
+    } else {
+        path.to_owned()
+    };
+    match format {
+        Some(format)
+            if !gif::is_gif_file(&path)
+                && !multipage::is_tiff_file(&path)
+                && ImageFormat::from_path(&path).ok() != Some(format.image_format()) =>
+        {
+            path.with_extension(format.extension())
+        }
+        _ => path,
+    }
+}
+
+/// Remove a file that was replaced by a copy in another format
+async fn remove_converted(path: &Path) -> Result<()> {
+    tokio::fs::remove_file(path)
+        .await
+        .with_context(|| format!("Failed to remove converted file: {}", path.display()))
+}
+
+/// Remove the letterbox from a single image file and report what was cropped.
+///
+/// This behaves like [`process_file`] but returns the [`CropResult`] for the image instead of
+/// logging it. In a dry run, the predicted crop is returned and no files are modified.
+///
+/// # Errors
+///
+/// Returns an error if the path is not a supported image, if the image cannot be read,
+/// decoded, or written, or if its cropped copy already exists in [`Config::output`] and
+/// [`Config::overwrite`] is [`OverwritePolicy::Skip`].
+pub async fn crop_file(path: &Path, config: &Config) -> Result<CropResult, LetterboxError> {
+    let target = match &config.output {
+        Some(output) => Some(
+            output_target(path, output, config)?
+                .with_context(|| format!("Output file already exists for: {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let preview_dir = config.preview_dir.as_deref();
+    Ok(crop_file_to(path, config, target.as_deref(), preview_dir).await?)
+}
+
+/// Remove the letterbox from an encoded image held in memory, such as one read from stdin.
+///
+/// Returns the image encoded in [`Config::format`], or in the format it was read in when that
+/// is `None`, along with the crop. The image is re-encoded even when there is nothing to crop.
+/// EXIF metadata and the ICC profile are carried over unless [`Config::strip_metadata`] or
+/// [`Config::strip_icc`] is set. `name` is only used in messages.
+///
+/// # Errors
+///
+/// Returns an error if the image cannot be decoded or encoded, if it is a GIF or an animated
+/// WebP, since animations can only be cropped from files, or if it is larger than
+/// [`Config::max_pixels`].
+pub fn crop_image_bytes(
+    bytes: &[u8],
+    name: &Path,
+    config: &Config,
+) -> Result<(Vec<u8>, CropResult), LetterboxError> {
+    let input_format = image::guess_format(bytes).map_err(|_| LetterboxError::unsupported(name))?;
+    if input_format == ImageFormat::Gif {
+        return Err(anyhow::anyhow!(
+            "GIF images can only be cropped from files: {}",
+            name.display()
+        )
+        .into());
+    }
+    if input_format == ImageFormat::WebP && webp::has_animation(bytes) {
+        return Err(anyhow::anyhow!(
+            "Animated WebP images can only be cropped from files: {}",
+            name.display()
+        )
+        .into());
+    }
+    if config.frame_select > 0 {
+        return Err(anyhow::anyhow!(
+            "Cannot select frame {}, the image is not animated: {}",
+            config.frame_select,
+            name.display()
+        )
+        .into());
+    }
+    if config.max_pixels.is_some() {
+        let (width, height) = image::ImageReader::with_format(Cursor::new(bytes), input_format)
+            .into_dimensions()
+            .map_err(|err| LetterboxError::decode(name, err))?;
+        if let Some(max) = exceeds_max_pixels(width, height, config) {
+            return Err(anyhow::anyhow!(
+                "Image is larger than {max} pixels: {} ({width}x{height})",
+                name.display()
+            )
+            .into());
+        }
+    }
+    let mut times = StageTimes::default();
+    let img = profile::timed(&mut times.decode, || match input_format {
+        ImageFormat::Ico => ico::decode_ico(bytes, name),
+        _ => decode_upright(bytes, Some(input_format))
+            .map_err(|err| LetterboxError::decode(name, err).into()),
+    })?;
+
+    let (rect, confidence) = profile::timed(&mut times.detect, || {
+        plan_crop(&img, &DetectionParams::new(config), name)
+    });
+    let mut result = CropResult::new(img.dimensions(), rect);
+    result.confidence = confidence;
+    info!("{}", describe_crop(name, &result));
+
+    let cropped = match rect {
+        Some(rect) => Cow::Owned(img.crop_imm(rect.x, rect.y, rect.width, rect.height)),
+        None => Cow::Borrowed(&img),
+    };
+    let settings = EncodeSettings::new(config);
+    let cropped = settings.resized(cropped);
+    let format = config
+        .format
+        .map_or(input_format, OutputFormat::image_format);
+    let encoded = profile::timed(&mut times.encode, || {
+        encode_image(&cropped, format, bytes, settings, name)
+    })?;
+    if let Some(profile) = &config.profile {
+        profile.record(name, times);
+    }
+    Ok((encoded, result))
+}
+
+/// Predict the crop of an image file at each of several thresholds, without modifying it.
+/// Detection runs as with [`detect_letterbox`], and animated GIFs are judged by their first
+/// frame.
+///
+/// # Errors
+///
+/// Returns an error if the path is not a supported image or cannot be read or decoded.
+pub async fn sweep_thresholds(
+    path: &Path,
+    thresholds: &[u8],
+) -> Result<Vec<(u8, CropResult)>, LetterboxError> {
+    if !is_image_file(path) {
+        return Err(LetterboxError::unsupported(path));
+    }
+    let img = decode_file(path).await?;
+    let full = CropRect::full(img.dimensions());
+    Ok(thresholds
+        .iter()
+        .map(|&threshold| {
+            let rect = detect_letterbox(&img, threshold);
+            (
+                threshold,
+                CropResult::new(img.dimensions(), (rect != full).then_some(rect)),
+            )
+        })
+        .collect())
+}
+
+/// Longest side of the thumbnails that [`find_candidates`] looks for a letterbox in
+const CANDIDATE_SIZE: u32 = 256;
+
+/// Whether a thumbnail of the image file shows a letterbox under the detection settings
+async fn is_candidate(path: &Path, params: &DetectionParams) -> Result<bool> {
+    let img = decode_file(path).await?;
+    let thumbnail = img.thumbnail(CANDIDATE_SIZE, CANDIDATE_SIZE);
+    Ok(detect_content(&thumbnail, params, path).is_some())
+}
+
+/// List the images under a file or directory that likely have a letterbox, without modifying
+/// them. Each image is still decoded in full, but detection runs on a thumbnail of it, which
+/// is much cheaper than detection on the full image. Bars thinner than a pixel of the
+/// thumbnail can be missed, and the crop adjustments of [`Config`], such as the maximum crop,
+/// are not applied.
+///
+/// Directories are walked as with [`process_directory`], and up to [`Config::jobs`] images
+/// are checked concurrently. Images that fail to decode are logged and left out. The
+/// candidates are returned sorted by path.
+///
+/// # Errors
+///
+/// Returns an error if a directory cannot be read.
+pub async fn find_candidates(
+    input: &Path,
+    config: &Config,
+) -> Result<Vec<PathBuf>, LetterboxError> {
+    let mut files = Vec::new();
+    if input.is_dir() {
+        let interrupted = AtomicBool::new(false);
+        walk_tree(
+            input,
+            config,
+            &interrupted,
+            &mut FileSink::Collect(&mut files),
+        )
+        .await?;
+    } else {
+        files.push((input.to_owned(), Destinations::default()));
+    }
+
+    let params = Arc::new(DetectionParams::new(config));
+    let mut tasks = JoinSet::new();
+    let mut candidates = Vec::new();
+    let mut record = |joined: Result<(PathBuf, Result<bool>), JoinError>| -> Result<()> {
+        match joined.context("Candidate check panicked")? {
+            (path, Ok(true)) => candidates.push(path),
+            (_, Ok(false)) => {}
+            (path, Err(err)) => warn!("Failed to check {}: {err:#}", path.display()),
+        }
+        Ok(())
+    };
+    for (path, _) in files {
+        if !is_image_file(&path) {
+            continue;
+        }
+        // Wait for a free slot so that at most `jobs` images are decoded at once
+        while tasks.len() >= config.jobs.max(1) {
+            let Some(joined) = tasks.join_next().await else {
+                break;
+            };
+            record(joined)?;
+        }
+        let params = Arc::clone(&params);
+        tasks.spawn(async move {
+            let likely = is_candidate(&path, &params).await;
+            (path, likely)
+        });
+    }
+    while let Some(joined) = tasks.join_next().await {
+        record(joined)?;
+    }
+
+    candidates.sort();
+    Ok(candidates)
+}
+
+/// Whether the path has a HEIC, HEIF, or AVIF extension
+fn is_heic_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        ["heic", "heif", "avif"]
+            .iter()
+            .any(|heic| ext.eq_ignore_ascii_case(heic))
+    })
+}
+
+/// Whether the path has a PSD extension
+fn is_psd_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("psd"))
+}
+
+/// Whether the path has a BMP extension
+fn is_bmp_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("bmp"))
+}
+
+/// Whether the file is an image format that can be cropped. HEIC, HEIF, and AVIF files are
+/// only supported with the `heic` feature, and PSD files with the `psd` feature.
+fn is_image_file(path: &Path) -> bool {
+    imx::is_image_file(path)
+        || is_bmp_file(path)
+        || ico::is_ico_file(path)
+        || gif::is_gif_file(path)
+        || multipage::is_tiff_file(path)
+        || (cfg!(feature = "heic") && is_heic_file(path))
+        || (cfg!(feature = "psd") && is_psd_file(path))
+}
+
+/// Crop a single image file, writing the cropped copy to `target` if given. Dry runs write
+/// their preview to `preview_dir`, see [`Config::preview_dir`].
+async fn crop_file_to(
+    path: &Path,
+    config: &Config,
+    target: Option<&Path>,
+    preview_dir: Option<&Path>,
+) -> Result<CropResult> {
+    if !is_image_file(path) {
+        anyhow::bail!(LetterboxError::unsupported(path));
+    }
+    if config.frame_select > 0
+        && !gif::is_gif_file(path)
+        && !(webp::is_webp_file(path) && webp::is_animated_webp(path).await?)
+    {
+        anyhow::bail!(
+            "Cannot select frame {}, the image is not animated: {}",
+            config.frame_select,
+            path.display()
+        );
+    }
+    let resize = match config.resize {
+        Some(_) if is_multi_frame(path).await? => {
+            warn!(
+                "Not resizing {}, animated and multi-page images are only cropped",
+                path.display()
+            );
+            None
+        }
+        resize => resize,
+    };
+
+    if config.dry_run {
+        let (img, result) = if gif::is_gif_file(path) {
+            let (frame, result) = gif::plan_gif(path, &DetectionParams::new(config)).await?;
+            (Some(frame), result)
+        } else if webp::is_webp_file(path) && webp::is_animated_webp(path).await? {
+            let (frame, result) = webp::plan_webp(path, &DetectionParams::new(config)).await?;
+            (Some(frame), result)
+        } else if multipage::is_tiff_file(path) {
+            let params = DetectionParams::new(config);
+            let result = multipage::plan_tiff(path, &params, config.shared_page_crop).await?;
+            (None, result)
+        } else {
+            let mut times = StageTimes::default();
+            let img = profile::timed_async(&mut times.decode, decode_file(path)).await?;
+            let params = DetectionParams::new(config);
+            let (rect, confidence, blank) = profile::timed(&mut times.detect, || {
+                let (rect, confidence) = plan_crop(&img, &params, path);
+                let blank = rect.is_none() && is_blank_image(&img, &params, path);
+                (rect, confidence, blank)
+            });
+            if let Some(profile) = &config.profile {
+                profile.record(path, times);
+            }
+            let mut result = CropResult::new(img.dimensions(), rect);
+            result.blank = blank;
+            result.confidence = confidence;
+            (Some(img), result)
+        };
+        if let Some(dir) = preview_dir {
+            // Multi-page TIFFs are previewed by their first page
+            let img = match img {
+                Some(img) => img,
+                None => decode_file(path).await?,
+            };
+            preview::write_preview(&img, &result, path, dir).await?;
+        }
+        if target.is_none() && !is_set_aside(&result, config) {
+            triage::sort_image(&converted_path(path, config.format), &result, config).await?;
+        }
+        return Ok(result);
+    }
+
+    if target.is_none() {
+        ensure_writable(path).await?;
+    }
+
+    // Remember the modification time before the file is rewritten
+    let mtime = if config.preserve_mtime {
+        let metadata = tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+        Some(FileTime::from_last_modification_time(&metadata))
+    } else {
+        None
+    };
+
+    let (result, written) = write_cropped(path, config, target).await?;
+    if is_set_aside(&result, config) {
+        return Ok(result);
+    }
+
+    if config.verify
+        && (result.modified || written != path)
+        && let Err(err) = verify_output(
+            &written,
+            resize.map_or(result.cropped, |resize| resize.dimensions(result.cropped)),
+        )
+        .await
+    {
+        if !config.continue_on_error {
+            return Err(err);
+        }
+        warn!("{err:#}");
+    }
+
+    if let Some(log) = &config.crop_log
+        && result.modified
+    {
+        log.append(path, &result, config).await?;
+    }
+
+    // In-place crops that were backed up can be undone with the help of a sidecar
+    if let (Some(suffix), None) = (&config.backup, target)
+        && (result.modified || written != path)
+    {
+        sidecar::write_sidecar(path, &written, suffix, &result).await?;
+    }
+
+    if config.mark && (result.modified || written != path) {
+        marker::mark_file(&written);
+    }
+
+    if let Some(mtime) = mtime {
+        filetime::set_file_mtime(&written, mtime).with_context(|| {
+            format!("Failed to restore modification time: {}", written.display())
+        })?;
+    }
+
+    if let (Some(pattern), Some(_)) = (&config.output_pattern, target) {
+        pattern::apply_pattern(pattern, path, &written, &result, config.overwrite).await?;
+    }
+
+    if target.is_none() {
+        triage::sort_image(&written, &result, config).await?;
+    }
+
+    Ok(result)
+}
+
+/// Decode a written image and check that it has the `expected` dimensions of the crop, after
+/// any resize. Animated and multi-page images are checked by their first frame.
+async fn verify_output(written: &Path, expected: (u32, u32)) -> Result<()> {
+    let img = decode_file(written).await.with_context(|| {
+        format!(
+            "Verification failed, the written image cannot be decoded: {}",
+            written.display()
+        )
+    })?;
+    let (width, height) = img.dimensions();
+    if (width, height) != expected {
+        anyhow::bail!(
+            "Verification failed, the written image is {width}x{height} instead of {}x{}: {}",
+            expected.0,
+            expected.1,
+            written.display()
+        );
+    }
+    Ok(())
+}
+
+/// Whether an image file may hold several frames or pages, which are cropped but not resized
+async fn is_multi_frame(path: &Path) -> Result<bool> {
+    Ok(gif::is_gif_file(path)
+        || multipage::is_tiff_file(path)
+        || (webp::is_webp_file(path) && webp::is_animated_webp(path).await?))
+}
+
+/// Crop an image file and write the result, returning the crop along with the written path
+async fn write_cropped(
+    path: &Path,
+    config: &Config,
+    target: Option<&Path>,
+) -> Result<(CropResult, PathBuf)> {
+    // Handle JXL files
+    if imx::is_jxl_file(path) {
+        info!("Processing JXL file: {}", path.display());
+        // The original JXL is always replaced by a PNG, so back it up whether or not it's cropped
+        if let (Some(suffix), None) = (&config.backup, target) {
+            backup_file(path, suffix, config.force).await?;
+        }
+        let target = prepare_target(path, target).await?;
+        let result = Arc::new(Mutex::new(None));
+        imx::process_jxl_file(
+            &target,
+            Some(create_processor(
+                Arc::new(config.clone()),
+                Arc::clone(&result),
+            )),
+        )
+        .await?;
+        let result = result
+            .lock()
+            .await
+            .take()
+            .with_context(|| format!("JXL file was not processed: {}", path.display()))?;
+        return Ok((result, converted_path(&target, config.format)));
+    }
+
+    // Originals are only overwritten, and so only need a backup, when processing in place
+    let backup = config.backup.as_deref().filter(|_| target.is_none());
+
+    // Animated GIFs are cropped frame by frame
+    if gif::is_gif_file(path) {
+        info!("Processing GIF file: {}", path.display());
+        let target = prepare_target(path, target).await?;
+        let result =
+            gif::remove_gif_letterbox(&target, &DetectionParams::new(config), backup, config.force)
+                .await
+                .with_context(|| format!("Failed to process image file: {}", path.display()))?;
+        return Ok((result, target));
+    }
+
+    // Animated WebPs are cropped frame by frame too, and stay WebP
+    if webp::is_webp_file(path) && webp::is_animated_webp(path).await? {
+        info!("Processing animated WebP file: {}", path.display());
+        let target = prepare_target(path, target).await?;
+        let result = webp::remove_webp_letterbox(
+            &target,
+            &DetectionParams::new(config),
+            backup,
+            config.force,
+        )
+        .await
+        .with_context(|| format!("Failed to process image file: {}", path.display()))?;
+        return Ok((result, target));
+    }
+
+    // TIFFs are cropped page by page
+    if multipage::is_tiff_file(path) {
+        info!("Processing TIFF file: {}", path.display());
+        let target = prepare_target(path, target).await?;
+        let result = multipage::remove_tiff_letterbox(
+            &target,
+            &DetectionParams::new(config),
+            config.shared_page_crop,
+            backup,
+            config.force,
+        )
+        .await
+        .with_context(|| format!("Failed to process image file: {}", path.display()))?;
+        return Ok((result, target));
+    }
+
+    info!("Processing image file: {}", path.display());
+    let destination = converted_path(target.unwrap_or(path), config.format);
+    // Converted images are written straight to their destination instead of to a copy
+    let converting = destination != target.unwrap_or(path);
+    let source = if converting {
+        path.to_owned()
+    } else {
+        prepare_target(path, target).await?
+    };
+    let (result, times) = remove_letterbox(
+        &source,
+        &destination,
+        &DetectionParams::new(config),
+        backup,
+        config.force,
+        EncodeSettings::new(config),
+    )
+    .await
+    .with_context(|| format!("Failed to process image file: {}", path.display()))?;
+    if let Some(profile) = &config.profile {
+        profile.record(path, times);
+    }
+
+    if is_set_aside(&result, config) {
+        // Nothing was written, so drop the copy made for the output directory
+        if source != path {
+            tokio::fs::remove_file(&source)
+                .await
+                .with_context(|| format!("Failed to remove {}", source.display()))?;
+        }
+        return Ok((result, path.to_owned()));
+    }
+    if converting && target.is_none() && !config.keep_original && !is_psd_file(path) {
+        remove_converted(path).await?;
+    }
+    Ok((result, destination))
+}
+
+/// Process a single image file to remove letterboxing.
+///
+/// When [`Config::output`] is set, the cropped result is written there and the original is
+/// left untouched. When [`Config::dry_run`] is set, the predicted crop is only logged.
+///
+/// Returns `None` for files that are skipped: non-image files, images smaller than
+/// [`Config::min_width`] or [`Config::min_height`] or larger than [`Config::max_pixels`], and
+/// images whose cropped copy already exists when [`Config::overwrite`] is
+/// [`OverwritePolicy::Skip`].
+///
+/// # Errors
+///
+/// Returns an error if the image cannot be read, decoded, or written, or if the file is not a
+/// supported image and [`Config::strict_input`] is set.
+pub async fn process_file(
+    path: &Path,
+    config: &Config,
+) -> Result<Option<CropResult>, LetterboxError> {
+    if config.strict_input && !is_image_file(path) {
+        return Err(LetterboxError::unsupported(path));
+    }
+    Ok(process_file_to(path, config, &Destinations::new(config)).await?)
+}
+
+/// Whether the file's extension is allowed by [`Config::extensions`]
+fn has_selected_extension(path: &Path, config: &Config) -> bool {
+    let Some(extensions) = &config.extensions else {
+        return true;
+    };
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            extensions
+                .iter()
+                .any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(ext))
+        })
+}
+
+/// Whether the file matches [`Config::exclude`], by its name or its path relative to `root`.
+/// Paths outside `root` are matched as they are.
+fn is_excluded(path: &Path, root: &Path, config: &Config) -> bool {
+    path.file_name()
+        .is_some_and(|name| config.exclude.is_match(name))
+        || config
+            .exclude
+            .is_match(path.strip_prefix(root).unwrap_or(path))
+}
+
+/// Whether the directory's name matches [`Config::exclude_dir`]
+fn is_excluded_dir(path: &Path, config: &Config) -> bool {
+    path.file_name()
+        .is_some_and(|name| config.exclude_dir.is_match(name))
+}
+
+/// Whether the file was modified at or after [`Config::since`]. Files whose modification time
+/// cannot be read count as recent, so that processing them reports the problem.
+async fn modified_since(path: &Path, config: &Config) -> bool {
+    let Some(since) = config.since else {
+        return true;
+    };
+    match tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+    {
+        Ok(modified) => modified >= since,
+        Err(_) => true,
+    }
+}
+
+/// Process a single image file, writing cropped copies and previews into the directories of
+/// `destinations`, and count the outcome in [`Config::stats`]
+async fn process_file_to(
+    path: &Path,
+    config: &Config,
+    destinations: &Destinations,
+) -> Result<Option<CropResult>> {
+    let events = config
+        .progress_events
+        .as_deref()
+        .filter(|_| is_image_file(path));
+    if let Some(events) = events {
+        events.start(path);
+    }
+    let outcome = match config.timeout {
+        Some(limit) => process_with_timeout(path, config, destinations, limit).await,
+        None => {
+            retry::with_policy(config, process_file_uncounted(path, config, destinations)).await
+        }
+    };
+    if let Some(stats) = &config.stats {
+        stats.record(&outcome);
+    }
+    if let Some(events) = events {
+        events.finish(path, &outcome);
+    }
+    outcome
+}
+
+/// Process a single image file with retries on a task of its own, abandoning it once it runs
+/// longer than `limit`, see [`Config::timeout`]
+async fn process_with_timeout(
+    path: &Path,
+    config: &Config,
+    destinations: &Destinations,
+    limit: Duration,
+) -> Result<Option<CropResult>> {
+    let task = {
+        let path = path.to_owned();
+        let config = config.clone();
+        let destinations = destinations.clone();
+        tokio::spawn(async move {
+            let processing = process_file_uncounted(&path, &config, &destinations);
+            retry::with_policy(&config, processing).await
+        })
+    };
+    let abort = task.abort_handle();
+    if let Ok(joined) = tokio::time::timeout(limit, task).await {
+        joined.with_context(|| format!("Processing panicked: {}", path.display()))?
+    } else {
+        abort.abort();
+        anyhow::bail!(
+            "Timed out after {} processing image file: {}",
+            humantime::format_duration(limit),
+            path.display()
+        )
+    }
+}
+
+/// Process a single image file as [`process_file_to`] does, without counting the outcome
+async fn process_file_uncounted(
+    path: &Path,
+    config: &Config,
+    destinations: &Destinations,
+) -> Result<Option<CropResult>> {
+    let output = destinations.output.as_deref();
+    if !has_selected_extension(path, config) {
+        info!(
+            target: SKIPPED_LOG_TARGET,
+            "Skipping file with unselected extension: {}",
+            path.display()
+        );
+        return Ok(None);
+    }
+    if is_excluded(path, Path::new(""), config) {
+        info!(target: SKIPPED_LOG_TARGET, "Skipping excluded file: {}", path.display());
+        return Ok(None);
+    }
+
+    if !is_image_file(path) {
+        if config.dry_run {
+            info!(target: SKIPPED_LOG_TARGET, "Would skip non-image file: {}", path.display());
+        } else {
+            warn!(target: SKIPPED_LOG_TARGET, "Skipping non-image file: {}", path.display());
+        }
+        return Ok(None);
+    }
+
+    if !config.force && marker::is_marked(path) {
+        info!(
+            target: SKIPPED_LOG_TARGET,
+            "Skipping image that was already processed: {}",
+            path.display()
+        );
+        return Ok(None);
+    }
+
+    // Leave small images such as icons alone, they are easily mangled by detection, and skip
+    // huge ones before they are decoded
+    if config.min_width > 0 || config.min_height > 0 || config.max_pixels.is_some() {
+        let (width, height) = read_dimensions(path).await?;
+        if width < config.min_width || height < config.min_height {
+            info!(
+                target: SKIPPED_LOG_TARGET,
+                "Skipping image smaller than {}x{}: {} ({width}x{height})",
+                config.min_width,
+                config.min_height,
+                path.display()
+            );
+            return Ok(None);
+        }
+        if let Some(max) = exceeds_max_pixels(width, height, config) {
+            warn!(
+                target: SKIPPED_LOG_TARGET,
+                "Skipping image larger than {max} pixels: {} ({width}x{height})",
+                path.display()
+            );
+            return Ok(None);
+        }
+    }
+
+    // Copies in an output directory are always written, so only in-place runs use the cache
+    let cache = config.cache.as_deref().filter(|_| output.is_none());
+    if let Some(cache) = cache
+        && let Some(result) = cache.lookup(path).await
+    {
+        debug!(
+            target: SKIPPED_LOG_TARGET,
+            "Skipping image unchanged since last run: {}",
+            path.display()
+        );
+        return Ok(Some(result));
+    }
+
+    let target = match output {
+        Some(output) => {
+            let Some(target) = output_target(path, output, config)? else {
+                info!(
+                    target: SKIPPED_LOG_TARGET,
+                    "Skipping image with existing output: {}",
+                    path.display()
+                );
+                return Ok(None);
+            };
+            Some(target)
+        }
+        None => None,
+    };
+
+    let preview_dir = destinations.preview.as_deref();
+    let result = crop_file_to(path, config, target.as_deref(), preview_dir).await?;
+    if is_set_aside(&result, config) {
+        apply_blank_policy(path, config, output).await?;
+        return Ok(None);
+    }
+    if let Some(cache) = cache {
+        cache.update(path, &result, config.dry_run).await;
+    }
+    let summary = describe_crop(path, &result);
+    if config.dry_run || !result.modified {
+        info!("{summary}");
+    } else {
+        info!(target: CROPPED_LOG_TARGET, "Cropped {summary}");
+    }
+
+    Ok(Some(result))
+}
+
+/// Whether an image was left alone because it is blank and [`Config::blank_policy`] does not
+/// keep blank images
+fn is_set_aside(result: &CropResult, config: &Config) -> bool {
+    result.blank && config.blank_policy != BlankPolicy::Keep
+}
+
+/// Skip or delete a blank image according to [`Config::blank_policy`]. Images are only
+/// deleted when processing in place, never when writing copies to an output directory.
+async fn apply_blank_policy(path: &Path, config: &Config, output: Option<&Path>) -> Result<()> {
+    match config.blank_policy {
+        BlankPolicy::Delete if output.is_none() && config.dry_run => {
+            info!("Would delete blank image: {}", path.display());
+        }
+        BlankPolicy::Delete if output.is_none() => {
+            tokio::fs::remove_file(path)
+                .await
+                .with_context(|| format!("Failed to delete blank image: {}", path.display()))?;
+            warn!("Deleted blank image: {}", path.display());
+        }
+        _ => info!(target: SKIPPED_LOG_TARGET, "Skipping blank image: {}", path.display()),
+    }
+    Ok(())
+}
+
+/// Record `dir` as visited, returning `false` if it was seen before. Directories are compared
+/// by canonical path, so symlinks leading back into the tree are only walked once.
+async fn first_visit(visited: &mut HashSet<PathBuf>, dir: &Path) -> Result<bool> {
+    let canonical = tokio::fs::canonicalize(dir)
+        .await
+        .with_context(|| format!("Failed to resolve directory: {}", dir.display()))?;
+    if visited.insert(canonical) {
+        Ok(true)
+    } else {
+        debug!(target: SKIPPED_LOG_TARGET, "Skipping already visited directory: {}", dir.display());
+        Ok(false)
+    }
+}
+
+/// Kind of a directory entry, with symlinks resolved according to [`Config::follow_symlinks`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    File,
+    Directory,
+    Other,
+}
+
+/// Classify a directory entry without following symlinked directories unless requested
+async fn entry_kind(entry: &tokio::fs::DirEntry, config: &Config) -> Result<EntryKind> {
+    let path = entry.path();
+    let file_type = entry
+        .file_type()
+        .await
+        .with_context(|| format!("Failed to read file type: {}", path.display()))?;
+
+    if !file_type.is_symlink() {
+        return Ok(if file_type.is_dir() {
+            EntryKind::Directory
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else {
+            EntryKind::Other
+        });
+    }
+
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+        debug!(target: SKIPPED_LOG_TARGET, "Skipping dangling symlink: {}", path.display());
+        return Ok(EntryKind::Other);
+    };
+    if metadata.is_file() {
+        Ok(EntryKind::File)
+    } else if metadata.is_dir() && config.follow_symlinks {
+        Ok(EntryKind::Directory)
+    } else {
+        debug!(target: SKIPPED_LOG_TARGET, "Skipping symlink: {}", path.display());
+        Ok(EntryKind::Other)
+    }
+}
+
+/// Whether the subdirectories of a directory `depth` levels below the root are processed
+fn descends(config: &Config, depth: usize) -> bool {
+    config.recursive && config.max_depth.is_none_or(|max| depth < max)
+}
+
+/// Count the image files in a directory that will be processed with `config`
+async fn count_images(root: &Path, config: &Config) -> Result<u64> {
+    let mut count = 0;
+    let mut pending = VecDeque::from([(root.to_owned(), 0, IgnoreRules::default())]);
+    let mut visited = HashSet::new();
+
+    while let Some((dir, depth, rules)) = pending.pop_front() {
+        if !first_visit(&mut visited, &dir).await? {
+            continue;
+        }
+        let rules = rules.enter(&dir).await?;
+
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("Failed to read directory entry in: {}", dir.display()))?
+        {
+            let path = entry.path();
+            match entry_kind(&entry, config).await? {
+                kind if rules.is_ignored(&path, kind == EntryKind::Directory) => {}
+                EntryKind::File
+                    if is_image_file(&path)
+                        && has_selected_extension(&path, config)
+                        && !is_excluded(&path, root, config) =>
+                {
+                    if modified_since(&path, config).await {
+                        count += 1;
+                    }
+                }
+                EntryKind::Directory
+                    if triage::is_sort_dir(&path, config) || is_excluded_dir(&path, config) => {}
+                EntryKind::Directory if descends(config, depth) => {
+                    pending.push_back((path, depth + 1, rules.clone()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(config.max_files.map_or(count, |max| count.min(max as u64)))
+}
+
+/// Create a progress bar for `total` files, drawn on stdout
+fn create_progress_bar(total: u64) -> ProgressBar {
+    let bar = ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::stdout());
+    if let Ok(style) = ProgressStyle::with_template(
+        "{bar:40} {pos}/{len} files, {msg} ({elapsed} elapsed, ETA {eta})",
+    ) {
+        bar.set_style(style);
+    }
+    bar
+}
+
+/// Count a finished image on the progress bar, showing the outcomes counted so far in
+/// [`Config::stats`]
+fn advance_progress(progress: &ProgressBar, config: &Config) {
+    if let Some(stats) = &config.stats {
+        let summary = stats.summary();
+        progress.set_message(format!(
+            "{} cropped, {} unchanged, {} errors",
+            summary.cropped, summary.unchanged, summary.errors
+        ));
+    }
+    progress.inc(1);
+}
+
+/// Files that failed to process, paired with the error for each
+pub type Failures = Vec<(PathBuf, anyhow::Error)>;
+
+/// Outcome of processing a directory
+#[derive(Debug, Default)]
+pub struct RunReport {
+    /// One entry per file that finished processing, in completion order
+    pub entries: Vec<ReportEntry>,
+    /// Files that failed when [`Config::continue_on_error`] is set
+    pub failures: Failures,
+    /// Whether the run was stopped early by Ctrl-C, see [`Config::interruptible`]
+    pub interrupted: bool,
+    /// Identical images removed after the run, see [`Config::dedupe`]
+    pub duplicates: usize,
+    /// Counts of file outcomes, updated as files finish
+    pub stats: Arc<Stats>,
+}
+
+impl RunReport {
+    /// Counts of the outcomes of the run, including the duplicates removed
+    #[must_use]
+    pub fn summary(&self) -> Summary {
+        Summary {
+            duplicates: self.duplicates,
+            ..self.stats.summary()
+        }
+    }
+
+    /// Add the outcomes of another run, such as that of a further input directory
+    pub fn merge(&mut self, other: Self) {
+        self.entries.extend(other.entries);
+        self.failures.extend(other.failures);
+        self.interrupted |= other.interrupted;
+        self.duplicates += other.duplicates;
+        self.stats.add(&other.stats);
+    }
+}
+
+/// Outcome of a file processing task
+type FileOutcome = (PathBuf, Result<Option<CropResult>>);
+
+/// State shared across a directory walk
+struct DirectoryWalk {
+    config: Arc<Config>,
+    semaphore: Arc<Semaphore>,
+    tasks: JoinSet<FileOutcome>,
+    /// Files set aside for the thread pool when [`Config::parallel`] is set, each with the
+    /// directory its cropped copy is written to
+    pooled: Vec<(PathBuf, Destinations)>,
+    progress: Option<ProgressBar>,
+    report: RunReport,
+    /// Set once Ctrl-C is pressed, after which no new files are started
+    interrupted: Arc<AtomicBool>,
+    /// Task waiting for Ctrl-C, when [`Config::interruptible`] is set
+    interrupt_listener: Option<JoinHandle<()>>,
+}
+
+impl DirectoryWalk {
+    fn new(config: &Config, progress: Option<ProgressBar>) -> Self {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let interrupt_listener = config.interruptible.then(|| {
+            let interrupted = Arc::clone(&interrupted);
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    warn!("Interrupted, finishing files in progress");
+                    interrupted.store(true, Ordering::Relaxed);
+                }
+            })
+        });
+
+        // Every task counts its file in the counters of the run as it finishes
+        let stats = Arc::new(Stats::new());
+        let config = Config {
+            stats: Some(Arc::clone(&stats)),
+            flat_targets: Some(config.flat_targets.clone().unwrap_or_default()),
+            ..config.clone()
+        };
+        Self {
+            semaphore: Arc::new(Semaphore::new(config.jobs.max(1))),
+            config: Arc::new(config),
+            tasks: JoinSet::new(),
+            pooled: Vec::new(),
+            progress,
+            report: RunReport {
+                stats,
+                ..RunReport::default()
+            },
+            interrupted,
+            interrupt_listener,
+        }
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Walk the tree under `root`, queueing files for processing as they are found. With
+    /// [`Config::parallel_io`], the walk runs on its own task and reads ahead of processing.
+    async fn walk(&mut self, root: &Path) -> Result<()> {
+        if self.config.parallel_io {
+            return self.walk_ahead(root).await;
+        }
+        let config = Arc::clone(&self.config);
+        let interrupted = Arc::clone(&self.interrupted);
+        walk_tree(root, &config, &interrupted, &mut FileSink::Queue(self)).await
+    }
+
+    /// Walk the tree under `root` on a separate task that sends the files it finds through a
+    /// channel of [`Config::read_ahead`] entries, so that reading directories overlaps with
+    /// processing the files found so far
+    async fn walk_ahead(&mut self, root: &Path) -> Result<()> {
+        let (sender, mut receiver) = mpsc::channel(self.config.read_ahead.max(1));
+        let root = root.to_owned();
+        let config = Arc::clone(&self.config);
+        let interrupted = Arc::clone(&self.interrupted);
+        let walker = tokio::spawn(async move {
+            walk_tree(&root, &config, &interrupted, &mut FileSink::Channel(sender)).await
+        });
+
+        let mut result = Ok(());
+        while let Some((path, destinations)) = receiver.recv().await {
+            result = self.queue_file(path, destinations).await;
+            if result.is_err() || self.is_interrupted() {
+                break;
+            }
+        }
+        // Closing the channel stops the walk if processing stopped early
+        drop(receiver);
+        let walked = walker.await.context("Directory walk panicked")?;
+        result.and(walked)
+    }
+
+    /// Queue a file for processing, setting it aside for the thread pool when
+    /// [`Config::parallel`] is set. JXL files are always processed as tokio tasks.
+    async fn queue_file(&mut self, path: PathBuf, destinations: Destinations) -> Result<()> {
+        if self.config.parallel && !imx::is_jxl_file(&path) {
+            self.pooled.push((path, destinations));
+            Ok(())
+        } else {
+            self.spawn_file(path, destinations).await
+        }
+    }
+
+    /// Queue a file for processing once a concurrency slot is free. Nothing is queued after
+    /// an interruption.
+    async fn spawn_file(&mut self, path: PathBuf, destinations: Destinations) -> Result<()> {
+        // Wait for a free slot so that at most `jobs` files are in flight, no matter how deep
+        // the tree is
+        let permit = Arc::clone(&self.semaphore).acquire_owned().await?;
+        if self.is_interrupted() {
+            return Ok(());
+        }
+        let config = Arc::clone(&self.config);
+        let progress = self.progress.clone().filter(|_| is_image_file(&path));
+        self.tasks.spawn(async move {
+            let _permit = permit;
+            let result = process_file_to(&path, &config, &destinations).await;
+            if let Some(progress) = progress {
+                advance_progress(&progress, &config);
+            }
+            (path, result)
+        });
+
+        // Check on finished files so that an error stops the walk early
+        while let Some(joined) = self.tasks.try_join_next() {
+            self.record(joined)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the outcome of a finished file. Failures are collected when
+    /// [`Config::continue_on_error`] is set and returned otherwise.
+    fn record(&mut self, joined: Result<FileOutcome, JoinError>) -> Result<()> {
+        let (path, result) = joined.context("File processing task panicked")?;
+        self.report.entries.push(ReportEntry::new(&path, &result));
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) if self.config.continue_on_error => {
+                warn!("Failed to process {}: {err:#}", path.display());
+                self.report.failures.push((path, err));
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Process the files set aside for the thread pool, keeping the first error
+    async fn run_pool(&mut self) -> Result<()> {
+        let files = std::mem::take(&mut self.pooled);
+        let outcomes = parallel::process_in_pool(
+            files,
+            Arc::clone(&self.config),
+            self.progress.clone(),
+            Arc::clone(&self.interrupted),
+        )
+        .await?;
+
+        let mut result = Ok(());
+        for outcome in outcomes {
+            let file_result = self.record(Ok(outcome));
+            if result.is_ok() {
+                result = file_result;
+            }
+        }
+        result
+    }
+
+    /// Process the files set aside for the thread pool and wait for files that are still
+    /// being processed, keeping the first error
+    async fn finish(&mut self, mut result: Result<()>) -> Result<()> {
+        if result.is_ok() && !self.pooled.is_empty() {
+            result = self.run_pool().await;
+        }
+        while let Some(joined) = self.tasks.join_next().await {
+            let file_result = self.record(joined);
+            if result.is_ok() {
+                result = file_result;
+            }
+        }
+
+        // Stop listening so that Ctrl-C behaves as usual once the run is over
+        if let Some(listener) = self.interrupt_listener.take() {
+            listener.abort();
+        }
+        self.report.interrupted = self.is_interrupted();
+
+        if let Some(progress) = &self.progress {
+            progress.finish();
+        }
+
+        result
+    }
+}
+
+/// Destination of the files found by [`walk_tree`]
+enum FileSink<'a> {
+    /// Queue files for processing right away
+    Queue(&'a mut DirectoryWalk),
+    /// Send files to the task that processes them
+    Channel(mpsc::Sender<(PathBuf, Destinations)>),
+    /// Collect the files found
+    Collect(&'a mut Vec<(PathBuf, Destinations)>),
+}
+
+impl FileSink<'_> {
+    /// Pass on a file to process, returning whether the walk should go on
+    async fn send(&mut self, path: PathBuf, destinations: Destinations) -> Result<bool> {
+        match self {
+            Self::Queue(walk) => {
+                walk.queue_file(path, destinations).await?;
+                Ok(true)
+            }
+            // The receiver is only dropped once processing has stopped
+            Self::Channel(sender) => Ok(sender.send((path, destinations)).await.is_ok()),
+            Self::Collect(files) => {
+                files.push((path, destinations));
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Walk the tree under `root` using a work queue, passing the files to process to `sink` as
+/// they are found, each with the directories its copies are written to. Subdirectories are
+/// only entered when [`Config::recursive`] is set.
+async fn walk_tree(
+    root: &Path,
+    config: &Config,
+    interrupted: &AtomicBool,
+    sink: &mut FileSink<'_>,
+) -> Result<()> {
+    let root_entry = (
+        root.to_owned(),
+        Destinations::new(config),
+        0,
+        IgnoreRules::default(),
+    );
+    let mut pending = VecDeque::from([root_entry]);
+    let mut visited = HashSet::new();
+    // Images passed on so far, for Config::max_files
+    let mut submitted = 0;
+    if config.max_files == Some(0) {
+        return Ok(());
+    }
+
+    while let Some((dir, destinations, depth, rules)) = pending.pop_front() {
+        if interrupted.load(Ordering::Relaxed) {
+            break;
+        }
+        if !first_visit(&mut visited, &dir).await? {
+            continue;
+        }
+        let rules = rules.enter(&dir).await?;
+        info!("Processing directory: {}", dir.display());
+
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("Failed to read directory entry in: {}", dir.display()))?
+        {
+            let path = entry.path();
+            match entry_kind(&entry, config).await? {
+                kind if rules.is_ignored(&path, kind == EntryKind::Directory) => {
+                    debug!(target: SKIPPED_LOG_TARGET, "Skipping ignored path: {}", path.display());
+                }
+                EntryKind::File if sidecar::is_sidecar(&path) => {}
+                EntryKind::File if is_excluded(&path, root, config) => {
+                    debug!(
+                        target: SKIPPED_LOG_TARGET,
+                        "Skipping excluded file: {}",
+                        path.display()
+                    );
+                }
+                EntryKind::File if has_selected_extension(&path, config) => {
+                    if !modified_since(&path, config).await {
+                        debug!(
+                            target: SKIPPED_LOG_TARGET,
+                            "Skipping file modified before --since: {}",
+                            path.display()
+                        );
+                        continue;
+                    }
+                    let eligible = is_image_file(&path);
+                    if !sink.send(path, destinations.clone()).await? {
+                        return Ok(());
+                    }
+                    if eligible {
+                        submitted += 1;
+                        if config.max_files.is_some_and(|max| submitted >= max) {
+                            info!("Stopping after {submitted} files, as set by --max-files");
+                            return Ok(());
+                        }
+                    }
+                }
+                EntryKind::Directory if triage::is_sort_dir(&path, config) => {
+                    debug!(
+                        target: SKIPPED_LOG_TARGET,
+                        "Skipping directory of sorted images: {}",
+                        path.display()
+                    );
+                }
+                EntryKind::Directory if is_excluded_dir(&path, config) => {
+                    debug!(
+                        target: SKIPPED_LOG_TARGET,
+                        "Skipping excluded directory: {}",
+                        path.display()
+                    );
+                }
+                EntryKind::Directory if descends(config, depth) => {
+                    let sub_destinations = destinations.join(&entry.file_name(), config);
+                    pending.push_back((path, sub_destinations, depth + 1, rules.clone()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Process a directory of image files.
+///
+/// Subdirectories are only processed when [`Config::recursive`] is set, in which case their
+/// structure is mirrored under [`Config::output`]. Up to [`Config::jobs`] files are processed
+/// concurrently, on a thread pool when [`Config::parallel`] is set. With
+/// [`Config::parallel_io`], directories are read ahead of processing on a separate task. When
+/// [`Config::progress`] is set, a progress bar tracks completed images.
+///
+/// Returns a [`RunReport`] with an entry for every file. When [`Config::continue_on_error`] is
+/// set, failed files are collected in the report; otherwise the first failure aborts the run.
+///
+/// # Errors
+///
+/// Returns an error if a directory cannot be read, or if any image fails to process and
+/// [`Config::continue_on_error`] is not set.
+pub async fn process_directory(dir: &Path, config: &Config) -> Result<RunReport, LetterboxError> {
+    let progress = if config.progress {
+        Some(create_progress_bar(count_images(dir, config).await?))
+    } else {
+        None
+    };
+
+    let mut walk = DirectoryWalk::new(config, progress);
+    let result = walk.walk(dir).await;
+    walk.finish(result).await?;
+
+    let mut report = walk.report;
+    if let Some(action) = config.dedupe {
+        if config.dry_run || report.interrupted {
+            info!("Not looking for duplicates, no images were written");
+        } else {
+            let root = config.output.as_deref().unwrap_or(dir);
+            report.duplicates = dedupe::remove_duplicates(root, config, action).await?;
+        }
+    }
+    Ok(report)
+}
+
+/// Process an explicit list of files, each as with [`process_file`].
+///
+/// Files are written to [`Config::output`] when set, and processed concurrently and reported
+/// the same way as with [`process_directory`].
+///
+/// # Errors
+///
+/// Returns an error if any file fails to process and [`Config::continue_on_error`] is not set.
+pub async fn process_files(
+    paths: &[PathBuf],
+    config: &Config,
+) -> Result<RunReport, LetterboxError> {
+    let progress = config.progress.then(|| {
+        let images = paths.iter().filter(|path| is_image_file(path)).count();
+        create_progress_bar(images as u64)
+    });
+
+    let mut walk = DirectoryWalk::new(config, progress);
+    let destinations = Destinations::new(config);
+    let mut result = Ok(());
+    for path in paths {
+        result = walk.queue_file(path.clone(), destinations.clone()).await;
+        if result.is_err() || walk.is_interrupted() {
+            break;
+        }
+    }
+    walk.finish(result).await?;
+
+    Ok(walk.report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, ImageBuffer, Rgba};
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Write a white image to `path`, with black bars over the top and bottom quarters when
+    /// `with_letterbox` is set. Shared by the tests of every module.
+    pub(crate) fn create_test_image(
+        path: &Path,
+        width: u32,
+        height: u32,
+        with_letterbox: bool,
+    ) -> Result<()> {
+        let mut img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(width, height);
+
+        // Fill the image with white pixels
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = if with_letterbox && (y < height / 4 || y > height * 3 / 4) {
+                    Rgba([0, 0, 0, 255]) // Black letterbox
+                } else {
+                    Rgba([255, 255, 255, 255]) // White content
+                };
+                img.put_pixel(x, y, pixel);
+            }
+        }
+
+        img.save(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_file_invalid_path() -> Result<()> {
+        let result = process_file(Path::new("nonexistent.jpg"), &Config::default()).await;
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_file_non_image() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let non_image = temp_dir.path().join("test.txt");
+        fs::write(&non_image, "not an image")?;
+
+        let result = process_file(&non_image, &Config::default()).await;
+        assert!(result.is_ok()); // Should skip non-image files
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_file_strict_input() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let non_image = temp_dir.path().join("test.txt");
+        fs::write(&non_image, "not an image")?;
+
+        let config = Config {
+            strict_input: true,
+            ..Config::default()
+        };
+        assert!(process_file(&non_image, &config).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_file_with_letterbox() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.png");
+        create_test_image(&image_path, 100, 100, true)?;
+
+        process_file(&image_path, &Config::default()).await?;
+
+        // Verify the image was processed
+        let processed_img = image::open(&image_path)?;
+        let (width, height) = processed_img.dimensions();
+        assert_eq!(width, 100);
+        assert!(height < 100); // Should be cropped
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_file_without_letterbox() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.png");
+        create_test_image(&image_path, 100, 100, false)?;
+
+        process_file(&image_path, &Config::default()).await?;
+
+        // Verify the image was not modified
+        let processed_img = image::open(&image_path)?;
+        let (width, height) = processed_img.dimensions();
+        assert_eq!(width, 100);
+        assert_eq!(height, 100); // Should not be cropped
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+
+        // Create test images in root directory
+        let img1 = temp_dir.path().join("test1.png");
+        let img2 = temp_dir.path().join("test2.png");
+        create_test_image(&img1, 100, 100, true)?;
+        create_test_image(&img2, 100, 100, false)?;
+
+        // Create subdirectory with more images
+        let sub_dir = temp_dir.path().join("subdir");
+        fs::create_dir(&sub_dir)?;
+        let img3 = sub_dir.join("test3.png");
+        create_test_image(&img3, 100, 100, true)?;
+
+        // Test non-recursive
+        process_directory(temp_dir.path(), &Config::default()).await?;
+        let processed_img1 = image::open(&img1)?;
+        assert!(processed_img1.dimensions().1 < 100); // Should be cropped
+        let processed_img2 = image::open(&img2)?;
+        assert_eq!(processed_img2.dimensions().1, 100); // Should not be cropped
+        let unprocessed_img3 = image::open(&img3)?;
+        assert_eq!(unprocessed_img3.dimensions().1, 100); // Should not be processed
+
+        // Test recursive
+        let config = Config {
+            recursive: true,
+            ..Config::default()
+        };
+        process_directory(temp_dir.path(), &config).await?;
+        let processed_img3 = image::open(&img3)?;
+        assert!(processed_img3.dimensions().1 < 100); // Should be cropped
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_with_output() -> Result<()> {
+        let input_dir = TempDir::new()?;
+        let output_dir = TempDir::new()?;
+
+        let img1 = input_dir.path().join("test1.png");
+        create_test_image(&img1, 100, 100, true)?;
+        let sub_dir = input_dir.path().join("subdir");
+        fs::create_dir(&sub_dir)?;
+        let img2 = sub_dir.join("test2.png");
+        create_test_image(&img2, 100, 100, true)?;
+
+        let output = output_dir.path().join("cropped");
+        let config = Config {
+            recursive: true,
+            output: Some(output.clone()),
+            ..Config::default()
+        };
+        process_directory(input_dir.path(), &config).await?;
+
+        // Originals are left untouched
+        assert_eq!(image::open(&img1)?.dimensions().1, 100);
+        assert_eq!(image::open(&img2)?.dimensions().1, 100);
+
+        // Cropped copies mirror the input tree
+        assert!(image::open(output.join("test1.png"))?.dimensions().1 < 100);
+        assert!(
+            image::open(output.join("subdir").join("test2.png"))?
+                .dimensions()
+                .1
+                < 100
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_keeps_input_formats() -> Result<()> {
+        let input_dir = TempDir::new()?;
+        let output_dir = TempDir::new()?;
+        create_test_image(&input_dir.path().join("a.png"), 100, 100, true)?;
+        let jpeg = image::open(input_dir.path().join("a.png"))?.to_rgb8();
+        jpeg.save(input_dir.path().join("b.jpg"))?;
+        // A PNG with the extension of a JPEG stays a PNG
+        fs::copy(
+            input_dir.path().join("a.png"),
+            input_dir.path().join("c.jpg"),
+        )?;
+
+        let config = Config {
+            output: Some(output_dir.path().to_owned()),
+            ..Config::default()
+        };
+        process_directory(input_dir.path(), &config).await?;
+
+        for (name, format) in [
+            ("a.png", ImageFormat::Png),
+            ("b.jpg", ImageFormat::Jpeg),
+            ("c.jpg", ImageFormat::Png),
+        ] {
+            let written = fs::read(output_dir.path().join(name))?;
+            assert_eq!(image::guess_format(&written)?, format, "{name}");
+            assert!(image::load_from_memory(&written)?.dimensions().1 < 100);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_mirrors_nested_names() -> Result<()> {
+        let input_dir = TempDir::new()?;
+        let output_dir = TempDir::new()?;
+        // The same file name at several depths must not collide in the output
+        for dir in ["", "a", "b", "a/deep/er"] {
+            let dir = input_dir.path().join(dir);
+            fs::create_dir_all(&dir)?;
+            create_test_image(&dir.join("frame.png"), 100, 100, true)?;
+        }
+        #[cfg(unix)]
+        let odd_name = {
+            use std::os::unix::ffi::OsStrExt;
+            let name = std::ffi::OsStr::from_bytes(b"odd\xff name.png");
+            create_test_image(&input_dir.path().join("b").join(name), 100, 100, true)?;
+            name
+        };
+
+        let config = Config {
+            recursive: true,
+            output: Some(output_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let report = process_directory(input_dir.path(), &config).await?;
+        assert_eq!(report.summary().cropped, if cfg!(unix) { 5 } else { 4 });
+        for dir in ["", "a", "b", "a/deep/er"] {
+            let written = output_dir.path().join(dir).join("frame.png");
+            assert_eq!(image::open(written)?.height(), 51);
+        }
+        #[cfg(unix)]
+        assert_eq!(
+            image::open(output_dir.path().join("b").join(odd_name))?.height(),
+            51
+        );
+
+        // Flattened, the copies are renamed apart in the output root instead, and processing
+        // the tree again skips the copies written the first time
+        let flat_dir = output_dir.path().join("flat");
+        let config = Config {
+            recursive: true,
+            output: Some(flat_dir.clone()),
+            flatten: true,
+            ..Config::default()
+        };
+        process_directory(input_dir.path(), &config).await?;
+        let mut names = fs::read_dir(&flat_dir)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        let mut expected = vec!["frame-1.png", "frame-2.png", "frame-3.png", "frame.png"];
+        if cfg!(unix) {
+            expected.push("odd\u{fffd} name.png");
+        }
+        assert_eq!(
+            names
+                .iter()
+                .map(|name| name.to_string_lossy())
+                .collect::<Vec<_>>(),
+            expected
+        );
+        let report = process_directory(input_dir.path(), &config).await?;
+        assert_eq!(report.summary().cropped, 0);
+        assert_eq!(fs::read_dir(&flat_dir)?.count(), expected.len());
+
+        // A single file has no root to mirror, so it goes straight into the output
+        let single = input_dir.path().join("a/deep/er/frame.png");
+        let config = Config {
+            output: Some(output_dir.path().join("single")),
+            ..Config::default()
+        };
+        process_file(&single, &config).await?;
+        assert_eq!(
+            image::open(output_dir.path().join("single/frame.png"))?.height(),
+            51
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_file_dry_run() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.png");
+        create_test_image(&image_path, 100, 100, true)?;
+        let original = fs::read(&image_path)?;
+
+        let config = Config {
+            dry_run: true,
+            ..Config::default()
+        };
+        process_file(&image_path, &config).await?;
+
+        // Verify the file was left untouched
+        assert_eq!(fs::read(&image_path)?, original);
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_crop() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(100, 100, |_, y| {
+            if (25..75).contains(&y) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        }));
+
+        let result = CropResult::new(
+            img.dimensions(),
+            letterbox_bounds(&img, [[10; 3]; 4], Axis::Both, [0; 3], 0, 1, 0),
+        );
+        assert_eq!(
+            describe_crop(Path::new("test.png"), &result),
+            "test.png: 100x100 -> 100x50, removing 25px top/25px bottom"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_crop_file_reports_exact_amounts() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.png");
+        create_test_image(&image_path, 100, 100, true)?;
+
+        let result = crop_file(&image_path, &Config::default()).await?;
+        assert_eq!(
+            result,
+            CropResult {
+                original: (100, 100),
+                cropped: (100, 51),
+                top: 25,
+                bottom: 24,
+                left: 0,
+                right: 0,
+                modified: true,
+                blank: false,
+                confidence: 1.0,
+            }
+        );
+        assert_eq!(image::open(&image_path)?.dimensions(), (100, 51));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_file_pillarbox() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.png");
+        let img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(100, 50, |x, _| {
+            if (20..80).contains(&x) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        img.save(&image_path)?;
+
+        // Restricting detection to top/bottom bars leaves the pillarbox alone
+        let vertical = Config {
+            axis: Axis::Vertical,
+            dry_run: true,
+            ..Config::default()
+        };
+        assert!(!crop_file(&image_path, &vertical).await?.modified);
+
+        let result = crop_file(&image_path, &Config::default()).await?;
+        assert_eq!(result.cropped, (60, 50));
+        assert_eq!((result.left, result.right), (20, 20));
+        Ok(())
+    }
+
+    #[test]
+    fn test_crop_image_bytes() -> Result<()> {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(100, 100, |_, y| {
+            if (25..75).contains(&y) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        }));
+        let mut png = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)?;
+
+        let config = Config {
+            format: Some(OutputFormat::Jpeg),
+            ..Config::default()
+        };
+        let (encoded, result) = crop_image_bytes(&png, Path::new("<stdin>"), &config)?;
+        assert_eq!(result.cropped, (100, 50));
+        assert_eq!(image::guess_format(&encoded)?, ImageFormat::Jpeg);
+        assert_eq!(image::load_from_memory(&encoded)?.dimensions(), (100, 50));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sweep_thresholds() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        // Gray bars that only count as letterbox from a threshold of 20
+        ImageBuffer::from_fn(100, 100, |_, y| {
+            if (25..75).contains(&y) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([20, 20, 20, 255])
+            }
+        })
+        .save(&path)?;
+
+        let sweep = sweep_thresholds(&path, &[10, 20]).await?;
+        assert_eq!(sweep[0].0, 10);
+        assert!(!sweep[0].1.modified);
+        assert_eq!(sweep[1].1.cropped, (100, 50));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_candidates() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let letterboxed = temp_dir.path().join("letterboxed.png");
+        create_test_image(&letterboxed, 1000, 1000, true)?;
+        create_test_image(&temp_dir.path().join("plain.png"), 1000, 1000, false)?;
+        fs::write(temp_dir.path().join("notes.txt"), "not an image")?;
+
+        let candidates = find_candidates(temp_dir.path(), &Config::default()).await?;
+        assert_eq!(candidates, [letterboxed.clone()]);
+        // Nothing is modified
+        assert_eq!(image::open(&letterboxed)?.height(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_letterbox_in_memory() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(100, 80, |x, _| {
+            if (10..90).contains(&x) {
+                Rgba([200, 200, 200, 255])
+            } else {
+                Rgba([4, 4, 4, 255])
+            }
+        }));
+
+        assert_eq!(
+            detect_letterbox(&img, 10),
+            CropRect {
+                x: 10,
+                y: 0,
+                width: 80,
+                height: 80,
+            }
+        );
+        assert_eq!(detect_letterbox(&img, 2), CropRect::full((100, 80)));
+    }
+
+    #[test]
+    fn test_detect_letterbox_per_channel_threshold() {
+        // Dark blue bars above and below white content
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(10, 10, |_, y| {
+            if (2..8).contains(&y) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([5, 5, 40, 255])
+            }
+        }));
+
+        assert_eq!(
+            letterbox_bounds(&img, [[10; 3]; 4], Axis::Both, [0; 3], 0, 1, 0),
+            None
+        );
+        assert_eq!(
+            letterbox_bounds(&img, [[10, 10, 50]; 4], Axis::Both, [0; 3], 0, 1, 0),
+            Some(CropRect {
+                x: 0,
+                y: 2,
+                width: 10,
+                height: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn test_detect_letterbox_invert() {
+        // Light gray border around a dark square
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(10, 10, |x, y| {
+            if (3..7).contains(&x) && (3..7).contains(&y) {
+                Rgba([30, 30, 30, 255])
+            } else {
+                Rgba([250, 248, 252, 255])
+            }
+        }));
+
+        assert_eq!(
+            letterbox_bounds(&img, [[10; 3]; 4], Axis::Both, [0; 3], 0, 1, 0),
+            None
+        );
+        assert_eq!(
+            letterbox_bounds(&img, [[10; 3]; 4], Axis::Both, [255; 3], 0, 1, 0),
+            Some(CropRect {
+                x: 3,
+                y: 3,
+                width: 4,
+                height: 4,
+            })
+        );
+
+        // An all-white image is never cropped away entirely
+        let white = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(10, 10, Rgba([255; 4])));
+        assert_eq!(
+            letterbox_bounds(&white, [[10; 3]; 4], Axis::Both, [255; 3], 0, 1, 0),
+            None
+        );
+    }
+
+    #[test]
+    fn test_detect_letterbox_tolerance() {
+        // Black bars with a single bright noise pixel in each bar row
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(100, 10, |x, y| {
+            if (2..8).contains(&y) || x == 50 {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        }));
+
+        assert_eq!(
+            letterbox_bounds(&img, [[10; 3]; 4], Axis::Vertical, [0; 3], 0, 1, 0),
+            None
+        );
+        assert_eq!(
+            letterbox_bounds(&img, [[10; 3]; 4], Axis::Vertical, [0; 3], 2, 1, 0),
+            Some(CropRect {
+                x: 0,
+                y: 2,
+                width: 100,
+                height: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn test_detect_letterbox_side_thresholds() {
+        // A black top bar and a dark gray bottom bar
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(100, 100, |_, y| match y {
+            0..10 => Rgba([0, 0, 0, 255]),
+            90.. => Rgba([30, 30, 30, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        }));
+
+        let bounds = |thresholds| {
+            letterbox_bounds(&img, thresholds, Axis::Vertical, [0; 3], 0, 1, 0)
+                .map(|rect| (rect.y, rect.height))
+        };
+        assert_eq!(bounds([[10; 3]; 4]), Some((10, 90)));
+        assert_eq!(bounds([[10; 3], [40; 3], [10; 3], [10; 3]]), Some((10, 80)));
+
+        let config = Config {
+            side_thresholds: SideThresholds {
+                bottom: Some([40; 3]),
+                ..SideThresholds::default()
+            },
+            ..Config::default()
+        };
+        assert_eq!(
+            detect_content(&img, &DetectionParams::new(&config), Path::new("test.png")),
+            Some(CropRect {
+                x: 0,
+                y: 10,
+                width: 100,
+                height: 80,
+            })
+        );
+    }
+
+    #[test]
+    fn test_detect_letterbox_rounded_corners() {
+        // The bars reach the content only between its light corners
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(100, 100, |x, y| {
+            let in_corner = (x < 5 || x >= 95) && ((10..20).contains(&y) || (80..90).contains(&y));
+            if (20..80).contains(&y) || in_corner {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        }));
+
+        let bounds = |corner| {
+            letterbox_bounds(&img, [[10; 3]; 4], Axis::Vertical, [0; 3], 0, 1, corner)
+                .map(|rect| (rect.y, rect.height))
+        };
+        assert_eq!(bounds(0), Some((10, 80)));
+        assert_eq!(bounds(8), Some((20, 60)));
+    }
+
+    #[test]
+    fn test_detect_letterbox_sample_step() {
+        // A one pixel wide streak in the top bar is only seen when its column is sampled
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(100, 100, |x, y| {
+            if (20..80).contains(&y) || (y < 10 && x == 51) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        }));
+
+        let bounds = |step| {
+            letterbox_bounds(&img, [[10; 3]; 4], Axis::Vertical, [0; 3], 0, step, 0)
+                .map(|rect| (rect.y, rect.height))
+        };
+        assert_eq!(bounds(1), Some((0, 80)));
+        assert_eq!(bounds(3), Some((0, 80)));
+        assert_eq!(bounds(2), Some((20, 60)));
+    }
+
+    #[test]
+    fn test_feathered() {
+        // Black bars that fade into the content over three rows
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(10, 100, |_, y| {
+            let distance = y.min(99 - y);
+            let value = match distance {
+                0..20 => 0,
+                20..23 => 60 * (distance - 19),
+                _ => 255,
+            };
+            let value = u8::try_from(value).unwrap_or(u8::MAX);
+            Rgba([value, value, value, 255])
+        }));
+
+        let rect = letterbox_bounds(&img, [[10; 3]; 4], Axis::Vertical, [0; 3], 0, 1, 0);
+        assert_eq!(rect.map(|rect| (rect.y, rect.height)), Some((20, 60)));
+        let rect = rect.map(|rect| feathered(&img, rect, [0; 3], 5));
+        assert_eq!(rect.map(|rect| (rect.y, rect.height)), Some((23, 54)));
+
+        // The feather never trims more than the given number of lines
+        let rect = letterbox_bounds(&img, [[10; 3]; 4], Axis::Vertical, [0; 3], 0, 1, 0)
+            .map(|rect| feathered(&img, rect, [0; 3], 1));
+        assert_eq!(rect.map(|rect| (rect.y, rect.height)), Some((21, 58)));
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_concurrent_error() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        for i in 0..4 {
+            create_test_image(
+                &temp_dir.path().join(format!("test{i}.png")),
+                100,
+                100,
+                true,
+            )?;
+        }
+        fs::write(temp_dir.path().join("corrupt.png"), "not an image")?;
+
+        let config = Config {
+            jobs: 2,
+            ..Config::default()
+        };
+        let result = process_directory(temp_dir.path(), &config).await;
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_parallel() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        for i in 0..4 {
+            create_test_image(
+                &temp_dir.path().join(format!("test{i}.png")),
+                100,
+                100,
+                i % 2 == 0,
+            )?;
+        }
+        let corrupt = temp_dir.path().join("corrupt.png");
+        fs::write(&corrupt, "not an image")?;
+
+        let config = Config {
+            parallel: true,
+            jobs: 2,
+            continue_on_error: true,
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        assert_eq!(report.entries.len(), 5);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0, corrupt);
+        // The counters updated by the pool threads agree with the recorded entries
+        assert_eq!(report.summary(), Summary::from_entries(&report.entries));
+        assert_eq!(report.summary().cropped, 2);
+        assert!(
+            image::open(temp_dir.path().join("test0.png"))?
+                .dimensions()
+                .1
+                < 100
+        );
+        assert_eq!(
+            image::open(temp_dir.path().join("test1.png"))?
+                .dimensions()
+                .1,
+            100
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_parallel_output() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let output_dir = TempDir::new()?;
+        create_test_image(&temp_dir.path().join("boxed.png"), 100, 100, true)?;
+        create_test_image(&temp_dir.path().join("plain.png"), 100, 100, false)?;
+
+        let config = Config {
+            parallel: true,
+            output: Some(output_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        assert_eq!(report.summary().cropped, 1);
+        assert!(
+            image::open(output_dir.path().join("boxed.png"))?
+                .dimensions()
+                .1
+                < 100
+        );
+        assert_eq!(
+            fs::read(output_dir.path().join("plain.png"))?,
+            fs::read(temp_dir.path().join("plain.png"))?
+        );
+        // The originals are left untouched
+        assert_eq!(
+            image::open(temp_dir.path().join("boxed.png"))?
+                .dimensions()
+                .1,
+            100
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_count_images() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_image(&temp_dir.path().join("test1.png"), 10, 10, false)?;
+        fs::write(temp_dir.path().join("notes.txt"), "not an image")?;
+        let sub_dir = temp_dir.path().join("subdir");
+        fs::create_dir(&sub_dir)?;
+        create_test_image(&sub_dir.join("test2.png"), 10, 10, false)?;
+
+        let config = Config::default();
+        assert_eq!(count_images(temp_dir.path(), &config).await?, 1);
+        let config = Config {
+            recursive: true,
+            ..Config::default()
+        };
+        assert_eq!(count_images(temp_dir.path(), &config).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_continue_on_error() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.png");
+        create_test_image(&image_path, 100, 100, true)?;
+        let corrupt = temp_dir.path().join("corrupt.png");
+        fs::write(&corrupt, "not an image")?;
+
+        let config = Config {
+            continue_on_error: true,
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0, corrupt);
+        assert_eq!(report.entries.len(), 2);
+        assert!(
+            report
+                .entries
+                .iter()
+                .any(|entry| entry.path == corrupt && entry.status == Status::Error)
+        );
+        assert!(image::open(&image_path)?.dimensions().1 < 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only_file() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let read_only = temp_dir.path().join("read-only.png");
+        create_test_image(&read_only, 100, 100, true)?;
+        let mut permissions = fs::metadata(&read_only)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&read_only, permissions)?;
+        let corrupt = temp_dir.path().join("corrupt.png");
+        fs::write(&corrupt, "not an image")?;
+
+        assert!(matches!(
+            process_file(&read_only, &Config::default()).await,
+            Err(LetterboxError::PermissionDenied { .. })
+        ));
+
+        let config = Config {
+            continue_on_error: true,
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+        let summary = report.summary();
+        assert_eq!((summary.errors, summary.permission_denied), (1, 1));
+        assert!(
+            report
+                .entries
+                .iter()
+                .any(|entry| entry.path == read_only && entry.status == Status::PermissionDenied)
+        );
+        assert_eq!(image::open(&read_only)?.dimensions().1, 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_backup() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cropped = temp_dir.path().join("cropped.png");
+        let unchanged = temp_dir.path().join("unchanged.png");
+        create_test_image(&cropped, 100, 100, true)?;
+        create_test_image(&unchanged, 100, 100, false)?;
+        let original = fs::read(&cropped)?;
+
+        let config = Config {
+            backup: Some(".bak".to_string()),
+            ..Config::default()
+        };
+        process_directory(temp_dir.path(), &config).await?;
+
+        // Only images that were actually cropped get a backup
+        assert_eq!(fs::read(temp_dir.path().join("cropped.png.bak"))?, original);
+        assert!(!temp_dir.path().join("unchanged.png.bak").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blank_policy() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("blank.png");
+        let blank = ImageBuffer::from_pixel(50, 50, Rgba([0u8, 0, 0, 255]));
+        blank.save(&path)?;
+
+        // Skipped by default, also when writing copies
+        assert_eq!(process_file(&path, &Config::default()).await?, None);
+        assert_eq!(image::open(&path)?.dimensions(), (50, 50));
+        let output = temp_dir.path().join("out");
+        let config = Config {
+            output: Some(output.clone()),
+            ..Config::default()
+        };
+        assert_eq!(process_file(&path, &config).await?, None);
+        assert!(!output.join("blank.png").exists());
+
+        let config = Config {
+            blank_policy: BlankPolicy::Keep,
+            ..config
+        };
+        let result = process_file(&path, &config).await?;
+        assert!(result.is_some_and(|result| result.blank && !result.modified));
+        assert_eq!(
+            image::open(output.join("blank.png"))?.dimensions(),
+            (50, 50)
+        );
+
+        let config = Config {
+            blank_policy: BlankPolicy::Delete,
+            ..Config::default()
+        };
+        assert_eq!(process_file(&path, &config).await?, None);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_max_files() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let sub_dir = temp_dir.path().join("sub");
+        fs::create_dir(&sub_dir)?;
+        for i in 0..3 {
+            create_test_image(&temp_dir.path().join(format!("{i}.png")), 100, 100, true)?;
+            create_test_image(&sub_dir.join(format!("{i}.png")), 100, 100, true)?;
+            fs::write(temp_dir.path().join(format!("{i}.txt")), "not an image")?;
+        }
+
+        let config = Config {
+            recursive: true,
+            dry_run: true,
+            max_files: Some(4),
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        let images = report
+            .entries
+            .iter()
+            .filter(|entry| entry.status == Status::Cropped)
+            .count();
+        assert_eq!(images, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_since() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let old = temp_dir.path().join("old.png");
+        let new = temp_dir.path().join("new.png");
+        create_test_image(&old, 100, 100, true)?;
+        create_test_image(&new, 100, 100, true)?;
+        let yesterday = SystemTime::now() - std::time::Duration::from_secs(24 * 60 * 60);
+        filetime::set_file_mtime(&old, FileTime::from_system_time(yesterday))?;
+
+        let config = Config {
+            since: Some(SystemTime::now() - std::time::Duration::from_secs(60 * 60)),
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        assert_eq!(report.entries.len(), 1);
+        assert_eq!(image::open(&old)?.height(), 100);
+        assert!(image::open(&new)?.height() < 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_extensions() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let png = temp_dir.path().join("test.PNG");
+        let webp = temp_dir.path().join("test.webp");
+        create_test_image(&png, 100, 100, true)?;
+        create_test_image(&webp, 100, 100, true)?;
+
+        let config = Config {
+            extensions: Some(vec!["png".to_string()]),
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        assert_eq!(report.entries.len(), 1);
+        assert!(image::open(&png)?.dimensions().1 < 100);
+        assert_eq!(image::open(&webp)?.dimensions().1, 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_file_min_size() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let icon = temp_dir.path().join("icon.png");
+        create_test_image(&icon, 16, 16, true)?;
+
+        let config = Config {
+            min_width: 32,
+            min_height: 32,
+            ..Config::default()
+        };
+        assert_eq!(process_file(&icon, &config).await?, None);
+        assert_eq!(image::open(&icon)?.dimensions(), (16, 16));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_file_max_pixels() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("huge.png");
+        create_test_image(&image_path, 100, 100, true)?;
+
+        let config = Config {
+            max_pixels: Some(5000),
+            ..Config::default()
+        };
+        assert_eq!(process_file(&image_path, &config).await?, None);
+        assert_eq!(image::open(&image_path)?.dimensions(), (100, 100));
+
+        let bytes = std::fs::read(&image_path)?;
+        assert!(crop_image_bytes(&bytes, &image_path, &config).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_file_max_crop_percent() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.png");
+        // Only a thin strip of content in the middle
+        let img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(100, 100, |_, y| {
+            if (45..55).contains(&y) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        img.save(&image_path)?;
+
+        let result = crop_file(&image_path, &Config::default()).await?;
+        assert!(!result.modified);
+        assert_eq!(image::open(&image_path)?.dimensions(), (100, 100));
+
+        let config = Config {
+            max_crop_percent: 95,
+            ..Config::default()
+        };
+        assert_eq!(crop_file(&image_path, &config).await?.cropped, (100, 10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_file_preserves_metadata() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.jpg");
+        let img: ImageBuffer<image::Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(64, 64, |_, y| {
+            if (16..48).contains(&y) {
+                image::Rgb([255, 255, 255])
+            } else {
+                image::Rgb([0, 0, 0])
+            }
+        });
+        let mut bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)?;
+        let mut metadata = Metadata::new();
+        metadata.set_tag(ExifTag::ImageDescription("letterboxed".to_string()));
+        metadata.write_to_vec(&mut bytes, FileExtension::JPEG)?;
+        fs::write(&image_path, bytes)?;
+
+        assert!(crop_file(&image_path, &Config::default()).await?.modified);
+
+        let cropped = fs::read(&image_path)?;
+        let metadata = Metadata::new_from_vec(&cropped, FileExtension::JPEG)?;
+        let description = ExifTag::ImageDescription(String::new());
+        assert!(metadata.get_tag(&description).next().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exif_orientation() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("rotated.jpg");
+        // Stored on its side: orientation 6 turns it clockwise for display, so the bars on the
+        // left and right of the stored pixels are shown at the top and bottom
+        let img: ImageBuffer<image::Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(96, 60, |x, _| {
+            if (16..80).contains(&x) {
+                image::Rgb([255, 255, 255])
+            } else {
+                image::Rgb([0, 0, 0])
+            }
+        });
+        let mut bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)?;
+        let mut metadata = Metadata::new();
+        metadata.set_tag(ExifTag::Orientation(vec![6]));
+        metadata.write_to_vec(&mut bytes, FileExtension::JPEG)?;
+        fs::write(&image_path, bytes)?;
+
+        // Only letterbox bars are removed, which are only there in display space
+        let config = Config {
+            axis: Axis::Vertical,
+            ..Config::default()
+        };
+        let result = crop_file(&image_path, &config).await?;
+        assert_eq!(result.original, (60, 96));
+        assert_eq!(result.cropped, (60, 64));
+        assert_eq!((result.top, result.bottom), (16, 16));
+
+        // The cropped image is stored upright, with its orientation reset
+        let cropped = fs::read(&image_path)?;
+        assert_eq!(image::load_from_memory(&cropped)?.dimensions(), (60, 64));
+        let metadata = Metadata::new_from_vec(&cropped, FileExtension::JPEG)?;
+        let orientation = ExifTag::Orientation(Vec::new());
+        assert!(matches!(
+            metadata.get_tag(&orientation).next(),
+            Some(ExifTag::Orientation(values)) if values == &[1]
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_file_preserves_icc_profile() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.jpg");
+        let img: ImageBuffer<image::Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(64, 64, |_, y| {
+            if (16..48).contains(&y) {
+                image::Rgb([255, 255, 255])
+            } else {
+                image::Rgb([0, 0, 0])
+            }
+        });
+        let profile = b"not a real profile, but carried over all the same".repeat(4);
+        let mut bytes = Vec::new();
+        let mut encoder = JpegEncoder::new(&mut bytes);
+        encoder.set_icc_profile(profile.clone())?;
+        DynamicImage::ImageRgb8(img).write_with_encoder(encoder)?;
+        fs::write(&image_path, &bytes)?;
+
+        assert!(crop_file(&image_path, &Config::default()).await?.modified);
+        assert_eq!(read_icc_profile(&fs::read(&image_path)?), Some(profile));
+
+        fs::write(&image_path, &bytes)?;
+        let config = Config {
+            strip_icc: true,
+            ..Config::default()
+        };
+        assert!(crop_file(&image_path, &config).await?.modified);
+        assert_eq!(read_icc_profile(&fs::read(&image_path)?), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_file_preserve_mtime() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("test.png");
+        create_test_image(&image_path, 100, 100, true)?;
+        let mtime = FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&image_path, mtime)?;
+
+        let output = temp_dir.path().join("cropped");
+        let config = Config {
+            output: Some(output.clone()),
+            preserve_mtime: true,
+            ..Config::default()
+        };
+        assert!(crop_file(&image_path, &config).await?.modified);
+
+        let written = fs::metadata(output.join("test.png"))?;
+        assert_eq!(FileTime::from_last_modification_time(&written), mtime);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_directory_symlink_loop() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_image(&temp_dir.path().join("test.png"), 100, 100, true)?;
+        let sub_dir = temp_dir.path().join("subdir");
+        fs::create_dir(&sub_dir)?;
+        std::os::unix::fs::symlink(temp_dir.path(), sub_dir.join("loop"))?;
+
+        let config = Config {
+            recursive: true,
+            follow_symlinks: true,
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        assert_eq!(report.entries.len(), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_directory_skips_symlinked_dirs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let images = temp_dir.path().join("images");
+        let walked = temp_dir.path().join("walked");
+        fs::create_dir(&images)?;
+        fs::create_dir(&walked)?;
+        create_test_image(&images.join("test.png"), 100, 100, true)?;
+        std::os::unix::fs::symlink(&images, walked.join("linked"))?;
+        std::os::unix::fs::symlink(images.join("test.png"), walked.join("file.png"))?;
+
+        let config = Config {
+            recursive: true,
+            dry_run: true,
+            ..Config::default()
+        };
+        let report = process_directory(&walked, &config).await?;
+
+        // Only the symlinked file is visited, not the directory
+        assert_eq!(report.entries.len(), 1);
+        assert!(report.entries[0].path.ends_with("file.png"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_files() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let listed = temp_dir.path().join("listed.png");
+        let unlisted = temp_dir.path().join("unlisted.png");
+        create_test_image(&listed, 100, 100, true)?;
+        create_test_image(&unlisted, 100, 100, true)?;
+
+        let report = process_files(std::slice::from_ref(&listed), &Config::default()).await?;
+
+        assert_eq!(report.entries.len(), 1);
+        assert_eq!(image::open(&listed)?.height(), 51);
+        assert_eq!(image::open(&unlisted)?.height(), 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pad() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let padded = temp_dir.path().join("padded.png");
+        let oversized = temp_dir.path().join("oversized.png");
+        create_test_image(&padded, 100, 100, true)?;
+        create_test_image(&oversized, 100, 100, true)?;
+
+        let config = Config {
+            pad: 5,
+            ..Config::default()
+        };
+        let result = crop_file(&padded, &config).await?;
+        assert_eq!((result.top, result.bottom), (20, 19));
+        assert_eq!(image::open(&padded)?.height(), 61);
+
+        // Padding wider than the bars keeps the original edges
+        let config = Config {
+            pad: 50,
+            ..Config::default()
+        };
+        let result = crop_file(&oversized, &config).await?;
+        assert!(!result.modified);
+        assert_eq!(image::open(&oversized)?.height(), 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_symmetric() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        let config = Config {
+            symmetric: true,
+            ..Config::default()
+        };
+        let result = crop_file(&path, &config).await?;
+
+        assert_eq!((result.top, result.bottom), (24, 24));
+        assert_eq!(image::open(&path)?.height(), 52);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_round_to() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        let config = Config {
+            round_to: 16,
+            max_crop_percent: 60,
+            ..Config::default()
+        };
+        let result = crop_file(&path, &config).await?;
+
+        // 51 rows of content are trimmed to 48, taking one from the top and two from the bottom
+        assert_eq!(result.cropped, (96, 48));
+        assert_eq!((result.top, result.bottom), (26, 26));
+        assert_eq!((result.left, result.right), (2, 2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_policy() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        let output = temp_dir.path().join("cropped");
+        let existing = output.join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+        fs::create_dir(&output)?;
+        fs::write(&existing, b"existing")?;
+
+        let mut config = Config {
+            output: Some(output.clone()),
+            ..Config::default()
+        };
+        assert!(process_file(&path, &config).await?.is_none());
+        assert_eq!(fs::read(&existing)?, b"existing");
+
+        config.overwrite = OverwritePolicy::Rename;
+        process_file(&path, &config).await?;
+        process_file(&path, &config).await?;
+        assert_eq!(image::open(output.join("test-1.png"))?.height(), 51);
+        assert_eq!(image::open(output.join("test-2.png"))?.height(), 51);
+
+        config.overwrite = OverwritePolicy::Overwrite;
+        process_file(&path, &config).await?;
+        assert_eq!(image::open(&existing)?.height(), 51);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_pattern() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        let output = temp_dir.path().join("cropped");
+        create_test_image(&path, 100, 100, true)?;
+
+        let config = Config {
+            output: Some(output.clone()),
+            output_pattern: Some(OutputPattern::parse("{stem}_{w}x{h}.{ext}")?),
+            overwrite: OverwritePolicy::Rename,
+            ..Config::default()
+        };
+        process_file(&path, &config).await?;
+        process_file(&path, &config).await?;
+
+        assert_eq!(image::open(output.join("test_100x51.png"))?.height(), 51);
+        assert!(output.join("test_100x51-1.png").exists());
+        // Nothing is left behind under the staging name
+        assert_eq!(fs::read_dir(&output)?.count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_convert_format() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let converted = temp_dir.path().join("converted.png");
+        let kept = temp_dir.path().join("kept.png");
+        create_test_image(&converted, 100, 100, true)?;
+        create_test_image(&kept, 100, 100, true)?;
+
+        let mut config = Config {
+            format: Some(OutputFormat::Jpeg),
+            ..Config::default()
+        };
+        process_file(&converted, &config).await?;
+        assert!(!converted.exists());
+        assert_eq!(image::open(converted.with_extension("jpg"))?.height(), 51);
+
+        config.keep_original = true;
+        process_file(&kept, &config).await?;
+        assert_eq!(image::open(&kept)?.height(), 100);
+        assert_eq!(image::open(kept.with_extension("jpg"))?.height(), 51);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_convert_background() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("logo.png");
+        // Transparent content between the bars, which detection sees as white
+        let img = ImageBuffer::from_fn(64, 64, |_, y| {
+            if (16..48).contains(&y) {
+                Rgba([255, 255, 255, 0])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        img.save(&path)?;
+
+        let config = Config {
+            format: Some(OutputFormat::Jpeg),
+            background: [255, 0, 0],
+            ..Config::default()
+        };
+        process_file(&path, &config).await?;
+        let converted = image::open(path.with_extension("jpg"))?.to_rgb8();
+        assert_eq!(converted.dimensions(), (64, 32));
+        let image::Rgb([red, green, blue]) = *converted.get_pixel(32, 16);
+        assert!(red > 240 && green < 16 && blue < 16);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 255, 128])));
+        assert_eq!(
+            *flatten(&img, [255, 255, 255]).get_pixel(0, 0),
+            Rgb([127, 127, 255])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webp_round_trip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.webp");
+        let img = ImageBuffer::from_fn(100, 100, |x, y| {
+            if (20..80).contains(&y) {
+                Rgba([u8::try_from(x).unwrap(), u8::try_from(y).unwrap(), 200, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        img.save(&path)?;
+
+        let result = process_file(&path, &Config::default()).await?.unwrap();
+        assert_eq!(result.cropped, (100, 60));
+
+        // Lossless WebP keeps every pixel of the content
+        let cropped = image::open(&path)?.to_rgba8();
+        assert_eq!(
+            cropped,
+            image::imageops::crop_imm(&img, 0, 20, 100, 60).to_image()
+        );
+
+        // Converted images are written losslessly as well
+        let png = temp_dir.path().join("test.png");
+        img.save(&png)?;
+        let config = Config {
+            format: Some(OutputFormat::Webp),
+            ..Config::default()
+        };
+        process_file(&png, &config).await?;
+        let converted = image::open(png.with_extension("webp"))?.to_rgba8();
+        assert_eq!(converted, cropped);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        let config = Config {
+            verify: true,
+            ..Config::default()
+        };
+        let result = process_file(&path, &config).await?.unwrap();
+        assert!(result.modified);
+        verify_output(&path, result.cropped).await?;
+
+        // Dimensions that do not match the written size fail the check
+        assert!(verify_output(&path, (100, 100)).await.is_err());
+
+        // So does a truncated file
+        let bytes = std::fs::read(&path)?;
+        std::fs::write(&path, &bytes[..bytes.len() / 2])?;
+        assert!(verify_output(&path, result.cropped).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timeout() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        // No image is read and cropped before a zero timeout expires
+        let config = Config {
+            timeout: Some(Duration::ZERO),
+            ..Config::default()
+        };
+        let err = process_file(&path, &config).await.unwrap_err();
+        assert!(err.to_string().starts_with("Timed out after"));
+
+        let config = Config {
+            timeout: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        assert!(
+            process_file(&path, &config)
+                .await?
+                .is_some_and(|r| r.modified)
+        );
+        let leftovers = fs::read_dir(temp_dir.path())?
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .is_ok_and(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_dimensions() {
+        let height = Resize {
+            width: None,
+            height: Some(30),
+        };
+        assert_eq!(height.dimensions((200, 60)), (100, 30));
+        assert_eq!(height.dimensions((1920, 800)), (72, 30));
+        let width = Resize {
+            width: Some(64),
+            height: None,
+        };
+        assert_eq!(width.dimensions((100, 1)), (64, 1));
+        let both = Resize {
+            width: Some(64),
+            height: Some(64),
+        };
+        assert_eq!(both.dimensions((200, 60)), (64, 64));
+    }
+
+    #[tokio::test]
+    async fn test_resize_after_crop() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("wide.png");
+        let img = image::RgbaImage::from_fn(200, 100, |_, y| {
+            if (20..80).contains(&y) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        img.save(&path)?;
+
+        let output = temp_dir.path().join("output");
+        let config = Config {
+            output: Some(output.clone()),
+            resize: Some(Resize {
+                width: None,
+                height: Some(30),
+            }),
+            resize_filter: ResizeFilter::Triangle,
+            verify: true,
+            ..Config::default()
+        };
+        let result = crop_file(&path, &config).await?;
+        assert_eq!(result.cropped, (200, 60));
+        assert_eq!(
+            image::open(output.join("wide.png"))?.dimensions(),
+            (100, 30)
+        );
+
+        let (encoded, _) = crop_image_bytes(&std::fs::read(&path)?, &path, &config)?;
+        assert_eq!(image::load_from_memory(&encoded)?.dimensions(), (100, 30));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_grayscale_stays_grayscale() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("gray.png");
+        let img = image::GrayImage::from_fn(100, 100, |_, y| {
+            if (25..75).contains(&y) {
+                image::Luma([180])
+            } else {
+                image::Luma([5])
+            }
+        });
+        img.save(&path)?;
+
+        let result = process_file(&path, &Config::default()).await?.unwrap();
+        assert_eq!(result.cropped, (100, 50));
+        let cropped = image::open(&path)?;
+        assert!(matches!(cropped, DynamicImage::ImageLuma8(_)));
+        assert_eq!(cropped.height(), 50);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_palette_png_keeps_palette() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("indexed.png");
+        // Two-bit indices into black, white, and a transparent red
+        let mut encoded = Vec::new();
+        let mut encoder = png::Encoder::new(&mut encoded, 8, 8);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Two);
+        encoder.set_palette(vec![0, 0, 0, 255, 255, 255, 255, 0, 0]);
+        encoder.set_trns(vec![255, 255, 128]);
+        let mut writer = encoder.write_header()?;
+        let row = |index: u8| [index * 0b0101_0101; 2];
+        let data: Vec<u8> = (0..8)
+            .flat_map(|y| match y {
+                0 | 1 | 6 | 7 => row(0),
+                2 => row(2),
+                _ => row(1),
+            })
+            .collect();
+        writer.write_image_data(&data)?;
+        writer.finish()?;
+        fs::write(&path, encoded)?;
+
+        let result = process_file(&path, &Config::default()).await?.unwrap();
+        assert_eq!(result.cropped, (8, 4));
+
+        let reader = png::Decoder::new(std::io::Cursor::new(fs::read(&path)?)).read_info()?;
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        assert_eq!(info.bit_depth, png::BitDepth::Two);
+        assert_eq!(info.height, 4);
+        let cropped = image::open(&path)?.to_rgba8();
+        assert_eq!(cropped.get_pixel(0, 0), &Rgba([255, 0, 0, 128]));
+        assert_eq!(cropped.get_pixel(7, 3), &Rgba([255, 255, 255, 255]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quality() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let low = temp_dir.path().join("low.jpg");
+        let high = temp_dir.path().join("high.jpg");
+        // Noisy content so that the quality setting makes a visible difference
+        let byte = |value: u32| value.to_le_bytes()[0];
+        let img = image::RgbImage::from_fn(100, 100, |x, y| {
+            if y < 25 {
+                image::Rgb([0, 0, 0])
+            } else {
+                image::Rgb([byte(x * 7), byte(y * 13), byte(x * y)])
+            }
+        });
+        img.save(&low)?;
+        img.save(&high)?;
+
+        let config = |quality| Config {
+            quality,
+            ..Config::default()
+        };
+        process_file(&low, &config(10)).await?;
+        process_file(&high, &config(100)).await?;
+
+        assert!(fs::metadata(&low)?.len() < fs::metadata(&high)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_converted_path() {
+        assert_eq!(
+            converted_path(Path::new("a.HEIC"), None),
+            Path::new("a.png")
+        );
+        assert_eq!(converted_path(Path::new("a.jxl"), None), Path::new("a.png"));
+        assert_eq!(converted_path(Path::new("a.psd"), None), Path::new("a.png"));
+        assert_eq!(
+            converted_path(Path::new("a.png"), Some(OutputFormat::Webp)),
+            Path::new("a.webp")
+        );
+        assert_eq!(
+            converted_path(Path::new("a.jpeg"), Some(OutputFormat::Jpeg)),
+            Path::new("a.jpeg")
+        );
+        assert_eq!(
+            converted_path(Path::new("a.gif"), Some(OutputFormat::Png)),
+            Path::new("a.gif")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_walk() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_image(&temp_dir.path().join("test.png"), 100, 100, true)?;
+
+        let mut walk = DirectoryWalk::new(&Config::default(), None);
+        walk.interrupted.store(true, Ordering::Relaxed);
+        let result = walk.walk(temp_dir.path()).await;
+        walk.finish(result).await?;
+
+        assert!(walk.report.interrupted);
+        assert!(walk.report.entries.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_directory_parallel_io() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let sub_dir = temp_dir.path().join("sub");
+        fs::create_dir(&sub_dir)?;
+        for i in 0..5 {
+            create_test_image(&temp_dir.path().join(format!("{i}.png")), 100, 100, true)?;
+            create_test_image(&sub_dir.join(format!("{i}.png")), 100, 100, true)?;
+        }
+
+        let config = Config {
+            recursive: true,
+            parallel_io: true,
+            read_ahead: 2,
+            jobs: 2,
+            ..Config::default()
+        };
+        let report = process_directory(temp_dir.path(), &config).await?;
+
+        assert_eq!(report.entries.len(), 10);
+        assert!(
+            report
+                .entries
+                .iter()
+                .all(|entry| entry.status == Status::Cropped)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_file_atomic() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        fs::write(&path, b"original")?;
+
+        write_file_atomic(&path, b"replaced").await?;
+
+        assert_eq!(fs::read(&path)?, b"replaced");
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_threshold() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let gray_bars = temp_dir.path().join("gray.png");
+        let no_border = temp_dir.path().join("none.png");
+        let image = |bar: [u8; 3]| {
+            image::RgbImage::from_fn(100, 100, move |_, y| {
+                if (25..75).contains(&y) {
+                    image::Rgb([200, 180, 160])
+                } else {
+                    image::Rgb(bar)
+                }
+            })
+        };
+        image([40, 42, 38]).save(&gray_bars)?;
+        image([200, 180, 160]).save(&no_border)?;
+
+        let config = Config {
+            auto_threshold: Some(AutoThreshold::Edges),
+            ..Config::default()
+        };
+        assert_eq!(crop_file(&gray_bars, &config).await?.cropped, (100, 50));
+        assert!(!crop_file(&no_border, &config).await?.modified);
+        let bars = DynamicImage::ImageRgb8(image([40, 42, 38])).to_rgba8();
+        assert_eq!(
+            estimate_threshold(&bars, Axis::Both, false),
+            Ok([48, 50, 46])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_corner_threshold() {
+        let image = |corner: [u8; 4]| {
+            image::RgbaImage::from_fn(100, 100, move |x, y| {
+                if (x, y) == (99, 99) {
+                    Rgba(corner)
+                } else if (25..75).contains(&y) {
+                    Rgba([200, 180, 160, 255])
+                } else {
+                    Rgba([40, 42, 38, 255])
+                }
+            })
+        };
+        let bars = image([44, 30, 38, 255]);
+        assert_eq!(corner_threshold(&bars, false), Ok([52, 50, 46]));
+
+        // A bright corner, such as a logo, makes the corners unreliable
+        let logo = image([250, 250, 250, 255]);
+        assert!(corner_threshold(&logo, false).is_err());
+        let params = DetectionParams::new(&Config {
+            auto_threshold: Some(AutoThreshold::Corners),
+            threshold: [60, 60, 60],
+            ..Config::default()
+        });
+        assert_eq!(
+            params.border(&logo, Path::new("logo.png")),
+            Ok(([60; 3], [0; 3]))
+        );
+    }
+
+    #[test]
+    fn test_border_color() {
+        let navy = Rgba([10, 20, 60, 255]);
+        let img = image::RgbaImage::from_fn(100, 100, |x, y| {
+            if (20..80).contains(&x) && (25..75).contains(&y) {
+                Rgba([200, 200, 200, 255])
+            } else {
+                navy
+            }
+        });
+        let content = Some(CropRect {
+            x: 20,
+            y: 25,
+            width: 60,
+            height: 50,
+        });
+        let detect = |border_color| {
+            let config = Config {
+                border_color,
+                ..Config::default()
+            };
+            DetectionParams::new(&config).detect(&img, Path::new("navy.png"))
+        };
+
+        assert_eq!(detect(None), None);
+        assert_eq!(detect(Some(BorderColor::Rgb([10, 20, 60]))), content);
+        assert_eq!(detect(Some(BorderColor::Auto)), content);
+        assert_eq!(
+            dominant_border_color(&img, Axis::Both, [10; 3]),
+            Ok([10, 20, 60])
+        );
+    }
+
+    #[test]
+    fn test_exact() {
+        // Navy bars whose innermost rows and columns are off by one in a single channel
+        let navy = Rgba([10, 20, 60, 255]);
+        let img = image::RgbaImage::from_fn(100, 100, |x, y| {
+            if (20..80).contains(&x) && (25..75).contains(&y) {
+                Rgba([200, 200, 200, 255])
+            } else if x == 19 || x == 80 || y == 24 || y == 75 {
+                Rgba([10, 20, 61, 255])
+            } else {
+                navy
+            }
+        });
+        let detect = |exact| {
+            let config = Config {
+                border_color: Some(BorderColor::Rgb([10, 20, 60])),
+                exact,
+                ..Config::default()
+            };
+            DetectionParams::new(&config).detect(&img, Path::new("navy.png"))
+        };
+
+        assert_eq!(
+            detect(false),
+            Some(CropRect {
+                x: 20,
+                y: 25,
+                width: 60,
+                height: 50,
+            })
+        );
+        assert_eq!(
+            detect(true),
+            Some(CropRect {
+                x: 19,
+                y: 24,
+                width: 62,
+                height: 52,
+            })
+        );
+    }
+
+    #[test]
+    fn test_autocrop_bounds() {
+        // A red frame around a white scan margin, with a small dark mark as the only content
+        let img = image::RgbaImage::from_fn(100, 100, |x, y| {
+            if x < 5 || y < 5 || x >= 95 || y >= 95 {
+                Rgba([200, 0, 0, 255])
+            } else if (40..50).contains(&x) && (60..65).contains(&y) {
+                Rgba([20, 20, 20, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        // Only the frame matches the edge color, so the white margin is kept
+        assert_eq!(
+            autocrop_bounds(&img, [10; 3]),
+            Ok(Some(CropRect {
+                x: 5,
+                y: 5,
+                width: 90,
+                height: 90
+            }))
+        );
+
+        let uniform = image::RgbaImage::from_pixel(50, 50, Rgba([255, 255, 255, 255]));
+        assert!(autocrop_bounds(&uniform, [10; 3]).is_err());
+
+        let margin = image::imageops::crop_imm(&img, 5, 5, 90, 90).to_image();
+        assert_eq!(
+            autocrop_bounds(&margin, [10; 3]),
+            Ok(Some(CropRect {
+                x: 35,
+                y: 55,
+                width: 10,
+                height: 5
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sides() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        let only_top = Sides {
+            top: true,
+            bottom: false,
+            left: false,
+            right: false,
+        };
+        let config = Config {
+            sides: only_top,
+            dry_run: true,
+            ..Config::default()
+        };
+        let result = crop_file(&path, &config).await?;
+        assert_eq!((result.top, result.bottom), (25, 0));
+        assert_eq!(result.cropped, (100, 75));
+
+        // The image only has top and bottom bars, so there is nothing to crop on the sides
+        let config = Config {
+            sides: Sides {
+                top: false,
+                left: true,
+                right: true,
+                ..only_top
+            },
+            ..config
+        };
+        assert!(!crop_file(&path, &config).await?.modified);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_min_border() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("framed.png");
+        // A one-pixel dark frame around content with a 20-pixel bar at the top
+        let img = image::RgbaImage::from_fn(100, 100, |x, y| {
+            if y < 20 || x == 0 || y == 0 || x == 99 || y == 99 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        img.save(&path)?;
+
+        let config = Config {
+            min_border: 2,
+            dry_run: true,
+            ..Config::default()
+        };
+        let result = crop_file(&path, &config).await?;
+        assert_eq!(
+            (result.top, result.bottom, result.left, result.right),
+            (20, 0, 0, 0)
+        );
+
+        // When every border is thinner than the minimum, the image is left unchanged
+        let config = Config {
+            min_border: 21,
+            ..config
+        };
+        assert!(!crop_file(&path, &config).await?.modified);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_min_confidence() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let save = |name: &str, content: u8| -> Result<PathBuf> {
+            let path = temp_dir.path().join(name);
+            let img = image::RgbaImage::from_fn(100, 100, |_, y| {
+                if (20..80).contains(&y) {
+                    Rgba([content, content, content, 255])
+                } else {
+                    Rgba([0, 0, 0, 255])
+                }
+            });
+            img.save(&path)?;
+            Ok(path)
+        };
+        let clean = save("clean.png", 255)?;
+        let dim = save("dim.png", 24)?;
+
+        let config = Config {
+            dry_run: true,
+            ..Config::default()
+        };
+        let result = crop_file(&dim, &config).await?;
+        assert!(result.modified);
+        assert!(result.confidence < 0.5);
+
+        let config = Config {
+            min_confidence: 0.5,
+            ..config
+        };
+        let result = crop_file(&clean, &config).await?;
+        assert!(result.modified);
+        assert!(result.confidence > 0.99);
+        let result = crop_file(&dim, &config).await?;
+        assert!(!result.modified);
+        assert!(result.confidence < 0.5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_16_bit_image() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("deep.png");
+        // The bars are at 10.1 in the 8-bit range, just above the threshold of 10 that an
+        // 8-bit conversion would round them down to
+        let img = image::ImageBuffer::from_fn(100, 100, |_, y| {
+            if (25..75).contains(&y) {
+                image::Rgb([40_000u16, 30_000, 20_000])
+            } else {
+                image::Rgb([2_600, 2_600, 2_600])
+            }
+        });
+        DynamicImage::ImageRgb16(img).save(&path)?;
+
+        assert!(!crop_file(&path, &Config::default()).await?.modified);
+        let config = Config {
+            threshold: [11; 3],
+            ..Config::default()
+        };
+        assert_eq!(crop_file(&path, &config).await?.cropped, (100, 50));
+
+        let cropped = image::open(&path)?;
+        assert_eq!(cropped.color(), image::ColorType::Rgb16);
+        assert_eq!(
+            cropped.as_rgb16().map(|img| img.get_pixel(0, 0).0),
+            Some([40_000, 30_000, 20_000])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_depth() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut dir = temp_dir.path().to_owned();
+        for level in 0..4 {
+            create_test_image(&dir.join(format!("level{level}.png")), 100, 100, true)?;
+            dir.push("sub");
+            std::fs::create_dir(&dir)?;
+        }
+
+        let config = Config {
+            recursive: true,
+            max_depth: Some(2),
+            ..Config::default()
+        };
+        assert_eq!(count_images(temp_dir.path(), &config).await?, 3);
+        let run = process_directory(temp_dir.path(), &config).await?;
+        assert_eq!(run.entries.len(), 3);
+        assert_eq!(
+            image::open(temp_dir.path().join("sub/sub/sub/level3.png"))?.height(),
+            100
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exclude() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::create_dir(temp_dir.path().join("raw"))?;
+        for name in ["image.png", "image-thumb.png", "raw/image.png"] {
+            create_test_image(&temp_dir.path().join(name), 100, 100, true)?;
+        }
+
+        let mut exclude = globset::GlobSetBuilder::new();
+        exclude.add(globset::Glob::new("*-thumb.png")?);
+        exclude.add(globset::Glob::new("raw/*")?);
+        let config = Config {
+            recursive: true,
+            exclude: exclude.build()?,
+            ..Config::default()
+        };
+        assert_eq!(count_images(temp_dir.path(), &config).await?, 1);
+        process_directory(temp_dir.path(), &config).await?;
+
+        let height =
+            |name: &str| -> Result<u32> { Ok(image::open(temp_dir.path().join(name))?.height()) };
+        assert_eq!(height("image.png")?, 51);
+        assert_eq!(height("image-thumb.png")?, 100);
+        assert_eq!(height("raw/image.png")?, 100);
+
+        let thumb = temp_dir.path().join("image-thumb.png");
+        assert!(process_file(&thumb, &config).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exclude_dir() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        for dir in ["node_modules/pkg", ".git", "photos"] {
+            std::fs::create_dir_all(temp_dir.path().join(dir))?;
+        }
+        for name in ["node_modules/pkg/a.png", ".git/b.png", "photos/c.png"] {
+            create_test_image(&temp_dir.path().join(name), 100, 100, true)?;
+        }
+
+        let mut exclude_dir = globset::GlobSetBuilder::new();
+        exclude_dir.add(globset::Glob::new("node_modules")?);
+        exclude_dir.add(globset::Glob::new(".*")?);
+        let config = Config {
+            recursive: true,
+            exclude_dir: exclude_dir.build()?,
+            ..Config::default()
+        };
+        assert_eq!(count_images(temp_dir.path(), &config).await?, 1);
+        let run = process_directory(temp_dir.path(), &config).await?;
+        assert_eq!(run.entries.len(), 1);
+        assert!(run.entries[0].path.ends_with("photos/c.png"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_letterboxignore() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::create_dir(temp_dir.path().join("assets"))?;
+        for name in ["image.png", "assets/logo.png"] {
+            create_test_image(&temp_dir.path().join(name), 100, 100, true)?;
+        }
+        std::fs::write(temp_dir.path().join(".letterboxignore"), "assets/\n")?;
+
+        let config = Config {
+            recursive: true,
+            ..Config::default()
+        };
+        let run = process_directory(temp_dir.path(), &config).await?;
+        assert_eq!(run.entries.len(), 1);
+        assert_eq!(
+            image::open(temp_dir.path().join("assets/logo.png"))?.height(),
+            100
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_bmp() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("asset.bmp");
+        create_test_image(&path, 100, 100, true)?;
+
+        let result = process_file(&path, &Config::default()).await?.unwrap();
+        assert!(result.modified);
+        assert_eq!((result.top, result.bottom), (25, 24));
+        let img = image::open(&path)?;
+        assert_eq!(img.dimensions(), result.cropped);
+        assert_eq!(img.to_rgba8().get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flatten_names_are_per_run() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let output = temp_dir.path().join("flat");
+        for name in ["first", "second"] {
+            let input = temp_dir.path().join(name);
+            fs::create_dir_all(&input)?;
+            create_test_image(&input.join("frame.png"), 100, 100, true)?;
+        }
+        let config = Config {
+            output: Some(output.clone()),
+            flatten: true,
+            ..Config::default()
+        };
+
+        // A name given in one run is free again in the next once its file is gone
+        process_directory(&temp_dir.path().join("first"), &config).await?;
+        fs::remove_file(output.join("frame.png"))?;
+        process_directory(&temp_dir.path().join("second"), &config).await?;
+        assert!(output.join("frame.png").exists());
+        assert!(!output.join("frame-1.png").exists());
+
+        // Runs sharing their names keep them apart
+        fs::remove_file(output.join("frame.png"))?;
+        let config = Config {
+            flat_targets: Some(Arc::default()),
+            ..config
+        };
+        process_directory(&temp_dir.path().join("first"), &config).await?;
+        fs::remove_file(output.join("frame.png"))?;
+        process_directory(&temp_dir.path().join("second"), &config).await?;
+        assert!(output.join("frame-1.png").exists());
+        Ok(())
+    }
+}
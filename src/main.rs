@@ -1,221 +1,1634 @@
 #![warn(clippy::all, clippy::pedantic)]
 
+use anstyle::{AnsiColor, Color, Style};
 use anyhow::{Context, Result};
-use clap::Parser;
-use log::{info, warn};
-use std::future::Future;
+use clap::{ColorChoice, Parser, Subcommand};
+use env_logger::WriteStyle;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{Level, LevelFilter, error, warn};
+use remove_letterbox::{
+    AutoThreshold, Axis, BlankPolicy, BorderColor, CROPPED_LOG_TARGET, Config, CropLog, CropResult,
+    DedupeAction, OutputFormat, OutputPattern, OverwritePolicy, Profile, ProgressEvents,
+    ReportEntry, ReportFormat, Resize, ResizeFilter, ResultCache, RunReport, SKIPPED_LOG_TARGET,
+    SideThresholds, Sides, Status, Summary, crop_image_bytes, find_candidates, process_directory,
+    process_file, process_files, sweep_thresholds, undo_directory, undo_file, watch_directory,
+    write_report,
+};
+use std::io::{IsTerminal, Read, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /// Command line tool to remove letterboxing from images
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Input directory or file path
+    /// Input directory or file path. `process` and `check` also take several paths as
+    /// positional arguments.
+    #[arg(short, long, global = true)]
+    input: Option<PathBuf>,
+
+    /// Increase log output: `-v` for info, `-vv` for debug, `-vvv` for trace.
+    /// `RUST_LOG` takes precedence when set.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log warnings and errors, and do not show a progress bar when processing a directory
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Color logs and the summary: cropped files green, skipped files yellow, and errors red.
+    /// `auto` colors output going to a terminal unless `NO_COLOR` is set.
+    #[arg(long, value_enum, value_name = "WHEN", global = true, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Options of `process`, which runs when no subcommand is given
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+/// What to do with the input
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Remove the letterbox from images. This is the default when no subcommand is given.
+    Process(ProcessArgs),
+
+    /// Check that no image has a letterbox, without modifying any files. Images that would be
+    /// cropped are listed and the tool exits with code 1.
+    Check(CheckArgs),
+
+    /// Undo earlier crops of the input file, or of every image in the input directory, by
+    /// restoring the backups recorded in their `.letterbox.json` sidecars
+    Undo(UndoArgs),
+
+    /// Report the crop predicted for a single input image at each of several thresholds,
+    /// without modifying it
+    Sweep(SweepArgs),
+}
+
+/// Options of the `process` subcommand
+#[derive(clap::Args, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct ProcessArgs {
+    /// Input files and directories, processed after `--input`
+    #[arg(value_name = "PATH")]
+    paths: Vec<PathBuf>,
+
+    #[command(flatten)]
+    detection: DetectionArgs,
+
+    #[command(flatten)]
+    run: RunArgs,
+
+    /// Read a single image from stdin instead of `--input`, writing the cropped image to stdout
+    /// as with `--stdout`
+    #[arg(long, conflicts_with_all = ["from_file", "output", "dry_run", "watch", "backup", "report"])]
+    stdin: bool,
+
+    /// Write the cropped image to stdout instead of modifying the input file, in the format
+    /// given by `--format` or the format it was read in
+    #[arg(long, conflicts_with_all = ["from_file", "output", "dry_run", "watch", "backup", "report"])]
+    stdout: bool,
+
+    /// Output directory for cropped copies. When omitted, images are modified in place.
+    /// Subdirectory structure is mirrored under this directory when processing recursively.
     #[arg(short, long)]
-    input: PathBuf,
+    output: Option<PathBuf>,
 
-    /// Process files recursively if input is a directory
+    /// Write every cropped copy directly into the output directory instead of mirroring
+    /// subdirectories. Images of the same name are renamed apart with a numeric suffix.
+    #[arg(long, requires = "output")]
+    flatten: bool,
+
+    /// What to do when a file already exists in the output directory: `skip` it, `overwrite`
+    /// it, or `rename` the new file with a numeric suffix
+    #[arg(long, value_enum, default_value_t = OverwritePolicy::Skip)]
+    overwrite_policy: OverwritePolicy,
+
+    /// Name cropped copies in the output directory after a template instead of the original
+    /// name, e.g. `{stem}_{w}x{h}.{ext}`. Tokens: `{stem}`, `{ext}`, `{w}`, `{h}`, and the
+    /// pixels cropped from each side as `{top}`, `{bottom}`, `{left}`, and `{right}`.
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_output_pattern, requires = "output")]
+    output_pattern: Option<OutputPattern>,
+
+    /// What to do with images made entirely of letterbox pixels, such as all-black frames:
+    /// `skip` them, `keep` them unchanged, or `delete` them when processing in place
+    #[arg(long, value_enum, default_value_t = BlankPolicy::Skip)]
+    blank_policy: BlankPolicy,
+
+    /// Scale cropped images to this size, as WxH, or as Wx or xH to compute the other dimension
+    /// from the aspect ratio of the crop. Animated GIFs and WebPs and TIFFs are not scaled.
+    #[arg(long, value_name = "WxH", value_parser = parse_resize)]
+    resize: Option<Resize>,
+
+    /// Scale cropped images to this height, keeping their aspect ratio. Same as `--resize xH`.
+    #[arg(long, value_name = "H", value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "resize")]
+    resize_height: Option<u32>,
+
+    /// Filter used to scale images with `--resize` or `--resize-height`
+    #[arg(long, value_enum, default_value_t = ResizeFilter::Lanczos3)]
+    filter: ResizeFilter,
+
+    /// Convert cropped images to this format, changing their extension to match. Animated GIFs
+    /// and TIFFs keep their format. WebP is always written losslessly.
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Write each cropped image in the format it was read in, found from its contents rather
+    /// than its extension. This is the default, and whichever of this and `--format` is given
+    /// last takes effect.
+    #[arg(long, overrides_with = "format")]
+    output_format_match: bool,
+
+    /// JPEG quality (0-100) for cropped images. Ignored for lossless formats such as PNG and WebP.
+    #[arg(long, default_value = "90", value_parser = clap::value_parser!(u8).range(0..=100))]
+    quality: u8,
+
+    /// Color as RRGGBB that transparent areas are composited onto when writing JPEG. Ignored
+    /// for lossless formats, which keep transparency.
+    #[arg(long, default_value = "ffffff", value_name = "RRGGBB", value_parser = parse_hex_color)]
+    background: [u8; 3],
+
+    /// Keep the original file when converting an image in place with `--format`. PSD files are
+    /// always kept.
+    #[arg(long, requires = "format")]
+    keep_original: bool,
+
+    /// Move images cropped in place into this directory once written
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    cropped_dir: Option<PathBuf>,
+
+    /// Move images processed in place without a letterbox into this directory
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    move_unchanged: Option<PathBuf>,
+
+    /// After processing the input directory, keep watching it and process new or changed images
+    /// until Ctrl-C is pressed
+    #[arg(long, conflicts_with_all = ["dry_run", "from_file"])]
+    watch: bool,
+
+    /// Report what would be cropped without modifying any files
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// List the images that likely have a letterbox, judged by a thumbnail of each, without
+    /// modifying them. A triage for large libraries before a full run, which saves detection
+    /// time but still decodes every image in full.
+    #[arg(long, conflicts_with_all = ["from_file", "stdin", "stdout", "watch"])]
+    list_candidates: bool,
+
+    /// Print a table of the borders found in each image and whether it would be cropped,
+    /// without modifying any files. Combine with `--report` to save the audit as JSON or CSV.
+    #[arg(long, conflicts_with_all = ["stdin", "stdout", "watch", "list_candidates", "output", "backup", "preview_dir", "cropped_dir", "move_unchanged"])]
+    detect_only: bool,
+
+    /// In a dry run, write a PNG copy of each image to this directory with the predicted crop
+    /// outlined in red
+    #[arg(long, value_name = "PATH", requires = "dry_run")]
+    preview_dir: Option<PathBuf>,
+
+    /// Back up each image before overwriting it in place
+    #[arg(short, long)]
+    backup: bool,
+
+    /// Suffix appended to the file name of backups
+    #[arg(long, default_value = ".bak", requires = "backup")]
+    backup_suffix: String,
+
+    /// Overwrite existing backups, and process images marked by `--mark`
+    #[arg(long)]
+    force: bool,
+
+    /// Mark written images as processed, so that later runs skip them unless `--force` is given
+    #[arg(long)]
+    mark: bool,
+
+    /// Drop EXIF metadata instead of copying it into the cropped image
+    #[arg(long)]
+    strip_metadata: bool,
+
+    /// Drop the ICC color profile instead of embedding it in the cropped image
+    #[arg(long)]
+    strip_icc: bool,
+
+    /// Keep the original file's modification time on the cropped image
+    #[arg(long)]
+    preserve_mtime: bool,
+
+    /// Decode each written image again and check its dimensions against the crop, failing the
+    /// file if it is corrupt or wrong-sized (only a warning with `--continue-on-error`)
+    #[arg(long)]
+    verify: bool,
+
+    /// Append a tab-separated line for every cropped image to this file, kept across runs
+    #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+    log_tsv: Option<PathBuf>,
+
+    /// Write a JSON object per line as each image starts and finishes processing, with an
+    /// `event` of `start`, `done`, `skipped`, or `error`, for tools that show progress. Events
+    /// go to stderr, or to the file given, such as `/dev/fd/3`.
+    #[arg(long, value_name = "PATH", num_args = 0..=1, require_equals = true, default_missing_value = "-")]
+    progress_json: Option<PathBuf>,
+
+    /// After processing a directory, remove images identical to an earlier one: `move` them to
+    /// a `duplicates` directory, which is the default, or `delete` them
+    #[arg(long, value_enum, value_name = "ACTION", num_args = 0..=1, default_missing_value = "move", conflicts_with = "dry_run")]
+    dedupe_after: Option<DedupeAction>,
+}
+
+/// Options of the `check` subcommand
+#[derive(clap::Args, Debug)]
+struct CheckArgs {
+    /// Input files and directories, checked after `--input`
+    #[arg(value_name = "PATH")]
+    paths: Vec<PathBuf>,
+
+    #[command(flatten)]
+    detection: DetectionArgs,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+/// Options of the `undo` subcommand
+#[derive(clap::Args, Debug)]
+struct UndoArgs {
+    /// Restore the images of subdirectories too
     #[arg(short, long)]
     recursive: bool,
+}
+
+/// Options of the `sweep` subcommand
+#[derive(clap::Args, Debug)]
+struct SweepArgs {
+    /// Comma-separated thresholds to predict the crop at, e.g. `5,10,15,20`
+    #[arg(value_name = "T,...", value_delimiter = ',', required = true)]
+    thresholds: Vec<u8>,
+}
 
+/// Options that decide which pixels belong to the letterbox and how much of it is cropped
+#[derive(clap::Args, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct DetectionArgs {
     /// Threshold for letterbox detection (0-255). Higher values will be more aggressive in detecting letterboxes.
     /// Default is 10, which means pixels with RGB values all below 10 are considered part of the letterbox.
     #[arg(short, long, default_value = "10")]
     threshold: u8,
+
+    /// Separate red, green, and blue thresholds, e.g. `10,12,8`. A pixel is part of the letterbox
+    /// only when every channel is at or below its threshold. Overrides `--threshold`.
+    #[arg(long, value_name = "R,G,B", value_parser = parse_rgb_threshold, conflicts_with = "threshold")]
+    threshold_rgb: Option<[u8; 3]>,
+
+    /// Threshold for the top border, as a single value or R,G,B. Overrides the global threshold
+    /// for that side only.
+    #[arg(long, value_name = "T|R,G,B", value_parser = parse_side_threshold, conflicts_with = "auto_threshold")]
+    threshold_top: Option<[u8; 3]>,
+
+    /// Threshold for the bottom border, as a single value or R,G,B
+    #[arg(long, value_name = "T|R,G,B", value_parser = parse_side_threshold, conflicts_with = "auto_threshold")]
+    threshold_bottom: Option<[u8; 3]>,
+
+    /// Threshold for the left border, as a single value or R,G,B
+    #[arg(long, value_name = "T|R,G,B", value_parser = parse_side_threshold, conflicts_with = "auto_threshold")]
+    threshold_left: Option<[u8; 3]>,
+
+    /// Threshold for the right border, as a single value or R,G,B
+    #[arg(long, value_name = "T|R,G,B", value_parser = parse_side_threshold, conflicts_with = "auto_threshold")]
+    threshold_right: Option<[u8; 3]>,
+
+    /// Estimate the threshold for each image: from the color of its `edges` (the default), leaving
+    /// images without a clear border unchanged, or from its four `corners`, falling back to
+    /// `--threshold` when they disagree
+    #[arg(long, value_enum, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "edges")]
+    auto_threshold: Option<AutoThreshold>,
+
+    /// Which borders to remove: `vertical` (top/bottom), `horizontal` (left/right), or `both`
+    #[arg(long, value_enum, default_value_t = Axis::Both)]
+    axis: Axis,
+
+    /// Only crop the top border. Combine with the other `--*-only` flags to crop several sides.
+    #[arg(long)]
+    top_only: bool,
+
+    /// Only crop the bottom border
+    #[arg(long)]
+    bottom_only: bool,
+
+    /// Only crop the left border
+    #[arg(long)]
+    left_only: bool,
+
+    /// Only crop the right border
+    #[arg(long)]
+    right_only: bool,
+
+    /// Remove light borders instead of dark ones. A pixel is part of the border when every channel
+    /// is at or above 255 minus the threshold.
+    #[arg(long)]
+    invert: bool,
+
+    /// Color of the borders to remove, as RRGGBB or `auto` to use the dominant color of each
+    /// image's uniform edges. Pixels within the threshold of this color are part of the border.
+    #[arg(long, value_name = "auto|RRGGBB", value_parser = parse_border_color, conflicts_with_all = ["invert", "auto_threshold"])]
+    border_color: Option<BorderColor>,
+
+    /// Trim any uniform margin, such as white scan borders or colored frames, by cropping to
+    /// the content that differs from the edge color by more than the threshold on all sides
+    #[arg(long, conflicts_with_all = ["border_color", "invert", "auto_threshold", "axis", "tolerance"])]
+    autocrop: bool,
+
+    /// Maximum percentage (0-100) of pixels in a row or column that may exceed the threshold while
+    /// still counting as letterbox. Helps with compression noise or stray pixels in the bars.
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=100))]
+    tolerance: u8,
+
+    /// Only remove rows and columns whose every pixel is exactly the border color, black by
+    /// default or set with `--border-color`, for clean synthetic images
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "threshold",
+            "threshold_rgb",
+            "threshold_top",
+            "threshold_bottom",
+            "threshold_left",
+            "threshold_right",
+            "auto_threshold",
+            "tolerance",
+            "rounded_corners",
+            "sample_step",
+            "feather",
+        ]
+    )]
+    exact: bool,
+
+    /// Ignore the corners of each row and column when looking for the edge of the content, for
+    /// screenshots with rounded corners
+    #[arg(long, conflicts_with = "autocrop")]
+    rounded_corners: bool,
+
+    /// Radius of the corners ignored with `--rounded-corners`, in pixels
+    #[arg(
+        long,
+        default_value = "32",
+        value_name = "PIXELS",
+        requires = "rounded_corners"
+    )]
+    corner_radius: u32,
+
+    /// Check only every Nth pixel of a row or column when looking for letterbox, to speed up
+    /// detection on very large images. Too large a step can miss thin bright streaks in a bar.
+    #[arg(long, default_value = "1", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    sample_step: u32,
+
+    /// Also trim up to this many rows or columns on each side with a letterbox while they
+    /// keep moving away from the border color toward the content, for bars with soft,
+    /// anti-aliased edges
+    #[arg(
+        long,
+        default_value = "0",
+        value_name = "PIXELS",
+        conflicts_with = "autocrop"
+    )]
+    feather: u32,
+
+    /// Refuse crops that would remove more than this percentage of the image's width or height
+    #[arg(long, default_value = "50", value_parser = clap::value_parser!(u8).range(0..=100))]
+    max_crop_percent: u8,
+
+    /// Keep borders thinner than this many pixels instead of cropping them, so that a stray
+    /// dark edge row or a one-pixel frame does not trigger a crop
+    #[arg(long, default_value = "0", value_name = "PIXELS")]
+    min_border: u32,
+
+    /// Refuse crops whose detection confidence, from 0 for bars that fade into the picture to 1
+    /// for a clean edge, is below this value. Crops below 0.5 are logged as a warning.
+    #[arg(long, default_value = "0", value_name = "0-1", value_parser = parse_confidence)]
+    min_confidence: f32,
+
+    /// Crop the same amount from opposite sides, keeping the content centered. The smaller of
+    /// the two detected borders is removed from both sides.
+    #[arg(long)]
+    symmetric: bool,
+
+    /// Keep this many pixels of border on each cropped side
+    #[arg(long, default_value = "0", value_name = "PIXELS")]
+    pad: u32,
+
+    /// Trim cropped images so their width and height are multiples of N, e.g. 2 or 16 for video
+    /// encoders
+    #[arg(long, default_value = "1", value_name = "N")]
+    round_to: u32,
+
+    /// Detect the letterbox of animated GIFs on every frame instead of only the first, cropping
+    /// all frames to the combined content area
+    #[arg(long)]
+    per_frame_crop: bool,
+
+    /// Detect the letterbox of animated GIFs and WebPs on frame N, counting from 0, instead of
+    /// the first, e.g. to skip a title card
+    #[arg(
+        long,
+        default_value = "0",
+        value_name = "N",
+        conflicts_with = "per_frame_crop"
+    )]
+    frame_select: usize,
+
+    /// Crop every page of a multi-page TIFF to the combined content area of all pages, instead
+    /// of cropping each page separately
+    #[arg(long)]
+    shared_page_crop: bool,
+}
+
+/// Options that select the files to process and control a run over them
+#[derive(clap::Args, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct RunArgs {
+    /// Process the newline-separated paths listed in this file instead of walking a directory.
+    /// Use `-` to read the list from stdin. Blank lines and lines starting with `#` are ignored.
+    #[arg(long, value_name = "PATH")]
+    from_file: Option<PathBuf>,
+
+    /// Split the `--from-file` list on NUL bytes instead of newlines, as written by
+    /// `find -print0`. Every path is taken as it is, without trimming or comments.
+    #[arg(short = '0', long, requires = "from_file")]
+    null: bool,
+
+    /// Process files recursively if input is a directory
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Only descend this many levels of subdirectories when processing recursively, where 0
+    /// means only the input directory
+    #[arg(long, value_name = "N", requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// Descend into symlinked directories when processing recursively
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Only process files with these comma-separated extensions, e.g. `png,jxl` (case-insensitive)
+    #[arg(long, value_name = "EXT", value_delimiter = ',')]
+    ext: Option<Vec<String>>,
+
+    /// Skip files whose name or path relative to the input directory matches this glob, e.g.
+    /// `*-thumb.png`. May be given several times.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Skip directories whose name matches this name or glob, e.g. `node_modules` or `.*`,
+    /// without reading them. May be given several times.
+    #[arg(long, value_name = "NAME")]
+    exclude_dir: Vec<String>,
+
+    /// Only process files in a directory modified within this duration, e.g. `2h` or `3d`, or
+    /// since an RFC 3339 timestamp such as `2024-05-01T12:00:00Z`
+    #[arg(long, value_name = "DURATION|TIMESTAMP", value_parser = parse_since)]
+    since: Option<SystemTime>,
+
+    /// Stop after this many images of a directory have been processed, for trying out settings
+    /// on part of a large directory. Combines with `--dry-run`.
+    #[arg(long, value_name = "N")]
+    max_files: Option<usize>,
+
+    /// Skip images narrower than this many pixels
+    #[arg(long, default_value = "0", value_name = "PIXELS")]
+    min_width: u32,
+
+    /// Skip images shorter than this many pixels
+    #[arg(long, default_value = "0", value_name = "PIXELS")]
+    min_height: u32,
+
+    /// Skip images with more pixels than this, checked before decoding
+    #[arg(long, value_name = "PIXELS")]
+    max_pixels: Option<u64>,
+
+    /// Number of files to process concurrently (defaults to the number of CPUs)
+    #[arg(short, long)]
+    jobs: Option<NonZeroUsize>,
+
+    /// Decode, crop, and encode images on a pool of `--jobs` threads once all files are found,
+    /// which is faster for large local batches. Animated, multi-page, and converted images, and
+    /// options such as --backup or --verify, use the async pipeline on the pool threads. JXL
+    /// files are still processed asynchronously.
+    #[arg(long)]
+    parallel: bool,
+
+    /// Read directories on a separate task that runs ahead of processing, so that listing
+    /// directories overlaps with decoding and encoding. Helps on network file systems.
+    #[arg(long, conflicts_with = "parallel")]
+    parallel_io: bool,
+
+    /// Number of found files that may wait for one of the `--jobs` workers with `--parallel-io`
+    #[arg(long, default_value = "256", value_name = "N", requires = "parallel_io", value_parser = clap::value_parser!(usize).range(1..))]
+    read_ahead: usize,
+
+    /// Fail instead of skipping an input file that is not a supported image
+    #[arg(long)]
+    strict_input: bool,
+
+    /// With `--strict-input`, fail a run over several files when more than this many of them
+    /// are skipped
+    #[arg(long, value_name = "N", requires = "strict_input")]
+    max_skipped: Option<usize>,
+
+    /// Keep going when a file fails to process, reporting all failures at the end
+    #[arg(short = 'k', long)]
+    continue_on_error: bool,
+
+    /// Retry reading or writing a file up to N times when it fails on a transient I/O error,
+    /// such as a timeout or `EIO` on network storage. Decoding errors are never retried.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Time to wait before the first retry, e.g. `500ms` or `2s`, doubling with every further one
+    #[arg(long, value_name = "DURATION", default_value = "500ms", value_parser = humantime::parse_duration)]
+    retry_delay: Duration,
+
+    /// Give up on a file that takes longer than this to process, e.g. `30s`, counting it as
+    /// failed, so that a decoder stuck on a malformed image does not stall the run
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Write a report with one entry per processed file to this path
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Format of the `--report`: a `json` array, or `csv` with a header row
+    #[arg(long, value_enum, default_value_t = ReportFormat::Json, requires = "report")]
+    report_format: ReportFormat,
+
+    /// Remember images without a letterbox in this JSON file, and skip them on later runs
+    /// while their size and modification time are unchanged. Only used when processing in place.
+    #[arg(long, value_name = "PATH")]
+    cache: Option<PathBuf>,
+
+    /// Time decoding, detection, and encoding of each image, logging the times at debug level
+    /// (with `-v`) and printing the totals to stderr at the end
+    #[arg(long)]
+    profile: bool,
 }
 
+/// Exit code when `check` finds images that would be cropped
+const CHECK_FAILED_EXIT_CODE: i32 = 1;
+
+/// Exit code when a run is stopped by Ctrl-C, following the shell convention of 128 + SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging
-    env_logger::init();
-
     // Parse command line arguments
     let args = Args::parse();
 
-    // Check if input path exists
-    if !args.input.exists() {
-        anyhow::bail!("Input path does not exist: {}", args.input.display());
-    }
+    // Initialize logging, letting RUST_LOG override the level chosen on the command line.
+    // little_exif logs an error for every image without metadata, which is not a problem here.
+    let (write_style, color_choice) = match args.color {
+        ColorChoice::Auto => (WriteStyle::Auto, anstream::ColorChoice::Auto),
+        ColorChoice::Always => (WriteStyle::Always, anstream::ColorChoice::Always),
+        ColorChoice::Never => (WriteStyle::Never, anstream::ColorChoice::Never),
+    };
+    anstream::ColorChoice::write_global(color_choice);
+    env_logger::Builder::new()
+        .filter_level(log_level(args.verbose, args.quiet))
+        .filter_module("little_exif", LevelFilter::Off)
+        .write_style(write_style)
+        .format(|buf, record| {
+            let level_style = buf.default_level_style(record.level());
+            let style = message_style(record.level(), record.target());
+            writeln!(
+                buf,
+                "[{} {}{:<5}{} {}] {}{}{}",
+                buf.timestamp(),
+                level_style.render(),
+                record.level(),
+                level_style.render_reset(),
+                record.target(),
+                style.render(),
+                record.args(),
+                style.render_reset()
+            )
+        })
+        .parse_default_env()
+        .init();
 
-    // Process single file or directory
-    if args.input.is_file() {
-        process_file(&args.input, args.threshold).await?;
-    } else if args.input.is_dir() {
-        process_directory(&args.input, args.recursive, args.threshold).await?;
+    let input = args.input.as_deref();
+    match args.command.unwrap_or(Command::Process(args.process)) {
+        Command::Process(mut process) => {
+            let inputs = input_paths(input, std::mem::take(&mut process.paths));
+            run_process(&inputs, process, args.quiet).await
+        }
+        Command::Check(check) => {
+            let inputs = input_paths(input, check.paths);
+            let config = Config {
+                dry_run: true,
+                ..detection_config(check.detection, &check.run, args.quiet)?
+            };
+            let config = load_run_state(config, &check.run).await?;
+            run_input(&inputs, &config, &check.run, args.quiet, RunMode::Check).await
+        }
+        Command::Undo(undo_args) => {
+            undo(existing_input(input)?, undo_args.recursive, args.quiet).await
+        }
+        Command::Sweep(sweep) => {
+            let input = existing_input(input)?;
+            if !input.is_file() {
+                anyhow::bail!("sweep requires a single image file: {}", input.display());
+            }
+            print!(
+                "{}",
+                format_sweep(&sweep_thresholds(input, &sweep.thresholds).await?)
+            );
+            Ok(())
+        }
     }
+}
 
-    Ok(())
+/// The `--input` path followed by the positional paths
+fn input_paths(input: Option<&Path>, paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    input.map(Path::to_owned).into_iter().chain(paths).collect()
 }
 
-/// Create a processor function that owns the threshold value
-fn create_processor<'a>(
-    threshold: u8,
-) -> impl for<'r> FnOnce(&'r Path) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> + Send + 'a
-{
-    move |path: &Path| {
-        let path = path.to_owned();
-        Box::pin(async move { imx::remove_letterbox_with_threshold(&path, threshold).await })
+/// Remove the letterbox from the inputs, or from stdin, as the `process` subcommand
+async fn run_process(inputs: &[PathBuf], args: ProcessArgs, quiet: bool) -> Result<()> {
+    let config = Config {
+        output: output_dir(args.output, inputs)?,
+        flatten: args.flatten,
+        overwrite: args.overwrite_policy,
+        output_pattern: args.output_pattern,
+        blank_policy: args.blank_policy,
+        resize: args.resize.or(args.resize_height.map(|height| Resize {
+            width: None,
+            height: Some(height),
+        })),
+        resize_filter: args.filter,
+        format: args.format.filter(|_| !args.output_format_match),
+        quality: args.quality,
+        background: args.background,
+        keep_original: args.keep_original,
+        cropped_dir: args.cropped_dir,
+        unchanged_dir: args.move_unchanged,
+        dry_run: args.dry_run || args.detect_only,
+        preview_dir: args.preview_dir,
+        backup: args.backup.then_some(args.backup_suffix),
+        force: args.force,
+        mark: args.mark,
+        strip_metadata: args.strip_metadata,
+        strip_icc: args.strip_icc,
+        preserve_mtime: args.preserve_mtime,
+        verify: args.verify,
+        dedupe: args.dedupe_after,
+        ..detection_config(args.detection, &args.run, quiet)?
+    };
+    let mut config = load_run_state(config, &args.run).await?;
+    if let Some(path) = &args.log_tsv {
+        config.crop_log = Some(Arc::new(CropLog::open(path).await?));
     }
-}
+    // Images flattened from several inputs, or processed again by --watch, share their names
+    if config.flatten {
+        config.flat_targets = Some(Arc::default());
+    }
+    config.progress_events = match args.progress_json.as_deref() {
+        None => None,
+        Some(path) if path == Path::new("-") => Some(Arc::new(ProgressEvents::stderr())),
+        Some(path) => Some(Arc::new(ProgressEvents::open(path)?)),
+    };
 
-/// Process a single image file to remove letterboxing
-async fn process_file(path: &Path, threshold: u8) -> Result<()> {
-    // Handle JXL files
-    if imx::is_jxl_file(path) {
-        info!("Processing JXL file: {}", path.display());
-        imx::process_jxl_file(path, Some(create_processor(threshold))).await?;
-        return Ok(());
+    if args.stdin || args.stdout {
+        let input = match inputs {
+            [] => None,
+            [input] if !args.stdin => Some(input.as_path()),
+            _ if args.stdin => anyhow::bail!("--stdin cannot be combined with input paths"),
+            _ => anyhow::bail!("--stdout takes a single input file"),
+        };
+        let piped = pipe(input, &config);
+        print_profile(&config);
+        return piped;
     }
 
-    // Handle other image formats
-    if !imx::is_image_file(path) {
-        warn!("Skipping non-image file: {}", path.display());
+    if args.list_candidates {
+        for input in existing_inputs(inputs)? {
+            for path in find_candidates(input, &config).await? {
+                println!("{}", path.display());
+            }
+        }
         return Ok(());
     }
 
-    info!("Processing image file: {}", path.display());
-    imx::remove_letterbox_with_threshold(path, threshold)
-        .await
-        .with_context(|| format!("Failed to process image file: {}", path.display()))?;
+    if !args.watch {
+        let mode = if args.detect_only {
+            RunMode::DetectOnly
+        } else {
+            RunMode::Process
+        };
+        return run_input(inputs, &config, &args.run, quiet, mode).await;
+    }
+    let input = match existing_inputs(inputs)? {
+        [input] if input.is_dir() => input,
+        [input] => anyhow::bail!("--watch requires a directory input: {}", input.display()),
+        _ => anyhow::bail!("--watch takes a single input directory"),
+    };
+    // Files that failed the initial pass should not stop the watch
+    if let Err(err) = run_input(inputs, &config, &args.run, quiet, RunMode::Process).await {
+        error!("{err:#}");
+    }
+    let watched = watch_directory(input, &config).await;
+    save_cache(&config).await?;
+    watched
+}
 
-    Ok(())
+/// Settings for the detection and run options shared by `process` and `check`, leaving the
+/// others at their defaults
+fn detection_config(detection: DetectionArgs, run: &RunArgs, quiet: bool) -> Result<Config> {
+    let defaults = Config::default();
+    Ok(Config {
+        threshold: detection.threshold_rgb.unwrap_or([detection.threshold; 3]),
+        side_thresholds: SideThresholds {
+            top: detection.threshold_top,
+            bottom: detection.threshold_bottom,
+            left: detection.threshold_left,
+            right: detection.threshold_right,
+        },
+        auto_threshold: detection.auto_threshold,
+        axis: detection.axis,
+        sides: selected_sides(&detection),
+        invert: detection.invert,
+        border_color: detection.border_color,
+        autocrop: detection.autocrop,
+        tolerance: detection.tolerance,
+        exact: detection.exact,
+        rounded_corners: detection.rounded_corners,
+        corner_radius: detection.corner_radius,
+        sample_step: detection.sample_step,
+        feather: detection.feather,
+        max_crop_percent: detection.max_crop_percent,
+        min_border: detection.min_border,
+        min_confidence: detection.min_confidence,
+        symmetric: detection.symmetric,
+        pad: detection.pad,
+        round_to: detection.round_to,
+        per_frame_crop: detection.per_frame_crop,
+        frame_select: detection.frame_select,
+        shared_page_crop: detection.shared_page_crop,
+        recursive: run.recursive,
+        max_depth: run.max_depth,
+        follow_symlinks: run.follow_symlinks,
+        extensions: run.ext.clone(),
+        exclude: build_exclude(&run.exclude, "exclude")?,
+        exclude_dir: build_exclude(&run.exclude_dir, "exclude-dir")?,
+        since: run.since,
+        max_files: run.max_files,
+        min_width: run.min_width,
+        min_height: run.min_height,
+        max_pixels: run.max_pixels,
+        jobs: run.jobs.map_or(defaults.jobs, NonZeroUsize::get),
+        parallel: run.parallel,
+        parallel_io: run.parallel_io,
+        read_ahead: run.read_ahead,
+        progress: !quiet && std::io::stdout().is_terminal(),
+        continue_on_error: run.continue_on_error,
+        retries: run.retries,
+        retry_delay: run.retry_delay,
+        timeout: run.timeout,
+        strict_input: run.strict_input,
+        interruptible: true,
+        ..defaults
+    })
+}
+
+/// Attach the `--cache` and `--profile` state to the finished settings. The cache is loaded
+/// last, since its entries are only valid for the settings they were recorded with.
+async fn load_run_state(mut config: Config, run: &RunArgs) -> Result<Config> {
+    if let Some(path) = &run.cache {
+        config.cache = Some(Arc::new(ResultCache::load(path, &config).await?));
+    }
+    if run.profile {
+        config.profile = Some(Arc::new(Profile::new()));
+    }
+    Ok(config)
+}
+
+/// The `--input` path, which must exist
+fn existing_input(input: Option<&Path>) -> Result<&Path> {
+    let input = input.context("--input is required")?;
+    if !input.exists() {
+        anyhow::bail!("Input path does not exist: {}", input.display());
+    }
+    Ok(input)
 }
 
-/// Process a directory of image files
-async fn process_directory(dir: &Path, recursive: bool, threshold: u8) -> Result<()> {
-    async fn process_directory_inner(dir: PathBuf, recursive: bool, threshold: u8) -> Result<()> {
-        info!("Processing directory: {}", dir.display());
+/// The input paths, of which there must be at least one, and which must all exist
+fn existing_inputs(inputs: &[PathBuf]) -> Result<&[PathBuf]> {
+    if inputs.is_empty() {
+        anyhow::bail!("An input path is required, as --input or as an argument");
+    }
+    for input in inputs {
+        existing_input(Some(input))?;
+    }
+    Ok(inputs)
+}
+
+/// What a run over the inputs prints besides the summary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunMode {
+    /// Nothing, the images are cropped
+    Process,
+    /// The images that would be cropped, which fail the run, as the `check` subcommand
+    Check,
+    /// The borders found in every image, as `--detect-only`
+    DetectOnly,
+}
+
+/// Process the files listed by `--from-file`, or the input files and directories, then write
+/// the report and summary along with the output of `mode`.
+async fn run_input(
+    inputs: &[PathBuf],
+    config: &Config,
+    run: &RunArgs,
+    quiet: bool,
+    mode: RunMode,
+) -> Result<()> {
+    let report = run.report.as_deref().map(|path| (path, run.report_format));
+
+    if let Some(list) = &run.from_file {
+        if !inputs.is_empty() {
+            anyhow::bail!("--from-file cannot be combined with input paths");
+        }
+        let paths = read_path_list(list, run.null)?;
+        let outcome = process_files(&paths, config).await;
+        save_cache(config).await?;
+        print_profile(config);
+        return finish_run(outcome?, report, quiet, mode, run.max_skipped).await;
+    }
 
-        let mut entries = tokio::fs::read_dir(&dir)
+    let inputs = existing_inputs(inputs)?;
+    if let [input] = inputs
+        && input.is_file()
+    {
+        let result = process_file(input, config)
             .await
-            .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+            .map_err(anyhow::Error::from);
+        save_cache(config).await?;
+        print_profile(config);
+        let entry = ReportEntry::new(input, &result);
+        if mode == RunMode::DetectOnly {
+            print!("{}", format_borders(std::slice::from_ref(&entry)));
+        }
+        if let Some((path, format)) = report {
+            write_report(path, &[entry], format).await?;
+        }
+        if mode == RunMode::Check && result.as_ref().is_ok_and(|r| r.is_some_and(|r| r.modified)) {
+            println!("{}", input.display());
+            std::process::exit(CHECK_FAILED_EXIT_CODE);
+        }
+        result?;
+        return Ok(());
+    }
+
+    let outcome = process_inputs(inputs, config).await;
+    save_cache(config).await?;
+    print_profile(config);
+    finish_run(outcome?, report, quiet, mode, run.max_skipped).await
+}
 
-        while let Some(entry) = entries
-            .next_entry()
+/// Process the input files together, as with `--from-file`, then each input directory in
+/// turn, combining the results into one report. A directory that fails is recorded as a
+/// failure with `--continue-on-error`, and stops the run otherwise.
+async fn process_inputs(inputs: &[PathBuf], config: &Config) -> Result<RunReport> {
+    let (files, dirs): (Vec<PathBuf>, Vec<PathBuf>) =
+        inputs.iter().cloned().partition(|input| input.is_file());
+    let mut run = if files.is_empty() {
+        RunReport::default()
+    } else {
+        process_files(&files, config).await?
+    };
+
+    for dir in dirs {
+        if run.interrupted {
+            break;
+        }
+        let outcome = process_directory(&dir, config)
             .await
-            .with_context(|| format!("Failed to read directory entry in: {}", dir.display()))?
-        {
-            let path = entry.path();
-            if path.is_file() {
-                process_file(&path, threshold).await?;
-            } else if path.is_dir() && recursive {
-                let fut = Box::pin(process_directory_inner(path, recursive, threshold));
-                fut.await?;
-            }
+            .with_context(|| format!("Failed to process directory: {}", dir.display()));
+        match outcome {
+            Ok(dir_run) => run.merge(dir_run),
+            Err(err) if config.continue_on_error => run.failures.push((dir, err)),
+            Err(err) => return Err(err),
         }
+    }
+    Ok(run)
+}
 
-        Ok(())
+/// Crop the image read from `input`, or from stdin when it is `None`, and write it to stdout
+fn pipe(input: Option<&Path>, config: &Config) -> Result<()> {
+    let (bytes, name) = match input {
+        Some(input) => {
+            let bytes = std::fs::read(input)
+                .with_context(|| format!("Failed to read image file: {}", input.display()))?;
+            (bytes, input)
+        }
+        None => {
+            let mut bytes = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut bytes)
+                .context("Failed to read image from stdin")?;
+            (bytes, Path::new("<stdin>"))
+        }
+    };
+
+    let (encoded, _) = crop_image_bytes(&bytes, name, config)?;
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(&encoded)
+        .and_then(|()| stdout.flush())
+        .context("Failed to write image to stdout")
+}
+
+/// Format the crops predicted by a threshold sweep as a table, one row per threshold
+fn format_sweep(sweep: &[(u8, CropResult)]) -> String {
+    let mut table = format!(
+        "{:>9}  {:>11}  {:>6}  {:>6}  {:>6}  {:>6}\n",
+        "Threshold", "Size", "Top", "Bottom", "Left", "Right"
+    );
+    for (threshold, result) in sweep {
+        let size = format!("{}x{}", result.cropped.0, result.cropped.1);
+        table.push_str(&format!(
+            "{threshold:>9}  {size:>11}  {:>6}  {:>6}  {:>6}  {:>6}\n",
+            result.top, result.bottom, result.left, result.right
+        ));
+    }
+    table
+}
+
+/// Format the borders found by `--detect-only` as a table, one row per file sorted by path.
+/// Files that were skipped or failed show no dimensions.
+fn format_borders(entries: &[ReportEntry]) -> String {
+    let mut table = format!(
+        "{:>11}  {:>6}  {:>6}  {:>6}  {:>6}  {:<7}  {}\n",
+        "Size", "Top", "Bottom", "Left", "Right", "Crop", "Path"
+    );
+    let mut entries: Vec<_> = entries.iter().collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    for entry in entries {
+        let size = entry.original.map_or_else(
+            || "-".to_owned(),
+            |(width, height)| format!("{width}x{height}"),
+        );
+        let crop = match entry.status {
+            Status::Cropped => "yes",
+            Status::Unchanged => "no",
+            Status::Skipped => "skipped",
+            Status::Error => "error",
+            Status::PermissionDenied => "denied",
+        };
+        table.push_str(&format!(
+            "{size:>11}  {:>6}  {:>6}  {:>6}  {:>6}  {crop:<7}  {}\n",
+            entry.top,
+            entry.bottom,
+            entry.left,
+            entry.right,
+            entry.path.display()
+        ));
+    }
+    table
+}
+
+/// Write the `--cache` file back, if one is used
+async fn save_cache(config: &Config) -> Result<()> {
+    match &config.cache {
+        Some(cache) => cache.save().await,
+        None => Ok(()),
+    }
+}
+
+/// Print the stage times summed by `--profile`, if it is set. They go to stderr so that they
+/// never mix with images or paths written to stdout.
+fn print_profile(config: &Config) {
+    if let Some(profile) = &config.profile {
+        eprintln!("{profile}");
+    }
+}
+
+/// Restore the originals of images cropped with a backup
+async fn undo(input: &Path, recursive: bool, quiet: bool) -> Result<()> {
+    if input.is_file() {
+        if !undo_file(input).await? {
+            anyhow::bail!(
+                "No crop to undo, sidecar not found for: {}",
+                input.display()
+            );
+        }
+    } else {
+        let restored = undo_directory(input, recursive).await?;
+        if !quiet {
+            println!("Restored {restored} files");
+        }
+    }
+    Ok(())
+}
+
+/// Write the report and summary for a run over several files, failing if any of them failed.
+/// In [`RunMode::Check`], images that would be cropped are listed and also fail the run, and
+/// so do more than `max_skipped` skipped files in any mode.
+async fn finish_run(
+    run: RunReport,
+    report: Option<(&Path, ReportFormat)>,
+    quiet: bool,
+    mode: RunMode,
+    max_skipped: Option<usize>,
+) -> Result<()> {
+    if let Some((path, format)) = report {
+        write_report(path, &run.entries, format).await?;
+    }
+    if mode == RunMode::DetectOnly {
+        print!("{}", format_borders(&run.entries));
+    }
+    let summary = run.summary();
+    if !quiet {
+        let style = summary_style(&summary);
+        anstream::println!("{}{summary}{}", style.render(), style.render_reset());
+    }
+
+    let failures = run.failures;
+    for (path, err) in &failures {
+        error!("{}: {err:#}", path.display());
+    }
+    if run.interrupted {
+        warn!("Stopped early after Ctrl-C");
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("{} file(s) failed to process", failures.len());
+    }
+    if let Some(max_skipped) = max_skipped
+        && summary.skipped > max_skipped
+    {
+        anyhow::bail!(
+            "{} file(s) were skipped, more than the {max_skipped} allowed",
+            summary.skipped
+        );
+    }
+    if mode == RunMode::Check {
+        let letterboxed: Vec<_> = run
+            .entries
+            .iter()
+            .filter(|entry| entry.status == Status::Cropped)
+            .collect();
+        for entry in &letterboxed {
+            println!("{}", entry.path.display());
+        }
+        if !letterboxed.is_empty() {
+            error!("{} file(s) have a letterbox", letterboxed.len());
+            std::process::exit(CHECK_FAILED_EXIT_CODE);
+        }
+    }
+
+    Ok(())
+}
+
+/// The directory images are read from: the input itself, or the parent of an input file
+fn input_dir(input: &Path) -> &Path {
+    if input.is_file() {
+        input
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."))
+    } else {
+        input
+    }
+}
+
+/// The `--output` directory, or `None` when it is the directory of every input, which is the
+/// same as processing in place. An output directory that only some of the inputs are read
+/// from is refused, since the other inputs would be cropped in place as well.
+fn output_dir(output: Option<PathBuf>, inputs: &[PathBuf]) -> Result<Option<PathBuf>> {
+    let Some(output) = output else {
+        return Ok(None);
+    };
+    let same = inputs
+        .iter()
+        .filter(|input| is_same_path(&output, input_dir(input)))
+        .count();
+    if same == 0 {
+        Ok(Some(output))
+    } else if same == inputs.len() {
+        warn!(
+            "Output directory is the same as the input directory, processing in place: {}",
+            output.display()
+        );
+        Ok(None)
+    } else {
+        anyhow::bail!(
+            "Output directory is also an input directory, give it as the only input to process it in place: {}",
+            output.display()
+        )
+    }
+}
+
+/// Read newline-separated paths from `source`, or from stdin when it is `-`
+fn read_path_list(source: &Path, null: bool) -> Result<Vec<PathBuf>> {
+    let contents = if source == Path::new("-") {
+        let mut contents = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut contents)
+            .context("Failed to read paths from stdin")?;
+        contents
+    } else {
+        std::fs::read(source)
+            .with_context(|| format!("Failed to read path list: {}", source.display()))?
+    };
+    if null {
+        return Ok(parse_null_list(&contents));
+    }
+    let contents = String::from_utf8(contents)
+        .with_context(|| format!("Path list is not valid UTF-8: {}", source.display()))?;
+    Ok(parse_path_list(&contents))
+}
+
+/// Parse a list of NUL-terminated paths, ignoring empty entries
+fn parse_null_list(contents: &[u8]) -> Vec<PathBuf> {
+    contents
+        .split(|&byte| byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+/// Path made of raw bytes, which are any sequence of bytes on Unix
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+/// Path made of raw bytes, which must be UTF-8 outside of Unix
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+/// Parse a list of paths, one per line, ignoring blank lines and `#` comments
+fn parse_path_list(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Style of a log message by its level and target: errors red, skipped files yellow, and
+/// cropped files green
+fn message_style(level: Level, target: &str) -> Style {
+    let color = match level {
+        Level::Error => Some(AnsiColor::Red),
+        _ if target == SKIPPED_LOG_TARGET => Some(AnsiColor::Yellow),
+        Level::Info if target == CROPPED_LOG_TARGET => Some(AnsiColor::Green),
+        _ => None,
+    };
+    Style::new().fg_color(color.map(Color::from))
+}
+
+/// Style of the summary, after the most severe outcome of the run
+fn summary_style(summary: &Summary) -> Style {
+    let color = if summary.errors > 0 || summary.permission_denied > 0 {
+        AnsiColor::Red
+    } else if summary.skipped > 0 {
+        AnsiColor::Yellow
+    } else {
+        AnsiColor::Green
+    };
+    Style::new().fg_color(Some(color.into())).bold()
+}
+
+/// Log level for the number of `--verbose` flags given
+fn log_level(verbose: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Warn;
+    }
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Check whether two paths refer to the same location on disk
+fn is_same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Parse the `--since` cutoff, either a duration before now or an absolute timestamp
+fn parse_since(value: &str) -> Result<SystemTime, String> {
+    if let Ok(duration) = humantime::parse_duration(value) {
+        return SystemTime::now()
+            .checked_sub(duration)
+            .ok_or_else(|| format!("duration '{value}' reaches too far back"));
+    }
+    humantime::parse_rfc3339_weak(value).map_err(|_| {
+        format!("expected a duration like '2h' or an RFC 3339 timestamp, got '{value}'")
+    })
+}
+
+/// Parse an `--output-pattern` template, rejecting unknown tokens before any file is processed
+fn parse_output_pattern(value: &str) -> Result<OutputPattern, String> {
+    OutputPattern::parse(value).map_err(|err| err.to_string())
+}
+
+/// Compile the patterns of `--exclude` or `--exclude-dir`, named by `option`, into a single
+/// matcher
+fn build_exclude(patterns: &[String], option: &str) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).with_context(|| format!("Invalid {option} pattern: {pattern}"))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .with_context(|| format!("Failed to build {option} patterns"))
+}
+
+/// Sides chosen with the `--*-only` flags, or every side when none is given
+fn selected_sides(args: &DetectionArgs) -> Sides {
+    if !(args.top_only || args.bottom_only || args.left_only || args.right_only) {
+        return Sides::ALL;
+    }
+    Sides {
+        top: args.top_only,
+        bottom: args.bottom_only,
+        left: args.left_only,
+        right: args.right_only,
+    }
+}
+
+/// Parse `--border-color`, either `auto` or a hex color such as `1a1a2e`
+fn parse_border_color(value: &str) -> Result<BorderColor, String> {
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(BorderColor::Auto);
+    }
+
+    parse_hex_color(value).map(BorderColor::Rgb)
+}
+
+/// Parse a hex color such as `1a1a2e`, with or without a leading `#`
+fn parse_hex_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("expected a color as RRGGBB, got '{value}'"));
+    }
+    let mut color = [0; 3];
+    for (c, channel) in color.iter_mut().enumerate() {
+        let digits = &hex[2 * c..2 * c + 2];
+        *channel = u8::from_str_radix(digits, 16)
+            .map_err(|e| format!("invalid color component '{digits}': {e}"))?;
+    }
+    Ok(color)
+}
+
+/// Parse a target size as WxH, or as Wx or xH to keep the aspect ratio
+fn parse_resize(value: &str) -> Result<Resize, String> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected a size as WxH, Wx, or xH, got '{value}'"))?;
+    let parse = |length: &str| match length.trim() {
+        "" => Ok(None),
+        length => match length.parse::<u32>() {
+            Ok(0) => Err("sizes must be at least 1 pixel".to_owned()),
+            Ok(length) => Ok(Some(length)),
+            Err(e) => Err(format!("invalid size '{length}': {e}")),
+        },
+    };
+    let resize = Resize {
+        width: parse(width)?,
+        height: parse(height)?,
+    };
+    if resize.width.is_none() && resize.height.is_none() {
+        return Err(format!(
+            "expected a width, a height, or both, got '{value}'"
+        ));
+    }
+    Ok(resize)
+}
+
+/// Parse a detection confidence between 0 and 1
+fn parse_confidence(value: &str) -> Result<f32, String> {
+    let confidence = value
+        .trim()
+        .parse::<f32>()
+        .map_err(|e| format!("invalid confidence '{value}': {e}"))?;
+    if !(0.0..=1.0).contains(&confidence) {
+        return Err(format!("confidence must be between 0 and 1, got '{value}'"));
     }
+    Ok(confidence)
+}
+
+/// Parse a comma-separated list of red, green, and blue thresholds
+fn parse_rgb_threshold(value: &str) -> Result<[u8; 3], String> {
+    let channels = value
+        .split(',')
+        .map(|channel| {
+            channel
+                .trim()
+                .parse::<u8>()
+                .map_err(|e| format!("invalid channel threshold '{channel}': {e}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    channels
+        .try_into()
+        .map_err(|_| format!("expected three comma-separated values (R,G,B), got '{value}'"))
+}
 
-    process_directory_inner(dir.to_owned(), recursive, threshold).await
+/// Parse the threshold of one side, either a single value for every channel or R,G,B
+fn parse_side_threshold(value: &str) -> Result<[u8; 3], String> {
+    if value.contains(',') {
+        return parse_rgb_threshold(value);
+    }
+    value
+        .trim()
+        .parse::<u8>()
+        .map(|threshold| [threshold; 3])
+        .map_err(|e| format!("invalid threshold '{value}': {e}"))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use image::{GenericImageView, ImageBuffer, Rgba};
-    use std::fs;
-    use tempfile::TempDir;
-
-    fn create_test_image(path: &Path, width: u32, height: u32, with_letterbox: bool) -> Result<()> {
-        let mut img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(width, height);
-
-        // Fill the image with white pixels
-        for y in 0..height {
-            for x in 0..width {
-                let pixel = if with_letterbox && (y < height / 4 || y > height * 3 / 4) {
-                    Rgba([0, 0, 0, 255]) // Black letterbox
-                } else {
-                    Rgba([255, 255, 255, 255]) // White content
-                };
-                img.put_pixel(x, y, pixel);
-            }
-        }
 
-        img.save(path)?;
-        Ok(())
+    #[test]
+    fn test_parse_border_color() {
+        assert_eq!(parse_border_color("auto"), Ok(BorderColor::Auto));
+        assert_eq!(
+            parse_border_color("1a1A2e"),
+            Ok(BorderColor::Rgb([0x1a, 0x1a, 0x2e]))
+        );
+        assert_eq!(
+            parse_border_color("#000080"),
+            Ok(BorderColor::Rgb([0, 0, 0x80]))
+        );
+        assert!(parse_border_color("12345").is_err());
+        assert!(parse_border_color("gg0000").is_err());
     }
 
-    #[tokio::test]
-    async fn test_process_file_invalid_path() -> Result<()> {
-        let result = process_file(Path::new("nonexistent.jpg"), 10).await;
-        assert!(result.is_err());
-        Ok(())
+    #[test]
+    fn test_parse_rgb_threshold() {
+        assert_eq!(parse_rgb_threshold("10,12,8"), Ok([10, 12, 8]));
+        assert_eq!(parse_rgb_threshold(" 1, 2 ,3"), Ok([1, 2, 3]));
+        assert!(parse_rgb_threshold("10,12").is_err());
+        assert!(parse_rgb_threshold("10,12,256").is_err());
     }
 
-    #[tokio::test]
-    async fn test_process_file_non_image() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let non_image = temp_dir.path().join("test.txt");
-        fs::write(&non_image, "not an image")?;
+    #[test]
+    fn test_parse_resize() {
+        let resize = |width, height| Ok(Resize { width, height });
+        assert_eq!(parse_resize("640x360"), resize(Some(640), Some(360)));
+        assert_eq!(parse_resize("x512"), resize(None, Some(512)));
+        assert_eq!(parse_resize("512X"), resize(Some(512), None));
+        assert!(parse_resize("x").is_err());
+        assert!(parse_resize("0x512").is_err());
+        assert!(parse_resize("512").is_err());
+    }
 
-        let result = process_file(&non_image, 10).await;
-        assert!(result.is_ok()); // Should skip non-image files
-        Ok(())
+    #[test]
+    fn test_parse_since() {
+        let hour_ago = parse_since("1h").unwrap();
+        let elapsed = hour_ago.elapsed().unwrap();
+        assert!(elapsed >= std::time::Duration::from_secs(3600));
+        assert!(elapsed < std::time::Duration::from_secs(3660));
+        assert_eq!(
+            parse_since("2024-05-01T12:00:00Z"),
+            Ok(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_714_564_800))
+        );
+        assert!(parse_since("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_side_threshold() {
+        assert_eq!(parse_side_threshold("30"), Ok([30; 3]));
+        assert_eq!(parse_side_threshold("30,32,28"), Ok([30, 32, 28]));
+        assert!(parse_side_threshold("300").is_err());
     }
 
-    #[tokio::test]
-    async fn test_process_file_with_letterbox() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let image_path = temp_dir.path().join("test.png");
-        create_test_image(&image_path, 100, 100, true)?;
+    #[test]
+    fn test_parse_path_list() {
+        let list = "a.png\n\n# comment\n  b/c.jxl  \n";
+        assert_eq!(
+            parse_path_list(list),
+            [PathBuf::from("a.png"), PathBuf::from("b/c.jxl")]
+        );
+    }
 
-        process_file(&image_path, 10).await?;
+    #[test]
+    fn test_parse_null_list() {
+        let list = b"a b.png\0line\nbreak.png\0\0# not a comment\0";
+        assert_eq!(
+            parse_null_list(list),
+            [
+                PathBuf::from("a b.png"),
+                PathBuf::from("line\nbreak.png"),
+                PathBuf::from("# not a comment")
+            ]
+        );
+    }
 
-        // Verify the image was processed
-        let processed_img = image::open(&image_path)?;
-        let (width, height) = processed_img.dimensions();
-        assert_eq!(width, 100);
-        assert!(height < 100); // Should be cropped
-        Ok(())
+    #[test]
+    fn test_format_sweep() {
+        let result = CropResult {
+            original: (100, 100),
+            cropped: (100, 50),
+            top: 25,
+            bottom: 25,
+            left: 0,
+            right: 0,
+            modified: true,
+            blank: false,
+            confidence: 1.0,
+        };
+        let table = format_sweep(&[(10, result)]);
+        assert_eq!(
+            table.lines().collect::<Vec<_>>(),
+            [
+                "Threshold         Size     Top  Bottom    Left   Right",
+                "       10       100x50      25      25       0       0"
+            ]
+        );
     }
 
-    #[tokio::test]
-    async fn test_process_file_without_letterbox() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let image_path = temp_dir.path().join("test.png");
-        create_test_image(&image_path, 100, 100, false)?;
+    #[test]
+    fn test_format_borders() {
+        let result = CropResult {
+            original: (1920, 1080),
+            cropped: (1920, 800),
+            top: 140,
+            bottom: 140,
+            left: 0,
+            right: 0,
+            modified: true,
+            blank: false,
+            confidence: 1.0,
+        };
+        let entries = [
+            ReportEntry::new(Path::new("b.png"), &Ok(Some(result))),
+            ReportEntry::new(Path::new("a.png"), &Err(anyhow::anyhow!("corrupt"))),
+        ];
+        assert_eq!(
+            format_borders(&entries).lines().collect::<Vec<_>>(),
+            [
+                "       Size     Top  Bottom    Left   Right  Crop     Path",
+                "          -       0       0       0       0  error    a.png",
+                "  1920x1080     140     140       0       0  yes      b.png"
+            ]
+        );
+    }
 
-        process_file(&image_path, 10).await?;
+    #[test]
+    fn test_subcommands() {
+        use clap::CommandFactory;
+        Args::command().debug_assert();
 
-        // Verify the image was not modified
-        let processed_img = image::open(&image_path)?;
-        let (width, height) = processed_img.dimensions();
-        assert_eq!(width, 100);
-        assert_eq!(height, 100); // Should not be cropped
-        Ok(())
+        // Without a subcommand, the options of `process` apply
+        let args = Args::try_parse_from(["remove-letterbox", "-i", "a.png", "-t", "20"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.process.detection.threshold, 20);
+
+        let args =
+            Args::try_parse_from(["remove-letterbox", "check", "-i", "photos", "-r"]).unwrap();
+        assert!(
+            matches!(args.command, Some(Command::Check(CheckArgs { ref run, .. })) if run.recursive)
+        );
+
+        let args =
+            Args::try_parse_from(["remove-letterbox", "-i", "a.png", "sweep", "5,10"]).unwrap();
+        assert!(
+            matches!(args.command, Some(Command::Sweep(SweepArgs { ref thresholds })) if thresholds == &[5, 10])
+        );
+
+        // Several inputs are given as arguments, after the one given with `--input`
+        let args =
+            Args::try_parse_from(["remove-letterbox", "-i", "a.png", "b.png", "photos"]).unwrap();
+        assert_eq!(
+            input_paths(args.input.as_deref(), args.process.paths),
+            [
+                PathBuf::from("a.png"),
+                PathBuf::from("b.png"),
+                PathBuf::from("photos")
+            ]
+        );
+        let args = Args::try_parse_from(["remove-letterbox", "check", "a", "b", "-r"]).unwrap();
+        assert!(
+            matches!(args.command, Some(Command::Check(CheckArgs { ref paths, .. })) if paths.len() == 2)
+        );
+
+        // `--exact` ignores any threshold, so setting one is an error
+        assert!(
+            Args::try_parse_from(["remove-letterbox", "-i", "a.png", "--exact", "-t", "5"])
+                .is_err()
+        );
+
+        // A bare `--auto-threshold` does not take the following input path as its mode
+        let args =
+            Args::try_parse_from(["remove-letterbox", "--auto-threshold", "photos"]).unwrap();
+        assert_eq!(
+            args.process.detection.auto_threshold,
+            Some(AutoThreshold::Edges)
+        );
+        assert_eq!(args.process.paths, [PathBuf::from("photos")]);
+        let args = Args::try_parse_from(["remove-letterbox", "--auto-threshold=corners", "a.png"])
+            .unwrap();
+        assert_eq!(
+            args.process.detection.auto_threshold,
+            Some(AutoThreshold::Corners)
+        );
+
+        // Whichever of `--format` and `--output-format-match` comes last wins
+        let args = Args::try_parse_from([
+            "remove-letterbox",
+            "--format",
+            "jpeg",
+            "--output-format-match",
+            "a.png",
+        ])
+        .unwrap();
+        assert_eq!(args.process.format, None);
+        let args = Args::try_parse_from([
+            "remove-letterbox",
+            "--output-format-match",
+            "--format",
+            "png",
+            "a.png",
+        ])
+        .unwrap();
+        assert_eq!(args.process.format, Some(OutputFormat::Png));
+        assert!(!args.process.output_format_match);
+
+        // Options of `process` do not apply to the other subcommands
+        assert!(
+            Args::try_parse_from(["remove-letterbox", "undo", "-i", "a.png", "--backup"]).is_err()
+        );
     }
 
-    #[tokio::test]
-    async fn test_process_directory() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-
-        // Create test images in root directory
-        let img1 = temp_dir.path().join("test1.png");
-        let img2 = temp_dir.path().join("test2.png");
-        create_test_image(&img1, 100, 100, true)?;
-        create_test_image(&img2, 100, 100, false)?;
-
-        // Create subdirectory with more images
-        let sub_dir = temp_dir.path().join("subdir");
-        fs::create_dir(&sub_dir)?;
-        let img3 = sub_dir.join("test3.png");
-        create_test_image(&img3, 100, 100, true)?;
-
-        // Test non-recursive
-        process_directory(temp_dir.path(), false, 10).await?;
-        let processed_img1 = image::open(&img1)?;
-        assert!(processed_img1.dimensions().1 < 100); // Should be cropped
-        let processed_img2 = image::open(&img2)?;
-        assert_eq!(processed_img2.dimensions().1, 100); // Should not be cropped
-        let unprocessed_img3 = image::open(&img3)?;
-        assert_eq!(unprocessed_img3.dimensions().1, 100); // Should not be processed
-
-        // Test recursive
-        process_directory(temp_dir.path(), true, 10).await?;
-        let processed_img3 = image::open(&img3)?;
-        assert!(processed_img3.dimensions().1 < 100); // Should be cropped
+    #[test]
+    fn test_styles() {
+        let green = Style::new().fg_color(Some(AnsiColor::Green.into()));
+        assert_eq!(message_style(Level::Info, CROPPED_LOG_TARGET), green);
+        assert_eq!(
+            message_style(Level::Warn, SKIPPED_LOG_TARGET),
+            Style::new().fg_color(Some(AnsiColor::Yellow.into()))
+        );
+        assert_eq!(
+            message_style(Level::Error, "remove_letterbox"),
+            Style::new().fg_color(Some(AnsiColor::Red.into()))
+        );
+        // The text of a message does not matter
+        assert_eq!(message_style(Level::Info, "remove_letterbox"), Style::new());
+
+        let summary = Summary {
+            errors: 1,
+            ..Summary::default()
+        };
+        assert_eq!(
+            summary_style(&summary),
+            Style::new().fg_color(Some(AnsiColor::Red.into())).bold()
+        );
+        assert_eq!(summary_style(&Summary::default()), green.bold());
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(0, false), LevelFilter::Warn);
+        assert_eq!(log_level(1, false), LevelFilter::Info);
+        assert_eq!(log_level(2, false), LevelFilter::Debug);
+        assert_eq!(log_level(5, false), LevelFilter::Trace);
+        assert_eq!(log_level(0, true), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn test_output_dir() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let out = temp_dir.path().join("out");
+        let other = temp_dir.path().join("other");
+        std::fs::create_dir_all(&out)?;
+        std::fs::create_dir_all(&other)?;
+
+        assert_eq!(output_dir(None, &[out.clone()])?, None);
+        assert_eq!(
+            output_dir(Some(out.clone()), &[other.clone()])?,
+            Some(out.clone())
+        );
+        // Writing into the only input directory processes it in place
+        assert_eq!(output_dir(Some(out.clone()), &[out.clone()])?, None);
+        // Another input must not be cropped in place along with it
+        assert!(output_dir(Some(out.clone()), &[out.clone(), other]).is_err());
         Ok(())
     }
 }
@@ -0,0 +1,197 @@
+//! Markers on written images, so that repeated runs do not crop them again. PNG images carry
+//! a `tEXt` chunk, added as they are encoded, and other formats an extended attribute.
+
+use image::{ColorType, DynamicImage, ImageFormat};
+use log::{debug, warn};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Keyword of the PNG `tEXt` chunk marking an image
+const MARKER_KEYWORD: &str = "letterbox-removed";
+
+/// Text of the marker chunk
+const MARKER_VALUE: &str = "1";
+
+/// Extended attribute marking an image in formats without text chunks
+const MARKER_XATTR: &str = "user.letterbox-removed";
+
+/// Signature at the start of every PNG file
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Whether an image was marked, by the chunk of a PNG or the extended attribute of other
+/// formats. Files that cannot be read count as unmarked.
+pub(crate) fn is_marked(path: &Path) -> bool {
+    // Files too short to be a PNG are not marked by a chunk, but may have the attribute
+    let marked = match File::open(path).and_then(|file| has_marker_chunk(BufReader::new(file))) {
+        Ok(true) => Ok(true),
+        Ok(false) | Err(_) => xattr::get(path, MARKER_XATTR).map(|value| value.is_some()),
+    };
+    marked.unwrap_or_else(|err| {
+        debug!("Failed to read marker of {}: {err}", path.display());
+        false
+    })
+}
+
+/// Mark an image as processed with an extended attribute, unless it is a PNG that got the
+/// marker chunk when it was encoded, see [`add_marker`]. Failing to set the attribute, for
+/// example on a file system without support for them, is only logged.
+pub(crate) fn mark_file(path: &Path) {
+    if is_marked(path) {
+        return;
+    }
+    if let Err(err) = xattr::set(path, MARKER_XATTR, MARKER_VALUE.as_bytes()) {
+        warn!("Failed to mark {} as processed: {err}", path.display());
+    }
+}
+
+/// Add the marker chunk to a PNG about to be encoded
+pub(crate) fn add_marker<W: Write>(
+    encoder: &mut png::Encoder<'_, W>,
+) -> Result<(), png::EncodingError> {
+    encoder.add_text_chunk(MARKER_KEYWORD.to_owned(), MARKER_VALUE.to_owned())
+}
+
+/// Encode an image as a PNG carrying the marker chunk, embedding `icc_profile` when there is
+/// one. 16-bit images keep their depth, and images of other color types are stored as 16-bit
+/// RGBA.
+pub(crate) fn encode_marked_png(
+    img: &DynamicImage,
+    icc_profile: Option<Vec<u8>>,
+) -> image::ImageResult<Vec<u8>> {
+    use png::{BitDepth, ColorType as PngColor};
+
+    let (color, depth) = match img.color() {
+        ColorType::L8 => (PngColor::Grayscale, BitDepth::Eight),
+        ColorType::La8 => (PngColor::GrayscaleAlpha, BitDepth::Eight),
+        ColorType::Rgb8 => (PngColor::Rgb, BitDepth::Eight),
+        ColorType::Rgba8 => (PngColor::Rgba, BitDepth::Eight),
+        ColorType::L16 => (PngColor::Grayscale, BitDepth::Sixteen),
+        ColorType::La16 => (PngColor::GrayscaleAlpha, BitDepth::Sixteen),
+        ColorType::Rgb16 => (PngColor::Rgb, BitDepth::Sixteen),
+        ColorType::Rgba16 => (PngColor::Rgba, BitDepth::Sixteen),
+        _ => {
+            let img = DynamicImage::ImageRgba16(img.to_rgba16());
+            return encode_marked_png(&img, icc_profile);
+        }
+    };
+    // PNG stores 16-bit samples in big-endian order
+    let data = if depth == BitDepth::Sixteen {
+        Cow::Owned(
+            img.as_bytes()
+                .chunks_exact(2)
+                .flat_map(|sample| u16::from_ne_bytes([sample[0], sample[1]]).to_be_bytes())
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(img.as_bytes())
+    };
+
+    let mut buf = Vec::new();
+    let mut info = png::Info::with_size(img.width(), img.height());
+    info.icc_profile = icc_profile.map(Cow::Owned);
+    let written = png::Encoder::with_info(&mut buf, info).and_then(|mut encoder| {
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        add_marker(&mut encoder)?;
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+        writer.finish()
+    });
+    written.map_err(|err| {
+        image::ImageError::Encoding(image::error::EncodingError::new(
+            ImageFormat::Png.into(),
+            err,
+        ))
+    })?;
+    Ok(buf)
+}
+
+/// Whether the chunks of a PNG before its image data include the marker. Other files never
+/// do.
+fn has_marker_chunk<R: Read + Seek>(mut file: R) -> std::io::Result<bool> {
+    let mut signature = [0; PNG_SIGNATURE.len()];
+    file.read_exact(&mut signature)?;
+    if signature != PNG_SIGNATURE {
+        return Ok(false);
+    }
+
+    let marker_len = MARKER_KEYWORD.len() + 1 + MARKER_VALUE.len();
+    loop {
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+        let (length, kind) = header.split_at(4);
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]);
+        // The marker is written before the image data, so later chunks need not be read
+        if kind == b"IDAT" || kind == b"IEND" {
+            return Ok(false);
+        }
+        if kind == b"tEXt" && length as usize == marker_len {
+            let mut text = vec![0; marker_len];
+            file.read_exact(&mut text)?;
+            if text
+                .split(|&byte| byte == 0)
+                .eq([MARKER_KEYWORD, MARKER_VALUE].map(str::as_bytes))
+            {
+                return Ok(true);
+            }
+            file.seek(SeekFrom::Current(4))?;
+        } else {
+            // Skip the chunk data and its CRC
+            file.seek(SeekFrom::Current(i64::from(length) + 4))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, process_file};
+    use anyhow::Result;
+    use image::{Rgba, RgbaImage};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_marked_png_is_skipped() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        // A letterbox inside a letterbox, so that a second pass would crop again
+        let img = RgbaImage::from_fn(100, 100, |_, y| {
+            if (40..60).contains(&y) {
+                Rgba([255, 255, 255, 255])
+            } else if (20..80).contains(&y) {
+                Rgba([5, 5, 5, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        img.save(&path)?;
+
+        let config = Config {
+            threshold: [0; 3],
+            max_crop_percent: 100,
+            mark: true,
+            ..Config::default()
+        };
+        assert!(process_file(&path, &config).await?.is_some());
+        assert!(has_marker_chunk(BufReader::new(File::open(&path)?))?);
+        assert!(xattr::get(&path, MARKER_XATTR)?.is_none());
+        assert_eq!(image::open(&path)?.height(), 60);
+
+        let config = Config {
+            threshold: [10; 3],
+            ..config
+        };
+        assert!(process_file(&path, &config).await?.is_none());
+        assert_eq!(image::open(&path)?.height(), 60);
+
+        let config = Config {
+            force: true,
+            ..config
+        };
+        assert!(process_file(&path, &config).await?.is_some());
+        assert_eq!(image::open(&path)?.height(), 20);
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+//! Letterbox removal for TIFF files, cropping every page and keeping their order
+
+use crate::{
+    CropRect, CropResult, DetectionParams, LetterboxError, adjust_crop, backup_file,
+    detect_content, plan_crop, read_file, write_file_atomic,
+};
+use anyhow::Result;
+use image::{DynamicImage, GenericImageView, ImageBuffer};
+use log::debug;
+use std::io::Cursor;
+use std::path::Path;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{TiffEncoder, colortype};
+
+/// Whether the path has a `.tif` or `.tiff` extension
+pub(crate) fn is_tiff_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"))
+}
+
+/// The decoded pages of a TIFF file, in order
+struct Pages {
+    pages: Vec<DynamicImage>,
+}
+
+impl Pages {
+    /// Read and decode every page of a TIFF file. Grayscale, RGB, and RGBA pages with 8 or 16
+    /// bits per channel are supported.
+    async fn read(path: &Path) -> Result<Self> {
+        let bytes = read_file(path).await?;
+        let decode_error = |err: tiff::TiffError| LetterboxError::decode(path, err);
+        let mut decoder = Decoder::new(Cursor::new(bytes)).map_err(decode_error)?;
+
+        let mut pages = Vec::new();
+        loop {
+            let (width, height) = decoder.dimensions().map_err(decode_error)?;
+            let color_type = decoder.colortype().map_err(decode_error)?;
+            let data = decoder.read_image().map_err(decode_error)?;
+            let page = decode_page(width, height, color_type, data).ok_or_else(|| {
+                LetterboxError::decode(
+                    path,
+                    format!("Unsupported TIFF page {} ({color_type:?})", pages.len() + 1),
+                )
+            })?;
+            pages.push(page);
+
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image().map_err(decode_error)?;
+        }
+        Ok(Self { pages })
+    }
+
+    /// Decide on the crop of every page. Each page is cropped separately, or with `shared`
+    /// set, every page is cropped to the combined content area of all pages.
+    fn plan_crops(
+        &self,
+        params: &DetectionParams,
+        shared: bool,
+        path: &Path,
+    ) -> Vec<Option<CropRect>> {
+        if !shared {
+            return self
+                .pages
+                .iter()
+                .map(|page| plan_crop(page, params, path).0)
+                .collect();
+        }
+
+        // Pages of different sizes cannot share a crop
+        let dimensions = self.pages.first().map(GenericImageView::dimensions);
+        if self
+            .pages
+            .iter()
+            .any(|page| Some(page.dimensions()) != dimensions)
+        {
+            debug!(
+                "Pages of {} differ in size, cropping them separately",
+                path.display()
+            );
+            return self.plan_crops(params, false, path);
+        }
+        let Some(dimensions) = dimensions else {
+            return Vec::new();
+        };
+
+        let full = CropRect::full(dimensions);
+        let rect = self
+            .pages
+            .iter()
+            .map(|page| detect_content(page, params, path).unwrap_or(full))
+            .reduce(CropRect::union)
+            .filter(|&rect| rect != full)
+            .and_then(|rect| adjust_crop(rect, dimensions, params, path));
+        vec![rect; self.pages.len()]
+    }
+
+    /// Encode the pages, each cropped to its rectangle, as a multi-page TIFF
+    fn encode(&self, crops: &[Option<CropRect>], path: &Path) -> Result<Vec<u8>> {
+        let encode_error = |err: tiff::TiffError| LetterboxError::encode(path, err);
+        let mut buf = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buf).map_err(encode_error)?;
+        for (page, crop) in self.pages.iter().zip(crops) {
+            let page = match crop {
+                Some(rect) => page.crop_imm(rect.x, rect.y, rect.width, rect.height),
+                None => page.clone(),
+            };
+            encode_page(&mut encoder, &page).map_err(encode_error)?;
+        }
+        drop(encoder);
+        Ok(buf.into_inner())
+    }
+}
+
+/// Build an image from the raw data of a TIFF page
+fn decode_page(
+    width: u32,
+    height: u32,
+    color_type: tiff::ColorType,
+    data: DecodingResult,
+) -> Option<DynamicImage> {
+    use tiff::ColorType::{Gray, RGB, RGBA};
+    Some(match (color_type, data) {
+        (Gray(8), DecodingResult::U8(data)) => {
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (Gray(16), DecodingResult::U16(data)) => {
+            DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (RGB(8), DecodingResult::U8(data)) => {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (RGB(16), DecodingResult::U16(data)) => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (RGBA(8), DecodingResult::U8(data)) => {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, data)?)
+        }
+        (RGBA(16), DecodingResult::U16(data)) => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, data)?)
+        }
+        _ => return None,
+    })
+}
+
+/// Append an image to a TIFF as a new page, keeping its color type and bit depth
+fn encode_page<W: std::io::Write + std::io::Seek>(
+    encoder: &mut TiffEncoder<W>,
+    page: &DynamicImage,
+) -> tiff::TiffResult<()> {
+    let (width, height) = page.dimensions();
+    match page {
+        DynamicImage::ImageLuma8(page) => {
+            encoder.write_image::<colortype::Gray8>(width, height, page.as_raw())
+        }
+        DynamicImage::ImageLuma16(page) => {
+            encoder.write_image::<colortype::Gray16>(width, height, page.as_raw())
+        }
+        DynamicImage::ImageRgb8(page) => {
+            encoder.write_image::<colortype::RGB8>(width, height, page.as_raw())
+        }
+        DynamicImage::ImageRgb16(page) => {
+            encoder.write_image::<colortype::RGB16>(width, height, page.as_raw())
+        }
+        DynamicImage::ImageRgba16(page) => {
+            encoder.write_image::<colortype::RGBA16>(width, height, page.as_raw())
+        }
+        page => encoder.write_image::<colortype::RGBA8>(width, height, page.to_rgba8().as_raw()),
+    }
+}
+
+/// Describe the crops of a TIFF by its first page, counting it as modified when any page is
+/// cropped
+fn describe(pages: &Pages, crops: &[Option<CropRect>]) -> CropResult {
+    let dimensions = pages
+        .pages
+        .first()
+        .map_or((0, 0), GenericImageView::dimensions);
+    let mut result = CropResult::new(dimensions, crops.first().copied().flatten());
+    result.modified = crops.iter().any(Option::is_some);
+    result
+}
+
+/// Predict the crops of a TIFF without modifying it
+pub(crate) async fn plan_tiff(
+    path: &Path,
+    params: &DetectionParams,
+    shared: bool,
+) -> Result<CropResult> {
+    let pages = Pages::read(path).await?;
+    let crops = pages.plan_crops(params, shared, path);
+    Ok(describe(&pages, &crops))
+}
+
+/// Crop every page of a TIFF and write it back to the same path, keeping the page order.
+/// When `backup` holds a suffix, the file is backed up before it is overwritten.
+pub(crate) async fn remove_tiff_letterbox(
+    path: &Path,
+    params: &DetectionParams,
+    shared: bool,
+    backup: Option<&str>,
+    force: bool,
+) -> Result<CropResult> {
+    let pages = Pages::read(path).await?;
+    let crops = pages.plan_crops(params, shared, path);
+
+    if crops.iter().any(Option::is_some) {
+        if let Some(suffix) = backup {
+            backup_file(path, suffix, force).await?;
+        }
+        let encoded = pages.encode(&crops, path)?;
+        write_file_atomic(path, &encoded).await?;
+        debug!("Cropped {} pages of {}", pages.pages.len(), path.display());
+    }
+
+    Ok(describe(&pages, &crops))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, crop_file};
+    use image::{Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    /// Write a 100x100 TIFF whose pages have black bars of the given heights at the top and
+    /// bottom
+    fn create_test_tiff(path: &Path, bars: &[u32]) -> Result<()> {
+        let mut buf = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buf)?;
+        for &bar in bars {
+            let page = RgbImage::from_fn(100, 100, |_, y| {
+                if y < bar || y >= 100 - bar {
+                    Rgb([0, 0, 0])
+                } else {
+                    Rgb([255, 255, 255])
+                }
+            });
+            encoder.write_image::<colortype::RGB8>(100, 100, page.as_raw())?;
+        }
+        drop(encoder);
+        std::fs::write(path, buf.into_inner())?;
+        Ok(())
+    }
+
+    /// Heights of the pages of a TIFF file
+    async fn page_heights(path: &Path) -> Result<Vec<u32>> {
+        let pages = Pages::read(path).await?;
+        Ok(pages.pages.iter().map(|page| page.height()).collect())
+    }
+
+    #[tokio::test]
+    async fn test_crop_multipage_tiff() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("scan.tiff");
+        create_test_tiff(&path, &[20, 10, 0])?;
+
+        let result = crop_file(&path, &Config::default()).await?;
+        assert!(result.modified);
+        assert_eq!(result.cropped, (100, 60));
+        assert_eq!(page_heights(&path).await?, [60, 80, 100]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crop_multipage_tiff_shared() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("scan.tif");
+        create_test_tiff(&path, &[20, 10])?;
+
+        let config = Config {
+            shared_page_crop: true,
+            ..Config::default()
+        };
+        crop_file(&path, &config).await?;
+
+        // The page with the thinner bars limits the crop
+        assert_eq!(page_heights(&path).await?, [80, 80]);
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! Palette-indexed PNGs, which are decoded to RGB and written back with their palette
+
+use crate::marker;
+use image::{DynamicImage, GenericImageView};
+use log::debug;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Palette of an indexed PNG, as read from its header
+struct Palette {
+    /// RGB triples of the palette entries
+    colors: Vec<u8>,
+    /// Alpha of the leading palette entries, if any are transparent
+    trns: Option<Vec<u8>>,
+    /// Bits per index
+    bit_depth: png::BitDepth,
+}
+
+impl Palette {
+    /// Read the palette of a PNG, or `None` if the PNG is not indexed
+    fn read(original: &[u8]) -> Option<Self> {
+        let reader = png::Decoder::new(Cursor::new(original)).read_info().ok()?;
+        let info = reader.info();
+        if info.color_type != png::ColorType::Indexed {
+            return None;
+        }
+        Some(Self {
+            colors: info.palette.as_deref()?.to_vec(),
+            trns: info.trns.as_deref().map(<[u8]>::to_vec),
+            bit_depth: info.bit_depth,
+        })
+    }
+
+    /// Index of each RGBA color of the palette. Colors listed more than once map to their
+    /// first entry.
+    fn lookup(&self) -> HashMap<[u8; 4], u8> {
+        let mut lookup = HashMap::new();
+        for (rgb, index) in self.colors.chunks_exact(3).zip(0..=u8::MAX) {
+            let alpha = self
+                .trns
+                .as_ref()
+                .and_then(|trns| trns.get(usize::from(index)))
+                .copied()
+                .unwrap_or(u8::MAX);
+            lookup
+                .entry([rgb[0], rgb[1], rgb[2], alpha])
+                .or_insert(index);
+        }
+        lookup
+    }
+
+    /// Bits per index as a number
+    fn bits(&self) -> u32 {
+        match self.bit_depth {
+            png::BitDepth::One => 1,
+            png::BitDepth::Two => 2,
+            png::BitDepth::Four => 4,
+            png::BitDepth::Eight | png::BitDepth::Sixteen => 8,
+        }
+    }
+}
+
+/// Encode `img` as an indexed PNG with the palette of `original`, if that is an indexed PNG.
+/// Returns `None` when it is not, or when the image holds a color missing from the palette,
+/// so that the caller writes an ordinary PNG instead. With `mark`, the image gets the marker
+/// chunk of [`crate::marker`]. The path is only used in messages.
+pub(crate) fn encode_indexed(
+    img: &DynamicImage,
+    original: &[u8],
+    mark: bool,
+    path: &Path,
+) -> Option<Vec<u8>> {
+    // The decoder expands palettes to 8-bit RGB, or RGBA when some entries are transparent
+    if !matches!(
+        img,
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_)
+    ) {
+        return None;
+    }
+    let palette = Palette::read(original)?;
+    let lookup = palette.lookup();
+    let bits = palette.bits();
+
+    let (width, height) = img.dimensions();
+    let row_len = (width * bits).div_ceil(8) as usize;
+    let mut data = vec![0; row_len * height as usize];
+    for (x, y, pixel) in img.pixels() {
+        let Some(&index) = lookup.get(&pixel.0) else {
+            debug!(
+                "Writing palette PNG as RGB, color {:?} is not in its palette: {}",
+                pixel.0,
+                path.display()
+            );
+            return None;
+        };
+        let offset = x * bits;
+        let shift = 8 - bits - offset % 8;
+        data[y as usize * row_len + (offset / 8) as usize] |= index << shift;
+    }
+
+    let mut buf = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buf, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(palette.bit_depth);
+    encoder.set_palette(palette.colors);
+    if let Some(trns) = palette.trns {
+        encoder.set_trns(trns);
+    }
+    let marked = if mark {
+        marker::add_marker(&mut encoder)
+    } else {
+        Ok(())
+    };
+    let written = marked
+        .and_then(|()| encoder.write_header())
+        .and_then(|mut writer| {
+            writer.write_image_data(&data)?;
+            writer.finish()
+        });
+    if let Err(err) = written {
+        debug!(
+            "Writing palette PNG as RGB, failed to encode it with its palette: {}: {err}",
+            path.display()
+        );
+        return None;
+    }
+    Some(buf)
+}
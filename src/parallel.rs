@@ -0,0 +1,272 @@
+//! Processing files on a rayon thread pool, so that decoding and encoding run on dedicated
+//! threads instead of the async runtime
+
+use crate::error::LetterboxError;
+use crate::profile::{self, StageTimes};
+use crate::{
+    BlankPolicy, CROPPED_LOG_TARGET, Config, CropResult, Destinations, DetectionParams,
+    EncodeSettings, FileOutcome, SKIPPED_LOG_TARGET, advance_progress, check_writable,
+    decode_image, describe_crop, encode_image, gif, has_selected_extension, is_blank_image,
+    is_excluded, is_heic_file, is_image_file, is_psd_file, marker, multipage, output_target,
+    plan_crop, process_file_to, webp, write_file_blocking,
+};
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use image::GenericImageView;
+use indicatif::ProgressBar;
+use log::{info, warn};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use tokio::runtime::Handle;
+
+/// Process `files`, each paired with the directories its copies are written to, on a pool
+/// of [`Config::jobs`] threads. Still images are read, cropped, and written on their pool
+/// thread by [`process_file_blocking`], the others wait there for [`process_file_to`] to
+/// finish on the runtime, see [`is_blocking_supported`].
+///
+/// No new files are started once `interrupted` is set, or after the first failure unless
+/// [`Config::continue_on_error`] is set. Outcomes are returned in the order of `files`.
+pub(crate) async fn process_in_pool(
+    files: Vec<(PathBuf, Destinations)>,
+    config: Arc<Config>,
+    progress: Option<ProgressBar>,
+    interrupted: Arc<AtomicBool>,
+) -> Result<Vec<FileOutcome>> {
+    let runtime = Handle::current();
+    let failed = AtomicBool::new(false);
+
+    tokio::task::spawn_blocking(move || {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.jobs.max(1))
+            .build()
+            .context("Failed to create thread pool")?;
+
+        let outcomes = pool.install(|| {
+            files
+                .into_par_iter()
+                .filter_map(|(path, destinations)| {
+                    if interrupted.load(Ordering::Relaxed) || failed.load(Ordering::Relaxed) {
+                        return None;
+                    }
+                    let result = if is_blocking_supported(&path, &config) {
+                        process_counted(&path, &config, || {
+                            process_file_blocking(&path, &config, &destinations)
+                        })
+                    } else {
+                        runtime.block_on(process_file_to(&path, &config, &destinations))
+                    };
+                    if result.is_err() && !config.continue_on_error {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    if let Some(progress) = progress.as_ref().filter(|_| is_image_file(&path)) {
+                        advance_progress(progress, &config);
+                    }
+                    Some((path, result))
+                })
+                .collect()
+        });
+        Ok(outcomes)
+    })
+    .await
+    .context("Thread pool task panicked")?
+}
+
+/// Whether `path` can be processed by [`process_file_blocking`]. HEIC and PSD images, which
+/// are converted, and GIF, WebP, and TIFF images, which may hold several frames, are left to
+/// [`process_file_to`], as are runs with settings that only it implements.
+fn is_blocking_supported(path: &Path, config: &Config) -> bool {
+    let still_image = !(is_heic_file(path)
+        || is_psd_file(path)
+        || gif::is_gif_file(path)
+        || webp::is_webp_file(path)
+        || multipage::is_tiff_file(path));
+    still_image
+        && !config.dry_run
+        && !config.verify
+        && config.blank_policy != BlankPolicy::Delete
+        && config.format.is_none()
+        && config.backup.is_none()
+        && config.output_pattern.is_none()
+        && config.cropped_dir.is_none()
+        && config.unchanged_dir.is_none()
+        && config.min_width == 0
+        && config.min_height == 0
+        && config.max_pixels.is_none()
+        && config.frame_select == 0
+        && config.retries == 0
+        && config.timeout.is_none()
+        && config.cache.is_none()
+        && config.crop_log.is_none()
+}
+
+/// Process a single file with `processing`, reporting it to [`Config::progress_events`] and
+/// counting its outcome in [`Config::stats`] as [`process_file_to`] does
+fn process_counted(
+    path: &Path,
+    config: &Config,
+    processing: impl FnOnce() -> Result<Option<CropResult>>,
+) -> Result<Option<CropResult>> {
+    let events = config
+        .progress_events
+        .as_deref()
+        .filter(|_| is_image_file(path));
+    if let Some(events) = events {
+        events.start(path);
+    }
+    let outcome = processing();
+    if let Some(stats) = &config.stats {
+        stats.record(&outcome);
+    }
+    if let Some(events) = events {
+        events.finish(path, &outcome);
+    }
+    outcome
+}
+
+/// Crop a still image file without the async runtime, writing the cropped copy to the output
+/// directory of `destinations` if it has one. Behaves like [`process_file_to`] for the files
+/// and settings of [`is_blocking_supported`].
+fn process_file_blocking(
+    path: &Path,
+    config: &Config,
+    destinations: &Destinations,
+) -> Result<Option<CropResult>> {
+    if !has_selected_extension(path, config) {
+        info!(
+            target: SKIPPED_LOG_TARGET,
+            "Skipping file with unselected extension: {}",
+            path.display()
+        );
+        return Ok(None);
+    }
+    if is_excluded(path, Path::new(""), config) {
+        info!(target: SKIPPED_LOG_TARGET, "Skipping excluded file: {}", path.display());
+        return Ok(None);
+    }
+    if !is_image_file(path) {
+        warn!(target: SKIPPED_LOG_TARGET, "Skipping non-image file: {}", path.display());
+        return Ok(None);
+    }
+    if !config.force && marker::is_marked(path) {
+        info!(
+            target: SKIPPED_LOG_TARGET,
+            "Skipping image that was already processed: {}",
+            path.display()
+        );
+        return Ok(None);
+    }
+
+    let target = match destinations.output.as_deref() {
+        Some(output) => {
+            let Some(target) = output_target(path, output, config)? else {
+                info!(
+                    target: SKIPPED_LOG_TARGET,
+                    "Skipping image with existing output: {}",
+                    path.display()
+                );
+                return Ok(None);
+            };
+            Some(target)
+        }
+        None => None,
+    };
+
+    let metadata = std::fs::metadata(path).map_err(|err| LetterboxError::io(path, err))?;
+    if target.is_none() {
+        check_writable(path, &metadata)?;
+    }
+    let mtime = config
+        .preserve_mtime
+        .then(|| FileTime::from_last_modification_time(&metadata));
+
+    info!("Processing image file: {}", path.display());
+    let written = match &target {
+        Some(target) => copy_to_target(path, target)?,
+        None => path.to_owned(),
+    };
+    let Some(result) = crop_in_place(&written, config)
+        .with_context(|| format!("Failed to process image file: {}", path.display()))?
+    else {
+        // Nothing was written, so drop the copy made for the output directory
+        if written != path {
+            std::fs::remove_file(&written)
+                .with_context(|| format!("Failed to remove {}", written.display()))?;
+        }
+        info!(target: SKIPPED_LOG_TARGET, "Skipping blank image: {}", path.display());
+        return Ok(None);
+    };
+
+    if config.mark && (result.modified || written != path) {
+        marker::mark_file(&written);
+    }
+    if let Some(mtime) = mtime {
+        filetime::set_file_mtime(&written, mtime).with_context(|| {
+            format!("Failed to restore modification time: {}", written.display())
+        })?;
+    }
+
+    let summary = describe_crop(path, &result);
+    if result.modified {
+        info!(target: CROPPED_LOG_TARGET, "Cropped {summary}");
+    } else {
+        info!("{summary}");
+    }
+    Ok(Some(result))
+}
+
+/// Copy the input file to `target`, creating its directory, and return the copy's path
+fn copy_to_target(path: &Path, target: &Path) -> Result<PathBuf> {
+    if let Some(output) = target.parent() {
+        std::fs::create_dir_all(output)
+            .with_context(|| format!("Failed to create output directory: {}", output.display()))?;
+    }
+    std::fs::copy(path, target)
+        .with_context(|| format!("Failed to copy {} to {}", path.display(), target.display()))?;
+    Ok(target.to_owned())
+}
+
+/// Read, detect, crop, and encode the image at `path`, replacing it when there is a letterbox
+/// to remove. Returns `None` for blank images that are skipped, which are left untouched.
+fn crop_in_place(path: &Path, config: &Config) -> Result<Option<CropResult>> {
+    let params = DetectionParams::new(config);
+    let mut times = StageTimes::default();
+    let start = Instant::now();
+    let original = std::fs::read(path).map_err(|err| LetterboxError::io(path, err))?;
+    let img = decode_image(&original, path)?;
+    times.decode = start.elapsed();
+    let (rect, confidence, blank) = profile::timed(&mut times.detect, || {
+        let (rect, confidence) = plan_crop(&img, &params, path);
+        let blank = rect.is_none() && is_blank_image(&img, &params, path);
+        (rect, confidence, blank)
+    });
+
+    let mut result = CropResult::new(img.dimensions(), rect);
+    result.blank = blank;
+    result.confidence = confidence;
+    if result.blank && config.blank_policy != BlankPolicy::Keep {
+        return Ok(None);
+    }
+
+    if let Some(rect) = rect {
+        let start = Instant::now();
+        let settings = EncodeSettings::new(config);
+        let cropped = settings.resized(Cow::Owned(img.crop_imm(
+            rect.x,
+            rect.y,
+            rect.width,
+            rect.height,
+        )));
+        let format = settings.output_format(&original, path)?;
+        let buf = encode_image(&cropped, format, &original, settings, path)?;
+        write_file_blocking(path, &buf)?;
+        times.encode = start.elapsed();
+    }
+    if let Some(profile) = &config.profile {
+        profile.record(path, times);
+    }
+    Ok(Some(result))
+}
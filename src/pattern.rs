@@ -0,0 +1,241 @@
+//! File name templates for cropped copies, such as `{stem}_{w}x{h}.{ext}`
+
+use crate::{CropResult, OverwritePolicy, SKIPPED_LOG_TARGET};
+use anyhow::{Context, Result};
+use log::info;
+use std::path::{Path, PathBuf};
+
+/// A value that a template token is replaced with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    /// File name of the input without its extension
+    Stem,
+    /// Extension of the written file, which differs from the input's when converting
+    Ext,
+    /// Width after cropping
+    Width,
+    /// Height after cropping
+    Height,
+    /// Pixels removed from the top edge
+    Top,
+    /// Pixels removed from the bottom edge
+    Bottom,
+    /// Pixels removed from the left edge
+    Left,
+    /// Pixels removed from the right edge
+    Right,
+}
+
+impl Token {
+    /// Token for a name between braces
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "stem" => Self::Stem,
+            "ext" => Self::Ext,
+            "w" => Self::Width,
+            "h" => Self::Height,
+            "top" => Self::Top,
+            "bottom" => Self::Bottom,
+            "left" => Self::Left,
+            "right" => Self::Right,
+            _ => return None,
+        })
+    }
+}
+
+/// Part of a template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Token(Token),
+}
+
+/// Template for the file names of cropped copies written to [`crate::Config::output`].
+///
+/// Tokens between braces are replaced for each image: `{stem}` and `{ext}` with the name and
+/// extension of the file, `{w}` and `{h}` with the cropped dimensions, and `{top}`,
+/// `{bottom}`, `{left}`, and `{right}` with the pixels removed from each edge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputPattern {
+    segments: Vec<Segment>,
+}
+
+impl OutputPattern {
+    /// Parse a template such as `{stem}_crop.{ext}`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for unknown tokens, unmatched braces, path separators, or a template
+    /// without any token, which would give every image the same name.
+    pub fn parse(template: &str) -> Result<Self> {
+        if template.contains(['/', '\\']) {
+            anyhow::bail!("Output pattern must be a file name, not a path: {template}");
+        }
+
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                anyhow::bail!("Unmatched '}}' in output pattern: {template}");
+            }
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .with_context(|| format!("Unmatched '{{' in output pattern: {template}"))?;
+            let name = &rest[start + 1..start + end];
+            let token = Token::from_name(name).with_context(|| {
+                format!(
+                    "Unknown token '{{{name}}}' in output pattern, expected one of {{stem}}, \
+                     {{ext}}, {{w}}, {{h}}, {{top}}, {{bottom}}, {{left}}, or {{right}}"
+                )
+            })?;
+            segments.push(Segment::Token(token));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_owned()));
+        }
+
+        if !segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Token(_)))
+        {
+            anyhow::bail!("Output pattern has no tokens, so every image would get the same name");
+        }
+        Ok(Self { segments })
+    }
+
+    /// File name for the image read from `input` and written to `written` with the crop in
+    /// `result`
+    fn render(&self, input: &Path, written: &Path, result: &CropResult) -> String {
+        let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = written.extension().unwrap_or_default().to_string_lossy();
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(text) => text.clone(),
+                Segment::Token(Token::Stem) => stem.to_string(),
+                Segment::Token(Token::Ext) => ext.to_string(),
+                Segment::Token(Token::Width) => result.cropped.0.to_string(),
+                Segment::Token(Token::Height) => result.cropped.1.to_string(),
+                Segment::Token(Token::Top) => result.top.to_string(),
+                Segment::Token(Token::Bottom) => result.bottom.to_string(),
+                Segment::Token(Token::Left) => result.left.to_string(),
+                Segment::Token(Token::Right) => result.right.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Hidden name in the output directory that an image is written to before it is renamed
+/// after the pattern, which needs the crop. The extension is kept, as it selects the format.
+pub(crate) fn staging_path(path: &Path, output: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_stem().unwrap_or_default());
+    name.push(format!(".{}", std::process::id()));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    output.join(name)
+}
+
+/// Rename a copy written to its staging path after the pattern, following `overwrite` when
+/// a file of that name exists. Returns the final path, or `None` when the copy was dropped
+/// because its name is taken.
+pub(crate) async fn apply_pattern(
+    pattern: &OutputPattern,
+    input: &Path,
+    written: &Path,
+    result: &CropResult,
+    overwrite: OverwritePolicy,
+) -> Result<Option<PathBuf>> {
+    let output = written.parent().unwrap_or(Path::new(""));
+    let name = pattern.render(input, written, result);
+    let mut target = output.join(&name);
+
+    if target.exists() {
+        match overwrite {
+            OverwritePolicy::Skip => {
+                info!(
+                    target: SKIPPED_LOG_TARGET,
+                    "Skipping image with existing output: {}",
+                    target.display()
+                );
+                tokio::fs::remove_file(written)
+                    .await
+                    .with_context(|| format!("Failed to remove {}", written.display()))?;
+                return Ok(None);
+            }
+            OverwritePolicy::Overwrite => {}
+            OverwritePolicy::Rename => {
+                let stem = Path::new(&name).file_stem().unwrap_or_default();
+                let extension = Path::new(&name).extension();
+                target = (1..=u32::MAX)
+                    .map(|n| {
+                        let mut candidate = stem.to_owned();
+                        candidate.push(format!("-{n}"));
+                        if let Some(extension) = extension {
+                            candidate.push(".");
+                            candidate.push(extension);
+                        }
+                        output.join(candidate)
+                    })
+                    .find(|candidate| !candidate.exists())
+                    .with_context(|| format!("No free name for {}", target.display()))?;
+            }
+        }
+    }
+
+    tokio::fs::rename(written, &target).await.with_context(|| {
+        format!(
+            "Failed to rename {} to {}",
+            written.display(),
+            target.display()
+        )
+    })?;
+    Ok(Some(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_pattern() -> Result<()> {
+        let result = CropResult {
+            original: (1920, 1080),
+            cropped: (1920, 800),
+            top: 140,
+            bottom: 140,
+            left: 0,
+            right: 0,
+            modified: true,
+            blank: false,
+            confidence: 1.0,
+        };
+        let render = |template| -> Result<String> {
+            Ok(OutputPattern::parse(template)?.render(
+                Path::new("in/frame.001.jxl"),
+                Path::new("out/frame.001.png"),
+                &result,
+            ))
+        };
+
+        assert_eq!(render("{stem}_crop.{ext}")?, "frame.001_crop.png");
+        assert_eq!(render("{stem}_{w}x{h}.{ext}")?, "frame.001_1920x800.png");
+        assert_eq!(
+            render("{top}-{bottom}-{stem}.png")?,
+            "140-140-frame.001.png"
+        );
+
+        assert!(OutputPattern::parse("{stem}_{width}.{ext}").is_err());
+        assert!(OutputPattern::parse("{stem.{ext}").is_err());
+        assert!(OutputPattern::parse("stem}.png").is_err());
+        assert!(OutputPattern::parse("cropped.png").is_err());
+        assert!(OutputPattern::parse("sub/{stem}.{ext}").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! Photoshop PSD decoding, flattening the layers into a single image
+
+use crate::LetterboxError;
+use anyhow::Result;
+use image::{DynamicImage, RgbaImage};
+use psd::Psd;
+use std::path::Path;
+
+/// Decode a PSD file into its flattened RGBA composite
+pub(crate) fn decode_psd(bytes: &[u8], path: &Path) -> Result<DynamicImage> {
+    let psd = Psd::from_bytes(bytes).map_err(|err| LetterboxError::decode(path, err))?;
+    let img = RgbaImage::from_raw(psd.width(), psd.height(), psd.rgba())
+        .ok_or_else(|| LetterboxError::decode(path, "pixel data does not fill the image"))?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, process_file};
+    use image::GenericImageView;
+    use tempfile::TempDir;
+
+    /// Encode an 8-bit RGB PSD without layers, holding only the composite image
+    fn flat_psd(width: u32, height: u32, pixel: impl Fn(u32, u32) -> [u8; 3]) -> Vec<u8> {
+        let mut bytes = b"8BPS".to_vec();
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&[0; 6]);
+        bytes.extend_from_slice(&3u16.to_be_bytes());
+        bytes.extend_from_slice(&height.to_be_bytes());
+        bytes.extend_from_slice(&width.to_be_bytes());
+        bytes.extend_from_slice(&8u16.to_be_bytes());
+        bytes.extend_from_slice(&3u16.to_be_bytes());
+        // Empty color mode data, image resources, and layer and mask sections
+        bytes.extend_from_slice(&[0; 12]);
+        // Uncompressed image data, one channel after the other
+        bytes.extend_from_slice(&0u16.to_be_bytes());
+        for channel in 0..3 {
+            for y in 0..height {
+                for x in 0..width {
+                    bytes.push(pixel(x, y)[channel]);
+                }
+            }
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_crop_psd() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("design.psd");
+        let bytes = flat_psd(100, 100, |_, y| {
+            if (20..80).contains(&y) {
+                [255, 255, 255]
+            } else {
+                [0, 0, 0]
+            }
+        });
+        std::fs::write(&path, &bytes)?;
+        assert_eq!(decode_psd(&bytes, &path)?.dimensions(), (100, 100));
+
+        let result = process_file(&path, &Config::default()).await?.unwrap();
+        assert_eq!(result.cropped, (100, 60));
+        // The layers are only in the PSD, so it is kept next to the flattened copy
+        assert!(path.exists());
+        assert_eq!(
+            image::open(path.with_extension("png"))?.dimensions(),
+            (100, 60)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Preview copies of images with the predicted crop outlined, for checking settings in a dry
+//! run
+
+use crate::{CropResult, write_file_atomic};
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageFormat, Rgba};
+use imageproc::drawing::draw_hollow_rect_mut;
+use imageproc::rect::Rect;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Color of the lines marking the crop
+const LINE_COLOR: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+/// Width of the lines marking the crop, in pixels
+const LINE_WIDTH: u32 = 2;
+
+/// Path of the preview of `path` in `dir`. The image's whole file name is kept, so that images
+/// differing only in extension get separate previews.
+fn preview_path(path: &Path, dir: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".png");
+    dir.join(name)
+}
+
+/// Write a PNG copy of an image to `dir` with the crop in `result` outlined. Images without a
+/// crop are copied without lines.
+pub(crate) async fn write_preview(
+    img: &DynamicImage,
+    result: &CropResult,
+    path: &Path,
+    dir: &Path,
+) -> Result<()> {
+    let mut preview = img.to_rgba8();
+    if result.modified {
+        let (width, height) = result.cropped;
+        // Lines are drawn inside the kept area, so they stay visible on crops reaching an edge
+        for inset in 0..LINE_WIDTH.min(width / 2).min(height / 2) {
+            let x = i32::try_from(result.left + inset)?;
+            let y = i32::try_from(result.top + inset)?;
+            let rect = Rect::at(x, y).of_size(width - 2 * inset, height - 2 * inset);
+            draw_hollow_rect_mut(&mut preview, rect, LINE_COLOR);
+        }
+    }
+
+    let target = preview_path(path, dir);
+    let mut png = Vec::new();
+    preview
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .with_context(|| format!("Failed to encode preview: {}", target.display()))?;
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create preview directory: {}", dir.display()))?;
+    write_file_atomic(&target, &png).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::create_test_image;
+    use crate::{Config, crop_file, process_directory};
+    use image::RgbaImage;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_write_preview() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        let preview_dir = temp_dir.path().join("previews");
+        let config = Config {
+            dry_run: true,
+            preview_dir: Some(preview_dir.clone()),
+            ..Config::default()
+        };
+        crop_file(&path, &config).await?;
+
+        // The original is untouched and the preview outlines the kept rows
+        assert_eq!(image::open(&path)?.height(), 100);
+        let preview = image::open(preview_dir.join("test.png.png"))?.to_rgba8();
+        assert_eq!(preview.dimensions(), (100, 100));
+        assert_eq!(*preview.get_pixel(50, 25), LINE_COLOR);
+        assert_eq!(*preview.get_pixel(50, 75), LINE_COLOR);
+        assert_eq!(*preview.get_pixel(50, 50), Rgba([255, 255, 255, 255]));
+        assert_eq!(*preview.get_pixel(50, 10), Rgba([0, 0, 0, 255]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_preview_mirrors_subdirectories() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("input");
+        // Images of the same name in different directories, of different heights
+        for (dir, height) in [("a", 50), ("b", 80)] {
+            std::fs::create_dir_all(input.join(dir))?;
+            RgbaImage::from_pixel(100, height, Rgba([255, 255, 255, 255]))
+                .save(input.join(dir).join("frame.png"))?;
+        }
+
+        let preview_dir = temp_dir.path().join("previews");
+        let config = Config {
+            dry_run: true,
+            recursive: true,
+            preview_dir: Some(preview_dir.clone()),
+            ..Config::default()
+        };
+        process_directory(&input, &config).await?;
+
+        assert_eq!(
+            image::open(preview_dir.join("a/frame.png.png"))?.height(),
+            50
+        );
+        assert_eq!(
+            image::open(preview_dir.join("b/frame.png.png"))?.height(),
+            80
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! Timing of the decode, detection, and encode stages of a run, to show where time goes
+
+use log::debug;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Time spent on one image in each stage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct StageTimes {
+    /// Reading and decoding the file
+    pub decode: Duration,
+    /// Detecting the letterbox and deciding on the crop
+    pub detect: Duration,
+    /// Cropping, encoding, and writing the result
+    pub encode: Duration,
+}
+
+/// Measure how long `stage` takes, adding the time to `total`
+pub(crate) fn timed<T>(total: &mut Duration, stage: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let value = stage();
+    *total += start.elapsed();
+    value
+}
+
+/// Measure how long the future `stage` takes to complete, adding the time to `total`
+pub(crate) async fn timed_async<T>(total: &mut Duration, stage: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let value = stage.await;
+    *total += start.elapsed();
+    value
+}
+
+/// Stage times summed over the images of a run. Animated GIFs and multi-page TIFFs are not
+/// timed.
+#[derive(Debug, Default)]
+pub struct Profile {
+    images: AtomicUsize,
+    decode_nanos: AtomicU64,
+    detect_nanos: AtomicU64,
+    encode_nanos: AtomicU64,
+}
+
+impl Profile {
+    /// Start a profile with nothing recorded
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Log the stage times of one image at debug level and add them to the totals
+    pub(crate) fn record(&self, path: &Path, times: StageTimes) {
+        debug!(
+            "Profiled {}: decode {:.2?}, detection {:.2?}, encode {:.2?}",
+            path.display(),
+            times.decode,
+            times.detect,
+            times.encode
+        );
+        let nanos = |duration: Duration| u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.images.fetch_add(1, Ordering::Relaxed);
+        self.decode_nanos
+            .fetch_add(nanos(times.decode), Ordering::Relaxed);
+        self.detect_nanos
+            .fetch_add(nanos(times.detect), Ordering::Relaxed);
+        self.encode_nanos
+            .fetch_add(nanos(times.encode), Ordering::Relaxed);
+    }
+
+    /// Number of images recorded so far
+    #[must_use]
+    pub fn images(&self) -> usize {
+        self.images.load(Ordering::Relaxed)
+    }
+
+    /// Stage times summed over the images recorded so far
+    fn totals(&self) -> StageTimes {
+        let total = |nanos: &AtomicU64| Duration::from_nanos(nanos.load(Ordering::Relaxed));
+        StageTimes {
+            decode: total(&self.decode_nanos),
+            detect: total(&self.detect_nanos),
+            encode: total(&self.encode_nanos),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let totals = self.totals();
+        let sum = (totals.decode + totals.detect + totals.encode).as_secs_f64();
+        let share = |stage: Duration| {
+            if sum > 0.0 {
+                stage.as_secs_f64() / sum * 100.0
+            } else {
+                0.0
+            }
+        };
+        write!(
+            f,
+            "Profiled {} images: decode {:.2?} ({:.0}%), detection {:.2?} ({:.0}%), encode {:.2?} ({:.0}%)",
+            self.images(),
+            totals.decode,
+            share(totals.decode),
+            totals.detect,
+            share(totals.detect),
+            totals.encode,
+            share(totals.encode)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_totals() {
+        let profile = Profile::new();
+        let times = StageTimes {
+            decode: Duration::from_millis(30),
+            detect: Duration::from_millis(10),
+            encode: Duration::from_millis(60),
+        };
+        profile.record(Path::new("a.png"), times);
+        profile.record(Path::new("b.png"), times);
+
+        assert_eq!(
+            profile.to_string(),
+            "Profiled 2 images: decode 60.00ms (30%), detection 20.00ms (10%), encode 120.00ms (60%)"
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! Machine-readable summaries of a run
+
+use crate::CropResult;
+use crate::error::is_permission_denied;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// File format of a report written by [`write_report`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// A JSON array with one object per file
+    #[default]
+    Json,
+    /// A header row followed by one row per file, with dimensions split into width and height
+    Csv,
+}
+
+/// What happened to a single file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The letterbox was removed, or would be removed in a dry run
+    Cropped,
+    /// No letterbox was detected
+    Unchanged,
+    /// The file is not a supported image
+    Skipped,
+    /// The file failed to process
+    Error,
+    /// The file could not be read or written for lack of permission
+    #[serde(rename = "permission_denied")]
+    PermissionDenied,
+}
+
+/// Report entry describing the outcome for one file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportEntry {
+    /// Path of the input file
+    pub path: PathBuf,
+    /// Outcome of processing the file
+    pub status: Status,
+    /// Whether the image was cropped, or would be cropped in a dry run
+    pub modified: bool,
+    /// Dimensions before cropping, if the image was decoded
+    pub original: Option<(u32, u32)>,
+    /// Dimensions after cropping, if the image was decoded
+    pub cropped: Option<(u32, u32)>,
+    /// Pixels removed from the top edge
+    pub top: u32,
+    /// Pixels removed from the bottom edge
+    pub bottom: u32,
+    /// Pixels removed from the left edge
+    pub left: u32,
+    /// Pixels removed from the right edge
+    pub right: u32,
+    /// Error message for failed files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Status {
+    /// Status of a file from the outcome of [`crate::process_file`]
+    fn of(outcome: &Result<Option<CropResult>>) -> Self {
+        match outcome {
+            Ok(Some(result)) if result.modified => Self::Cropped,
+            Ok(Some(_)) => Self::Unchanged,
+            Ok(None) => Self::Skipped,
+            Err(err) if is_permission_denied(err) => Self::PermissionDenied,
+            Err(_) => Self::Error,
+        }
+    }
+
+    /// Name of the status, as written in reports
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Cropped => "cropped",
+            Self::Unchanged => "unchanged",
+            Self::Skipped => "skipped",
+            Self::Error => "error",
+            Self::PermissionDenied => "permission_denied",
+        }
+    }
+}
+
+impl ReportEntry {
+    /// Build the entry for a file from the outcome of [`crate::process_file`]
+    #[must_use]
+    pub fn new(path: &Path, outcome: &Result<Option<CropResult>>) -> Self {
+        let blank = Self {
+            path: path.to_owned(),
+            status: Status::Skipped,
+            modified: false,
+            original: None,
+            cropped: None,
+            top: 0,
+            bottom: 0,
+            left: 0,
+            right: 0,
+            error: None,
+        };
+
+        match outcome {
+            Ok(Some(result)) => Self {
+                status: Status::of(outcome),
+                modified: result.modified,
+                original: Some(result.original),
+                cropped: Some(result.cropped),
+                top: result.top,
+                bottom: result.bottom,
+                left: result.left,
+                right: result.right,
+                ..blank
+            },
+            Ok(None) => blank,
+            Err(err) => Self {
+                status: Status::of(outcome),
+                error: Some(format!("{err:#}")),
+                ..blank
+            },
+        }
+    }
+}
+
+/// Counts of file outcomes over a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Files that finished processing, whatever the outcome
+    pub processed: usize,
+    /// Files that were cropped, or would be in a dry run
+    pub cropped: usize,
+    /// Images without a letterbox
+    pub unchanged: usize,
+    /// Files that were not processed as images
+    pub skipped: usize,
+    /// Files that failed to process, other than for lack of permission
+    pub errors: usize,
+    /// Files that could not be read or written for lack of permission
+    pub permission_denied: usize,
+    /// Identical images removed after the run
+    pub duplicates: usize,
+}
+
+impl Summary {
+    /// Tally the outcomes recorded in `entries`
+    #[must_use]
+    pub fn from_entries(entries: &[ReportEntry]) -> Self {
+        entries.iter().fold(Self::default(), |mut summary, entry| {
+            summary.processed += 1;
+            match entry.status {
+                Status::Cropped => summary.cropped += 1,
+                Status::Unchanged => summary.unchanged += 1,
+                Status::Skipped => summary.skipped += 1,
+                Status::Error => summary.errors += 1,
+                Status::PermissionDenied => summary.permission_denied += 1,
+            }
+            summary
+        })
+    }
+}
+
+/// Counts of file outcomes that concurrent tasks add to as their files finish, so that a
+/// progress bar and the final [`Summary`] see the same numbers
+#[derive(Debug, Default)]
+pub struct Stats {
+    cropped: AtomicUsize,
+    unchanged: AtomicUsize,
+    skipped: AtomicUsize,
+    errors: AtomicUsize,
+    permission_denied: AtomicUsize,
+}
+
+impl Stats {
+    /// Start with all counts at zero
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count the outcome of [`crate::process_file`] for one file
+    pub fn record(&self, outcome: &Result<Option<CropResult>>) {
+        let counter = match Status::of(outcome) {
+            Status::Cropped => &self.cropped,
+            Status::Unchanged => &self.unchanged,
+            Status::Skipped => &self.skipped,
+            Status::Error => &self.errors,
+            Status::PermissionDenied => &self.permission_denied,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add the counts recorded in `other`
+    pub(crate) fn add(&self, other: &Self) {
+        for (counter, added) in [
+            (&self.cropped, &other.cropped),
+            (&self.unchanged, &other.unchanged),
+            (&self.skipped, &other.skipped),
+            (&self.errors, &other.errors),
+            (&self.permission_denied, &other.permission_denied),
+        ] {
+            counter.fetch_add(added.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// The counts recorded so far
+    #[must_use]
+    pub fn summary(&self) -> Summary {
+        let cropped = self.cropped.load(Ordering::Relaxed);
+        let unchanged = self.unchanged.load(Ordering::Relaxed);
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        let errors = self.errors.load(Ordering::Relaxed);
+        let permission_denied = self.permission_denied.load(Ordering::Relaxed);
+        Summary {
+            processed: cropped + unchanged + skipped + errors + permission_denied,
+            cropped,
+            unchanged,
+            skipped,
+            errors,
+            permission_denied,
+            duplicates: 0,
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} files: {} cropped, {} unchanged, {} skipped, {} errors",
+            self.processed, self.cropped, self.unchanged, self.skipped, self.errors
+        )?;
+        if self.permission_denied > 0 {
+            write!(f, ", {} permission denied", self.permission_denied)?;
+        }
+        if self.duplicates > 0 {
+            write!(f, ", {} duplicates removed", self.duplicates)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write report entries to `path` in the given format, sorted by path so that reports of the
+/// same files are identical however the run was scheduled. In a dry run, the entries hold the
+/// predicted crops, which makes the report a baseline to diff later runs against.
+///
+/// # Errors
+///
+/// Returns an error if the entries cannot be serialized or the file cannot be written.
+pub async fn write_report(
+    path: &Path,
+    entries: &[ReportEntry],
+    format: ReportFormat,
+) -> Result<()> {
+    let mut entries = entries.to_vec();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let contents = match format {
+        ReportFormat::Json => {
+            serde_json::to_vec_pretty(&entries).context("Failed to serialize report")?
+        }
+        ReportFormat::Csv => to_csv(&entries).context("Failed to serialize report")?,
+    };
+    tokio::fs::write(path, contents)
+        .await
+        .with_context(|| format!("Failed to write report: {}", path.display()))
+}
+
+/// Serialize report entries as CSV, leaving the dimensions of files that were not decoded empty
+fn to_csv(entries: &[ReportEntry]) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record([
+        "path",
+        "status",
+        "original_width",
+        "original_height",
+        "cropped_width",
+        "cropped_height",
+        "top",
+        "bottom",
+        "left",
+        "right",
+        "error",
+    ])?;
+
+    let dimension = |size: Option<(u32, u32)>, pick: fn((u32, u32)) -> u32| {
+        size.map(|size| pick(size).to_string()).unwrap_or_default()
+    };
+    for entry in entries {
+        writer.write_record([
+            entry.path.to_string_lossy().into_owned(),
+            entry.status.as_str().to_owned(),
+            dimension(entry.original, |(width, _)| width),
+            dimension(entry.original, |(_, height)| height),
+            dimension(entry.cropped, |(width, _)| width),
+            dimension(entry.cropped, |(_, height)| height),
+            entry.top.to_string(),
+            entry.bottom.to_string(),
+            entry.left.to_string(),
+            entry.right.to_string(),
+            entry.error.clone().unwrap_or_default(),
+        ])?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_entry_json() -> Result<()> {
+        let result = CropResult {
+            original: (100, 100),
+            cropped: (100, 50),
+            top: 25,
+            bottom: 25,
+            left: 0,
+            right: 0,
+            modified: true,
+            blank: false,
+            confidence: 1.0,
+        };
+        let cropped = ReportEntry::new(Path::new("a.png"), &Ok(Some(result)));
+        let failed = ReportEntry::new(Path::new("b.png"), &Err(anyhow::anyhow!("bad data")));
+
+        let json: serde_json::Value = serde_json::to_value([cropped, failed])?;
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "path": "a.png",
+                    "status": "cropped",
+                    "modified": true,
+                    "original": [100, 100],
+                    "cropped": [100, 50],
+                    "top": 25,
+                    "bottom": 25,
+                    "left": 0,
+                    "right": 0
+                },
+                {
+                    "path": "b.png",
+                    "status": "error",
+                    "modified": false,
+                    "original": null,
+                    "cropped": null,
+                    "top": 0,
+                    "bottom": 0,
+                    "left": 0,
+                    "right": 0,
+                    "error": "bad data"
+                }
+            ])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_report_sorted() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("report.json");
+        let unchanged = CropResult {
+            original: (10, 10),
+            cropped: (10, 10),
+            top: 0,
+            bottom: 0,
+            left: 0,
+            right: 0,
+            modified: false,
+            blank: false,
+            confidence: 1.0,
+        };
+        // Entries are recorded in the order files finish
+        let entries = [
+            ReportEntry::new(Path::new("b.png"), &Ok(Some(unchanged))),
+            ReportEntry::new(Path::new("a.png"), &Ok(Some(unchanged))),
+        ];
+        write_report(&path, &entries, ReportFormat::Json).await?;
+
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+        assert_eq!(json[0]["path"], "a.png");
+        assert_eq!(json[1]["path"], "b.png");
+        assert_eq!(json[1]["modified"], false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_entry_csv() -> Result<()> {
+        let result = CropResult {
+            original: (100, 100),
+            cropped: (100, 50),
+            top: 25,
+            bottom: 25,
+            left: 0,
+            right: 0,
+            modified: true,
+            blank: false,
+            confidence: 1.0,
+        };
+        let entries = [
+            ReportEntry::new(Path::new("a, \"b\".png"), &Ok(Some(result))),
+            ReportEntry::new(Path::new("c.txt"), &Ok(None)),
+        ];
+
+        let csv = String::from_utf8(to_csv(&entries)?)?;
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "path,status,original_width,original_height,cropped_width,cropped_height,top,bottom,left,right,error",
+                "\"a, \"\"b\"\".png\",cropped,100,100,100,50,25,25,0,0,",
+                "c.txt,skipped,,,,,0,0,0,0,",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary() {
+        let unchanged = CropResult {
+            original: (10, 10),
+            cropped: (10, 10),
+            top: 0,
+            bottom: 0,
+            left: 0,
+            right: 0,
+            modified: false,
+            blank: false,
+            confidence: 1.0,
+        };
+        let entries = [
+            ReportEntry::new(Path::new("a.png"), &Ok(Some(unchanged))),
+            ReportEntry::new(Path::new("b.txt"), &Ok(None)),
+            ReportEntry::new(Path::new("c.png"), &Ok(None)),
+        ];
+
+        let summary = Summary::from_entries(&entries);
+        assert_eq!(
+            summary.to_string(),
+            "Processed 3 files: 0 cropped, 1 unchanged, 2 skipped, 0 errors"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats_from_concurrent_tasks() -> Result<()> {
+        let stats = std::sync::Arc::new(Stats::new());
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..100 {
+            let stats = std::sync::Arc::clone(&stats);
+            tasks.spawn(async move {
+                let outcome = if i % 4 == 0 {
+                    Err(anyhow::anyhow!("bad data"))
+                } else {
+                    Ok(None)
+                };
+                stats.record(&outcome);
+            });
+        }
+        while let Some(joined) = tasks.join_next().await {
+            joined?;
+        }
+
+        let summary = stats.summary();
+        assert_eq!(summary.processed, 100);
+        assert_eq!(summary.errors, 25);
+        assert_eq!(summary.skipped, 75);
+        Ok(())
+    }
+}
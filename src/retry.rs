@@ -0,0 +1,221 @@
+//! Retrying reads and writes that failed on transient I/O errors, such as those of network
+//! storage
+
+use crate::Config;
+use anyhow::Result;
+use log::warn;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// `EIO`, which network filesystems report for failures that often pass
+#[cfg(unix)]
+const EIO: i32 = 5;
+
+/// Whether an I/O error may go away when the operation is repeated
+fn is_transient_io(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(EIO) {
+        return true;
+    }
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Whether an error was caused by a transient I/O error. Decoding errors and permanent I/O
+/// errors, such as a missing file or denied permission, fail the same way every time.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(image::ImageError::IoError(err)) = cause.downcast_ref::<image::ImageError>() {
+            return is_transient_io(err);
+        }
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(is_transient_io)
+    })
+}
+
+/// How often a failed read or write is repeated, see [`Config::retries`]
+#[derive(Clone, Copy, Debug, Default)]
+struct Policy {
+    retries: u32,
+    delay: Duration,
+}
+
+tokio::task_local! {
+    /// Retries of the reads and writes of the file that the current task processes
+    static POLICY: Policy;
+}
+
+/// Process a file with `processing`, whose reads and writes through [`with_retries`] are
+/// repeated as `config` says. Only the failed operation is repeated, never the steps that
+/// already succeeded.
+pub(crate) async fn with_policy<T>(config: &Config, processing: impl Future<Output = T>) -> T {
+    let policy = Policy {
+        retries: config.retries,
+        delay: config.retry_delay,
+    };
+    POLICY.scope(policy, processing).await
+}
+
+/// Run the read or write `attempt` on the file at `path`, repeating it up to
+/// [`Config::retries`] times while it fails with a transient I/O error. The first retry waits
+/// [`Config::retry_delay`], and the delay doubles with every further retry. Outside of
+/// [`with_policy`], the attempt is made once.
+pub(crate) async fn with_retries<T, F>(path: &Path, mut attempt: impl FnMut() -> F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let policy = POLICY.try_with(|policy| *policy).unwrap_or_default();
+    let mut delay = policy.delay;
+    for retry in 1..=policy.retries {
+        match attempt().await {
+            Err(err) if is_transient(&err) => {
+                warn!(
+                    "Retrying {} in {delay:.2?} ({retry}/{}): {err:#}",
+                    path.display(),
+                    policy.retries
+                );
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2);
+            }
+            outcome => return outcome,
+        }
+    }
+    attempt().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    #[test]
+    fn test_is_transient() {
+        let timed_out = anyhow::Error::new(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(is_transient(
+            &timed_out.context("Failed to read image file")
+        ));
+        #[cfg(unix)]
+        assert!(is_transient(&anyhow::Error::new(
+            io::Error::from_raw_os_error(EIO)
+        )));
+        let missing = anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound));
+        assert!(!is_transient(&missing));
+        assert!(!is_transient(&anyhow::anyhow!(
+            "Failed to decode image file"
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_with_retries() -> Result<()> {
+        let config = Config {
+            retries: 3,
+            retry_delay: Duration::from_millis(1),
+            ..Config::default()
+        };
+        let path = Path::new("flaky.png");
+
+        // Transient errors are retried until the operation succeeds
+        let attempts = Cell::new(0);
+        let counter = &attempts;
+        let retried = with_retries(path, || async move {
+            counter.set(counter.get() + 1);
+            if counter.get() < 3 {
+                Err(io::Error::from(io::ErrorKind::TimedOut)).context("Failed to read image file")
+            } else {
+                Ok(7)
+            }
+        });
+        let value = with_policy(&config, retried).await?;
+        assert_eq!((value, attempts.get()), (7, 3));
+
+        // Other errors fail right away
+        attempts.set(0);
+        let retried = with_retries(path, || async move {
+            counter.set(counter.get() + 1);
+            Err::<(), _>(anyhow::anyhow!("Failed to decode image file"))
+        });
+        assert!(with_policy(&config, retried).await.is_err());
+        assert_eq!(attempts.get(), 1);
+
+        // A transient error that persists fails after the last retry
+        attempts.set(0);
+        let failing = || async move {
+            counter.set(counter.get() + 1);
+            Err::<(), _>(io::Error::from(io::ErrorKind::TimedOut).into())
+        };
+        assert!(
+            with_policy(&config, with_retries(path, failing))
+                .await
+                .is_err()
+        );
+        assert_eq!(attempts.get(), 4);
+
+        // Without a policy, nothing is retried
+        attempts.set(0);
+        assert!(with_retries(path, failing).await.is_err());
+        assert_eq!(attempts.get(), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_failure_after_write_is_not_retried() -> Result<()> {
+        use crate::{BorderColor, CropLog, process_file};
+        use image::{Rgba, RgbaImage};
+        use std::sync::Arc;
+
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        // A letterbox inside a letterbox, so that cropping twice would remove both
+        RgbaImage::from_fn(100, 100, |_, y| {
+            if (40..60).contains(&y) {
+                Rgba([255, 255, 255, 255])
+            } else if (20..80).contains(&y) {
+                Rgba([5, 5, 5, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        })
+        .save(&path)?;
+
+        // A crop log on a pipe whose reader is gone, so that appending to it fails with a
+        // broken pipe once the image was written
+        let fifo = temp_dir.path().join("crops.tsv");
+        let created = std::process::Command::new("mkfifo").arg(&fifo).status()?;
+        assert!(created.success());
+        let reader = std::thread::spawn({
+            let fifo = fifo.clone();
+            move || std::fs::File::open(fifo).map(drop)
+        });
+        let crop_log = CropLog::open(&fifo).await?;
+        reader.join().expect("reader panicked")?;
+
+        // The border color is found anew on every pass, so a second pass crops the inner box
+        let config = Config {
+            threshold: [0; 3],
+            border_color: Some(BorderColor::Auto),
+            max_crop_percent: 100,
+            retries: 2,
+            retry_delay: Duration::from_millis(1),
+            crop_log: Some(Arc::new(crop_log)),
+            ..Config::default()
+        };
+        assert!(process_file(&path, &config).await.is_err());
+        assert_eq!(image::open(&path)?.height(), 60);
+        Ok(())
+    }
+}
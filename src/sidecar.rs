@@ -0,0 +1,212 @@
+//! Sidecar files recording in-place crops, so that they can be undone from the backup
+
+use crate::CropResult;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Suffix appended to the name of a cropped image to name its sidecar
+const SIDECAR_SUFFIX: &str = ".letterbox.json";
+
+/// Record of an in-place crop, stored next to the cropped image
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Sidecar {
+    /// File name of the original image, which differs from the cropped image's name when it
+    /// was converted to another format
+    original: PathBuf,
+    /// File name of the backup of the original
+    backup: PathBuf,
+    /// Dimensions of the original image
+    original_size: (u32, u32),
+    /// Dimensions of the cropped image
+    cropped_size: (u32, u32),
+    /// Pixels removed from the top, bottom, left, and right edges
+    removed: [u32; 4],
+}
+
+/// Path of the sidecar belonging to an image
+fn sidecar_path(image: &Path) -> PathBuf {
+    let mut name = OsString::from(image.as_os_str());
+    name.push(SIDECAR_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Whether the path is a sidecar written by [`write_sidecar`]
+pub(crate) fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(SIDECAR_SUFFIX))
+}
+
+/// Record that `original` was cropped in place into `cropped`, after being backed up with
+/// `suffix` appended to its name
+pub(crate) async fn write_sidecar(
+    original: &Path,
+    cropped: &Path,
+    suffix: &str,
+    result: &CropResult,
+) -> Result<()> {
+    let original_name = original
+        .file_name()
+        .with_context(|| format!("Input path has no file name: {}", original.display()))?;
+    let mut backup = original_name.to_owned();
+    backup.push(suffix);
+
+    let sidecar = Sidecar {
+        original: original_name.into(),
+        backup: backup.into(),
+        original_size: result.original,
+        cropped_size: result.cropped,
+        removed: [result.top, result.bottom, result.left, result.right],
+    };
+    let path = sidecar_path(cropped);
+    let json = serde_json::to_vec_pretty(&sidecar).context("Failed to serialize sidecar")?;
+    tokio::fs::write(&path, json)
+        .await
+        .with_context(|| format!("Failed to write sidecar: {}", path.display()))
+}
+
+/// Restore the original of a cropped image from the backup recorded in its sidecar, removing
+/// the cropped image and the sidecar. `path` may be the cropped image or its sidecar.
+///
+/// Returns `false` when the image has no sidecar.
+///
+/// # Errors
+///
+/// Returns an error if the sidecar cannot be read or its backup is missing.
+pub async fn undo_file(path: &Path) -> Result<bool> {
+    let (image, sidecar) = if is_sidecar(path) {
+        let name = path.as_os_str().to_string_lossy();
+        let image = PathBuf::from(&name[..name.len() - SIDECAR_SUFFIX.len()]);
+        (image, path.to_owned())
+    } else {
+        (path.to_owned(), sidecar_path(path))
+    };
+
+    let json = match tokio::fs::read(&sidecar).await {
+        Ok(json) => json,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read sidecar: {}", sidecar.display()));
+        }
+    };
+    let record: Sidecar = serde_json::from_slice(&json)
+        .with_context(|| format!("Invalid sidecar: {}", sidecar.display()))?;
+
+    let dir = image.parent().unwrap_or(Path::new(""));
+    let original = dir.join(&record.original);
+    let backup = dir.join(&record.backup);
+    if !tokio::fs::try_exists(&backup).await.unwrap_or(false) {
+        anyhow::bail!(
+            "Cannot undo crop of {}, backup is missing: {}",
+            image.display(),
+            backup.display()
+        );
+    }
+
+    if original != image {
+        match tokio::fs::remove_file(&image).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                warn!("Cropped image is already gone: {}", image.display());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to remove cropped image: {}", image.display())
+                });
+            }
+        }
+    }
+    tokio::fs::rename(&backup, &original)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to restore {} from {}",
+                original.display(),
+                backup.display()
+            )
+        })?;
+    tokio::fs::remove_file(&sidecar)
+        .await
+        .with_context(|| format!("Failed to remove sidecar: {}", sidecar.display()))?;
+
+    let (width, height) = record.original_size;
+    info!(
+        "Restored {} ({width}x{height}) from {}",
+        original.display(),
+        backup.display()
+    );
+    Ok(true)
+}
+
+/// Undo every crop recorded by a sidecar in a directory, and its subdirectories when
+/// `recursive` is set. Symlinked directories are not followed.
+///
+/// Returns the number of restored images.
+///
+/// # Errors
+///
+/// Returns an error if a directory cannot be read or a crop cannot be undone.
+pub async fn undo_directory(dir: &Path, recursive: bool) -> Result<usize> {
+    let mut restored = 0;
+    let mut pending = VecDeque::from([dir.to_owned()]);
+
+    while let Some(dir) = pending.pop_front() {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("Failed to read directory entry in: {}", dir.display()))?
+        {
+            let path = entry.path();
+            let file_type = entry
+                .file_type()
+                .await
+                .with_context(|| format!("Failed to read file type: {}", path.display()))?;
+            if file_type.is_dir() && recursive {
+                pending.push_back(path);
+            } else if file_type.is_file() && is_sidecar(&path) && undo_file(&path).await? {
+                restored += 1;
+            }
+        }
+    }
+
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::create_test_image;
+    use crate::{Config, crop_file};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_undo_crop() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        let config = Config {
+            backup: Some(".bak".to_owned()),
+            ..Config::default()
+        };
+        crop_file(&path, &config).await?;
+        assert!(sidecar_path(&path).exists());
+        assert_eq!(image::open(&path)?.height(), 51);
+
+        assert_eq!(undo_directory(temp_dir.path(), false).await?, 1);
+        assert_eq!(image::open(&path)?.height(), 100);
+        assert!(!sidecar_path(&path).exists());
+        assert!(!temp_dir.path().join("test.png.bak").exists());
+        assert!(!undo_file(&path).await?);
+        Ok(())
+    }
+}
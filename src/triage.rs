@@ -0,0 +1,116 @@
+//! Sorting of processed images into separate directories for cropped and untouched files
+
+use crate::{Config, CropResult};
+use anyhow::{Context, Result};
+use log::info;
+use std::path::Path;
+
+/// Directory that an image with this crop is moved to, see [`Config::cropped_dir`] and
+/// [`Config::unchanged_dir`]
+fn sort_dir<'a>(result: &CropResult, config: &'a Config) -> Option<&'a Path> {
+    if result.modified {
+        config.cropped_dir.as_deref()
+    } else {
+        config.unchanged_dir.as_deref()
+    }
+}
+
+/// Whether `dir` is one of the directories images are sorted into, which are not walked so
+/// that moved images are not processed again
+pub(crate) fn is_sort_dir(dir: &Path, config: &Config) -> bool {
+    let Ok(dir) = std::path::absolute(dir) else {
+        return false;
+    };
+    [&config.cropped_dir, &config.unchanged_dir]
+        .into_iter()
+        .flatten()
+        .any(|sort_dir| std::path::absolute(sort_dir).is_ok_and(|sort_dir| sort_dir == dir))
+}
+
+/// Move the image processed in place at `written` into the directory for its crop, keeping
+/// its file name. An existing file of that name is never replaced.
+pub(crate) async fn sort_image(written: &Path, result: &CropResult, config: &Config) -> Result<()> {
+    let Some(dir) = sort_dir(result, config) else {
+        return Ok(());
+    };
+    let file_name = written
+        .file_name()
+        .with_context(|| format!("Input path has no file name: {}", written.display()))?;
+    let destination = dir.join(file_name);
+    if config.dry_run {
+        info!(
+            "Would move {} to {}",
+            written.display(),
+            destination.display()
+        );
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+    if tokio::fs::try_exists(&destination).await.unwrap_or(false) {
+        anyhow::bail!(
+            "Cannot move {}, a file of that name exists: {}",
+            written.display(),
+            destination.display()
+        );
+    }
+    tokio::fs::rename(written, &destination)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to move {} to {}",
+                written.display(),
+                destination.display()
+            )
+        })?;
+    info!("Moved {} to {}", written.display(), destination.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process_directory;
+    use image::{GenericImageView, Rgba, RgbaImage};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_sort_images() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("input");
+        std::fs::create_dir(&input)?;
+        for (name, bar) in [("boxed.png", 20), ("plain.png", 0)] {
+            let img = RgbaImage::from_fn(100, 100, |_, y| {
+                if y < bar || y >= 100 - bar {
+                    Rgba([0, 0, 0, 255])
+                } else {
+                    Rgba([255, 255, 255, 255])
+                }
+            });
+            img.save(input.join(name))?;
+        }
+
+        // The sorting directories live inside the input, and are not processed again
+        let config = Config {
+            recursive: true,
+            cropped_dir: Some(input.join("cropped")),
+            unchanged_dir: Some(input.join("unchanged")),
+            ..Config::default()
+        };
+        process_directory(&input, &config).await?;
+
+        assert!(!input.join("boxed.png").exists());
+        assert!(!input.join("plain.png").exists());
+        assert_eq!(
+            image::open(input.join("cropped/boxed.png"))?.dimensions(),
+            (100, 60)
+        );
+        assert_eq!(
+            image::open(input.join("unchanged/plain.png"))?.dimensions(),
+            (100, 100)
+        );
+        Ok(())
+    }
+}
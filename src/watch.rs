@@ -0,0 +1,229 @@
+//! Processing new images as they appear in a directory
+
+use crate::ignore_file::IgnoreRules;
+use crate::{
+    Config, Destinations, has_selected_extension, is_excluded, is_image_file, process_file_to,
+};
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+
+/// How long a file has to go without changes before it is processed, so that files that are
+/// still being written are left alone
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Watch a directory and process images as they are created or modified, until Ctrl-C is
+/// pressed.
+///
+/// Subdirectories are watched when [`Config::recursive`] is set, down to [`Config::max_depth`],
+/// and their structure is mirrored under [`Config::output`]. Files are processed once they
+/// have not changed for a short while. Failures are logged and do not stop the watch.
+///
+/// # Errors
+///
+/// Returns an error if the directory cannot be watched.
+pub async fn watch_directory(dir: &Path, config: &Config) -> Result<()> {
+    watch_and_notify(dir, config, || {}).await
+}
+
+/// Watch a directory as [`watch_directory`] does, calling `ready` once the watcher is in place
+/// and files created from then on are seen
+async fn watch_and_notify(dir: &Path, config: &Config, ready: impl FnOnce()) -> Result<()> {
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let _ = sender.send(event);
+    })
+    .context("Failed to create file watcher")?;
+    let mode = if config.recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher
+        .watch(dir, mode)
+        .with_context(|| format!("Failed to watch directory: {}", dir.display()))?;
+    info!(
+        "Watching {} for new images, press Ctrl-C to stop",
+        dir.display()
+    );
+    ready();
+
+    let mut watch = DirectoryWatch {
+        dir,
+        config,
+        output_root: config.output.as_ref().and_then(|o| o.canonicalize().ok()),
+        pending: HashMap::new(),
+        written: HashMap::new(),
+    };
+    let mut tick = tokio::time::interval(SETTLE_TIME / 2);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    loop {
+        tokio::select! {
+            result = &mut ctrl_c => {
+                result.context("Failed to listen for Ctrl-C")?;
+                info!("Stopped watching {}", dir.display());
+                return Ok(());
+            }
+            Some(event) = events.recv() => match event {
+                Ok(event) => watch.record(event),
+                Err(err) => warn!("File watcher error: {err}"),
+            },
+            _ = tick.tick() => watch.process_settled().await,
+        }
+    }
+}
+
+/// State of a running directory watch
+struct DirectoryWatch<'a> {
+    dir: &'a Path,
+    config: &'a Config,
+    /// Canonical output directory, whose files are never processed again
+    output_root: Option<PathBuf>,
+    /// Files waiting to settle, with the time of their latest change
+    pending: HashMap<PathBuf, Instant>,
+    /// Modification times of files rewritten in place, so that those writes are not picked up
+    /// as new changes
+    written: HashMap<PathBuf, SystemTime>,
+}
+
+impl DirectoryWatch<'_> {
+    /// Note the files touched by a file system event
+    fn record(&mut self, event: Event) {
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+        for path in event.paths {
+            if self.is_watched(&path) {
+                self.pending.insert(path, Instant::now());
+            }
+        }
+    }
+
+    /// Whether a changed path is an image that should be processed
+    fn is_watched(&self, path: &Path) -> bool {
+        if !path.is_file()
+            || !is_image_file(path)
+            || !has_selected_extension(path, self.config)
+            || is_excluded(path, self.dir, self.config)
+        {
+            return false;
+        }
+        if let Some(max_depth) = self.config.max_depth {
+            let depth = path
+                .parent()
+                .and_then(|parent| parent.strip_prefix(self.dir).ok())
+                .map_or(0, |relative| relative.components().count());
+            if depth > max_depth {
+                return false;
+            }
+        }
+        match (&self.output_root, path.canonicalize()) {
+            (Some(output), Ok(path)) => !path.starts_with(output),
+            _ => true,
+        }
+    }
+
+    /// Process the files that have not changed for [`SETTLE_TIME`]
+    async fn process_settled(&mut self) {
+        let settled: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, changed)| changed.elapsed() >= SETTLE_TIME)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in settled {
+            self.pending.remove(&path);
+            self.process(&path).await;
+        }
+    }
+
+    /// Process a single settled file, logging failures
+    async fn process(&mut self, path: &Path) {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let Some(before) = modified(path) else {
+            return;
+        };
+        if self.written.get(path) == Some(&before) {
+            return;
+        }
+
+        match IgnoreRules::for_file(self.dir, path).await {
+            Ok(Some(rules)) if !rules.is_ignored(path, false) => {}
+            Ok(_) => return,
+            Err(err) => {
+                error!("Failed to process {}: {err:#}", path.display());
+                return;
+            }
+        }
+
+        let relative = path
+            .parent()
+            .and_then(|parent| parent.strip_prefix(self.dir).ok());
+        let mirrored = |dir: &PathBuf| match relative {
+            Some(relative) => dir.join(relative),
+            None => dir.clone(),
+        };
+        let destinations = Destinations {
+            output: self.config.output.as_ref().map(mirrored),
+            preview: self.config.preview_dir.as_ref().map(mirrored),
+        };
+        match process_file_to(path, self.config, &destinations).await {
+            Ok(_) => {
+                if destinations.output.is_none()
+                    && let Some(after) = modified(path)
+                {
+                    self.written.insert(path.to_owned(), after);
+                }
+            }
+            Err(err) => error!("Failed to process {}: {err:#}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::create_test_image;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_watch_directory() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path().to_owned();
+        let (ready, started) = tokio::sync::oneshot::channel();
+        let watch = tokio::spawn(async move {
+            let ready = move || {
+                let _ = ready.send(());
+            };
+            watch_and_notify(&dir, &Config::default(), ready).await
+        });
+        // Only write the image once the watcher sees it
+        started.await?;
+
+        let path = temp_dir.path().join("new.png");
+        create_test_image(&path, 100, 100, true)?;
+
+        let mut height = 100;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            // The file may be caught while it is being rewritten
+            if let Ok(img) = image::open(&path) {
+                height = img.height();
+            }
+            if height != 100 {
+                break;
+            }
+        }
+        watch.abort();
+
+        assert_eq!(height, 51);
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+//! Letterbox removal for animated WebPs, applying one crop to every frame
+
+use crate::gif::Animation;
+use crate::{
+    CropRect, CropResult, DetectionParams, LetterboxError, backup_file, read_file,
+    write_file_atomic,
+};
+use anyhow::Result;
+use image::codecs::webp::{WebPDecoder, WebPEncoder};
+use image::metadata::LoopCount;
+use image::{AnimationDecoder, DynamicImage, ExtendedColorType, imageops};
+use log::debug;
+use std::io::Cursor;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Flag of the VP8X chunk marking an animation
+const ANIMATION_FLAG: u8 = 0x02;
+
+/// Flag of the VP8X chunk marking frames that may be transparent
+const ALPHA_FLAG: u8 = 0x10;
+
+/// Flag of an ANMF chunk to draw the frame over the canvas instead of blending it with the
+/// previous one. Decoded frames are already composited onto the full canvas.
+const NO_BLEND_FLAG: u8 = 0x02;
+
+/// Largest value of the 24-bit fields of the WebP container
+const MAX_U24: u32 = (1 << 24) - 1;
+
+/// Whether the path has a `.webp` extension
+pub(crate) fn is_webp_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("webp"))
+}
+
+/// Whether the WebP file holds an animation, judged by the flags of its extended header
+pub(crate) async fn is_animated_webp(path: &Path) -> Result<bool> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|err| LetterboxError::io(path, err))?;
+    let mut header = [0; 21];
+    if file.read_exact(&mut header).await.is_err() {
+        return Ok(false);
+    }
+    Ok(has_animation(&header))
+}
+
+/// Whether the encoded WebP starting with `bytes` holds an animation
+pub(crate) fn has_animation(bytes: &[u8]) -> bool {
+    bytes.len() > 20
+        && &bytes[0..4] == b"RIFF"
+        && &bytes[8..12] == b"WEBP"
+        && &bytes[12..16] == b"VP8X"
+        && bytes[20] & ANIMATION_FLAG != 0
+}
+
+/// Read and decode every frame of an animated WebP file
+async fn read_animation(path: &Path) -> Result<Animation> {
+    let bytes = read_file(path).await?;
+    let decoder =
+        WebPDecoder::new(Cursor::new(bytes)).map_err(|err| LetterboxError::decode(path, err))?;
+    let loop_count = decoder.loop_count();
+    let frames = decoder
+        .into_frames()
+        .collect_frames()
+        .map_err(|err| LetterboxError::decode(path, err))?;
+    Ok(Animation { frames, loop_count })
+}
+
+/// Append a RIFF chunk, padded to an even length
+fn push_chunk(buf: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) -> Result<()> {
+    buf.extend_from_slice(fourcc);
+    buf.extend_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+    buf.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        buf.push(0);
+    }
+    Ok(())
+}
+
+/// Append the lower 24 bits of `value`, as stored in the fields of the WebP container
+fn push_u24(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.min(MAX_U24).to_le_bytes()[..3]);
+}
+
+/// Encode the animation as a lossless animated WebP with every frame cropped to `rect`,
+/// keeping frame durations and the loop count. Each frame is encoded as a lossless still
+/// image, whose bitstream is then wrapped in a frame of the animation.
+fn encode(animation: Animation, rect: CropRect, path: &Path) -> Result<Vec<u8>> {
+    let mut chunks = Vec::new();
+
+    let mut header = vec![ANIMATION_FLAG | ALPHA_FLAG, 0, 0, 0];
+    push_u24(&mut header, rect.width - 1);
+    push_u24(&mut header, rect.height - 1);
+    push_chunk(&mut chunks, b"VP8X", &header)?;
+
+    // A transparent background, and a loop count of 0 for endless repetition
+    let loops = match animation.loop_count {
+        LoopCount::Infinite => 0,
+        LoopCount::Finite(count) => u16::try_from(count.get()).unwrap_or(u16::MAX),
+    };
+    let mut anim = vec![0; 4];
+    anim.extend_from_slice(&loops.to_le_bytes());
+    push_chunk(&mut chunks, b"ANIM", &anim)?;
+
+    for frame in animation.frames {
+        let cropped =
+            imageops::crop_imm(frame.buffer(), rect.x, rect.y, rect.width, rect.height).to_image();
+        let mut still = Vec::new();
+        WebPEncoder::new_lossless(&mut still)
+            .encode(
+                cropped.as_raw(),
+                rect.width,
+                rect.height,
+                ExtendedColorType::Rgba8,
+            )
+            .map_err(|err| LetterboxError::encode(path, err))?;
+        // A plain lossless still is a RIFF header followed by a single VP8L chunk
+        let bitstream = still.get(12..).unwrap_or_default();
+        if !bitstream.starts_with(b"VP8L") {
+            anyhow::bail!("Unexpected WebP frame encoding: {}", path.display());
+        }
+
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let mut anmf = Vec::with_capacity(16 + bitstream.len());
+        // The frame covers the whole canvas, so its offset is zero
+        push_u24(&mut anmf, 0);
+        push_u24(&mut anmf, 0);
+        push_u24(&mut anmf, rect.width - 1);
+        push_u24(&mut anmf, rect.height - 1);
+        push_u24(&mut anmf, numer / denom.max(1));
+        anmf.push(NO_BLEND_FLAG);
+        anmf.extend_from_slice(bitstream);
+        push_chunk(&mut chunks, b"ANMF", &anmf)?;
+    }
+
+    let mut buf = Vec::with_capacity(12 + chunks.len());
+    buf.extend_from_slice(b"RIFF");
+    buf.extend_from_slice(&u32::try_from(4 + chunks.len())?.to_le_bytes());
+    buf.extend_from_slice(b"WEBP");
+    buf.extend_from_slice(&chunks);
+    Ok(buf)
+}
+
+/// Predict the crop of an animated WebP without modifying it, returning the frame it was
+/// detected on along with it
+pub(crate) async fn plan_webp(
+    path: &Path,
+    params: &DetectionParams,
+) -> Result<(DynamicImage, CropResult)> {
+    let animation = read_animation(path).await?;
+    let rect = animation.plan_crop(params, path)?;
+    let frame = animation.preview_frame(params, path)?;
+    Ok((frame, CropResult::new(animation.dimensions(), rect)))
+}
+
+/// Crop every frame of an animated WebP to the same letterbox-free area and write it back to
+/// the same path. When `backup` holds a suffix, the file is backed up before it is overwritten.
+pub(crate) async fn remove_webp_letterbox(
+    path: &Path,
+    params: &DetectionParams,
+    backup: Option<&str>,
+    force: bool,
+) -> Result<CropResult> {
+    let animation = read_animation(path).await?;
+    let dimensions = animation.dimensions();
+    let rect = animation.plan_crop(params, path)?;
+
+    if let Some(rect) = rect {
+        if let Some(suffix) = backup {
+            backup_file(path, suffix, force).await?;
+        }
+        let frames = animation.frames.len();
+        let encoded = encode(animation, rect, path)?;
+        write_file_atomic(path, &encoded).await?;
+        debug!("Cropped {frames} frames of {}", path.display());
+    }
+
+    Ok(CropResult::new(dimensions, rect))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, crop_file};
+    use image::{Delay, Frame, Rgba, RgbaImage};
+    use std::num::NonZeroU32;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_crop_animated_webp() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("test.webp");
+        let frames = [20, 10]
+            .into_iter()
+            .map(|bar| {
+                let buffer = RgbaImage::from_fn(100, 100, |_, y| {
+                    if y < bar || y >= 100 - bar {
+                        Rgba([0, 0, 0, 255])
+                    } else {
+                        Rgba([255, 255, 255, 255])
+                    }
+                });
+                Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(50, 1))
+            })
+            .collect();
+        let animation = Animation {
+            frames,
+            loop_count: LoopCount::Finite(NonZeroU32::new(3).unwrap()),
+        };
+        let full = CropRect::full(animation.dimensions());
+        std::fs::write(&path, encode(animation, full, &path)?)?;
+        assert!(is_animated_webp(&path).await?);
+
+        let result = crop_file(&path, &Config::default()).await?;
+        assert_eq!(result.cropped, (100, 60));
+
+        let animation = read_animation(&path).await?;
+        assert_eq!(animation.frames.len(), 2);
+        assert!(
+            animation
+                .frames
+                .iter()
+                .all(|frame| frame.buffer().dimensions() == (100, 60))
+        );
+        assert_eq!(
+            animation.frames[1].delay(),
+            Delay::from_numer_denom_ms(50, 1)
+        );
+        assert!(matches!(
+            animation.loop_count,
+            LoopCount::Finite(count) if count == NonZeroU32::new(3).unwrap()
+        ));
+        Ok(())
+    }
+}